@@ -0,0 +1,152 @@
+//! Names imported instead of the standard functions.
+//!
+//! The headers of glibc redirect some functions to other symbols, depending
+//! on the compiler flags. For example, with `-D_FILE_OFFSET_BITS=64`, a call
+//! to `open` imports `open64`, and with `-D_FORTIFY_SOURCE`, a call to
+//! `printf` imports `__printf_chk`.
+//!
+//! [`ObjectFile::replace_family`] replaces every name in the family of a
+//! function.
+//!
+//! [`ObjectFile::replace_family`]: crate::ObjectFile::replace_family
+
+use std::result;
+
+use crate::errors::RestoreError;
+use crate::Replacement;
+
+/// Families of functions. The first name of every family is the standard
+/// function, followed by the symbols that the headers can use for it.
+static FAMILIES: &[&[&str]] = &[
+    // Large file support.
+    &["creat", "creat64"],
+    &["fallocate", "fallocate64"],
+    &["fgetpos", "fgetpos64"],
+    &["fopen", "fopen64"],
+    &["freopen", "freopen64"],
+    &["fseeko", "fseeko64"],
+    &["fsetpos", "fsetpos64"],
+    &["fstatfs", "fstatfs64"],
+    &["ftello", "ftello64"],
+    &["ftruncate", "ftruncate64"],
+    &["getrlimit", "getrlimit64"],
+    &["glob", "glob64"],
+    &["lseek", "lseek64"],
+    &["mmap", "mmap64"],
+    &["open", "open64", "__open_2", "__open64_2"],
+    &["openat", "openat64", "__openat_2", "__openat64_2"],
+    &["posix_fallocate", "posix_fallocate64"],
+    &["pread", "pread64", "__pread_chk", "__pread64_chk"],
+    &["pwrite", "pwrite64"],
+    &["readdir", "readdir64"],
+    &["sendfile", "sendfile64"],
+    &["setrlimit", "setrlimit64"],
+    &["statfs", "statfs64"],
+    &["tmpfile", "tmpfile64"],
+    &["truncate", "truncate64"],
+    // `stat` functions, before glibc 2.33.
+    &["stat", "stat64", "__xstat", "__xstat64"],
+    &["fstat", "fstat64", "__fxstat", "__fxstat64"],
+    &["lstat", "lstat64", "__lxstat", "__lxstat64"],
+    &["fstatat", "fstatat64", "__fxstatat", "__fxstatat64"],
+    // ISO C99 and C23 versions of the `scanf` and `strtol` functions.
+    &["scanf", "__isoc99_scanf", "__isoc23_scanf"],
+    &["fscanf", "__isoc99_fscanf", "__isoc23_fscanf"],
+    &["sscanf", "__isoc99_sscanf", "__isoc23_sscanf"],
+    &["vscanf", "__isoc99_vscanf", "__isoc23_vscanf"],
+    &["vfscanf", "__isoc99_vfscanf", "__isoc23_vfscanf"],
+    &["vsscanf", "__isoc99_vsscanf", "__isoc23_vsscanf"],
+    &["strtol", "__isoc23_strtol"],
+    &["strtoll", "__isoc23_strtoll"],
+    &["strtoul", "__isoc23_strtoul"],
+    &["strtoull", "__isoc23_strtoull"],
+    // Fortified functions (`-D_FORTIFY_SOURCE`).
+    &["fprintf", "__fprintf_chk"],
+    &["memcpy", "__memcpy_chk"],
+    &["memmove", "__memmove_chk"],
+    &["memset", "__memset_chk"],
+    &["printf", "__printf_chk"],
+    &["read", "__read_chk"],
+    &["snprintf", "__snprintf_chk"],
+    &["sprintf", "__sprintf_chk"],
+    &["strcat", "__strcat_chk"],
+    &["strcpy", "__strcpy_chk"],
+    &["strncpy", "__strncpy_chk"],
+    &["vfprintf", "__vfprintf_chk"],
+    &["vprintf", "__vprintf_chk"],
+    &["vsnprintf", "__vsnprintf_chk"],
+    &["vsprintf", "__vsprintf_chk"],
+];
+
+/// Returns the family of `name`, starting with the standard function. If
+/// `name` is not in the table, it returns `None`.
+pub(crate) fn family(name: &str) -> Option<&'static [&'static str]> {
+    FAMILIES.iter().copied().find(|f| f.contains(&name))
+}
+
+/// Returns `true` if `name` has the same signature as the standard function
+/// of its family, so it can be replaced by the same function.
+///
+/// The `stat` functions before glibc 2.33 receive a version number, the
+/// fortified functions receive the size of the buffer, and `__open_2`
+/// does not receive the mode. On 32-bit platforms, the `64` variants use
+/// 64-bit offsets and structures.
+pub(crate) fn same_signature(name: &str) -> bool {
+    let different = name.starts_with("__xstat")
+        || name.starts_with("__fxstat")
+        || name.starts_with("__lxstat")
+        || name.ends_with("_chk")
+        || name.ends_with("_2")
+        || (cfg!(target_pointer_width = "32") && name.ends_with("64"));
+
+    !different
+}
+
+/// Replacements created by [`ObjectFile::replace_family`].
+///
+/// All PLT entries are restored when this value is dropped.
+///
+/// [`ObjectFile::replace_family`]: crate::ObjectFile::replace_family
+pub struct FamilyReplacement {
+    replacements: Vec<(String, Replacement)>,
+    missed: Vec<String>,
+}
+
+impl FamilyReplacement {
+    pub(crate) fn new(replacements: Vec<(String, Replacement)>, missed: Vec<String>) -> Self {
+        FamilyReplacement {
+            replacements,
+            missed,
+        }
+    }
+
+    /// Returns the names that were replaced.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.replacements.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the names in the family that are imported by the object, but
+    /// were not replaced because their signature is different from the
+    /// standard function, like `__xstat` for `stat`.
+    pub fn missed(&self) -> &[String] {
+        &self.missed
+    }
+
+    /// Returns the replacement of every name.
+    pub fn replacements(&self) -> impl Iterator<Item = &Replacement> + '_ {
+        self.replacements.iter().map(|(_, r)| r)
+    }
+
+    /// Returns the replacement of every name.
+    pub fn replacements_mut(&mut self) -> impl Iterator<Item = &mut Replacement> + '_ {
+        self.replacements.iter_mut().map(|(_, r)| r)
+    }
+
+    /// Restore all PLT entries. See [`Replacement::restore`].
+    ///
+    /// Every replacement is restored, even if some of them fail. The first
+    /// error is returned, and the failed replacements are kept.
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        Replacement::restore_each(&mut self.replacements, |(_, r)| r)
+    }
+}
@@ -0,0 +1,208 @@
+//! Hooks that invoke a single handler for every function imported by an
+//! object.
+//!
+//! Every PLT entry of a function gets its own [trampoline](crate::trampoline),
+//! whose context identifies the entry. The callback of the trampolines
+//! invokes the handler with the [`SlotInfo`] of the entry, and then returns
+//! the next function in the chain, so the trampoline jumps to it with the
+//! same registers and stack of the original call.
+//!
+//! The handler is not invoked for nested calls in the same thread (see
+//! [`HookGuard`](crate::HookGuard)), so it can call functions imported by
+//! the object. If the handler panics, the process is aborted.
+//!
+//! The handler can't modify the arguments of the call (see the [registers
+//! saved by the entry](crate::trampoline#saved-registers)).
+
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::result;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::chain::{self, Link};
+use crate::errors::{Error, ErrorKind, RestoreError, Result};
+use crate::reentrancy::HookGuard;
+use crate::trampoline::{Callback, Trampolines};
+use crate::{
+    c_symbol_name, install_batch, BatchItem, Hook, ObjectFile, ReplaceOptions, Replacement,
+};
+use crate::{elf, slot, symbols};
+
+/// Functions that are never hooked, because they can be called by the
+/// trampolines before the handler is invoked.
+const EXCLUDED: &[&str] = &["__tls_get_addr", "___tls_get_addr"];
+
+/// The PLT entry of a call received by the handler of
+/// [`ObjectFile::hook_all`].
+pub struct SlotInfo {
+    name: String,
+    index: usize,
+    slot: *const *const c_void,
+}
+
+// The address of the entry is never dereferenced.
+unsafe impl Send for SlotInfo {}
+unsafe impl Sync for SlotInfo {}
+
+impl SlotInfo {
+    /// Name of the function, without the version.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Index of the entry in [`ObjectFile::symbols`].
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Address of the PLT entry.
+    pub fn slot(&self) -> *const *const c_void {
+        self.slot
+    }
+}
+
+type Handler = dyn Fn(&SlotInfo) + Send + Sync;
+
+/// Data used by the trampoline of a single entry.
+struct Context {
+    info: SlotInfo,
+    handler: Arc<Handler>,
+
+    /// Link to the next function in the chain of the entry.
+    next: Link,
+}
+
+/// Callback for the trampolines. It invokes the handler, unless the thread
+/// is already running a hook, and returns the address of the next function.
+unsafe extern "C" fn dispatch(context: *const c_void) -> *const c_void {
+    let context = &*(context as *const Context);
+
+    if let Some(_guard) = HookGuard::enter() {
+        // A panic can't unwind through the trampoline and the C caller.
+        if panic::catch_unwind(AssertUnwindSafe(|| (context.handler)(&context.info))).is_err() {
+            std::process::abort();
+        }
+    }
+
+    context.next.load(Ordering::SeqCst)
+}
+
+/// Contexts of the trampolines. They are owned by the trampolines, and
+/// released with them, when all entries are restored and there are no
+/// calls running in the handler.
+struct DispatchData {
+    contexts: Vec<Context>,
+}
+
+/// Hooks installed by [`ObjectFile::hook_all`].
+///
+/// All PLT entries are restored when this value is dropped.
+pub struct CatchAllHook {
+    replacements: Vec<Replacement>,
+    data: Arc<DispatchData>,
+}
+
+impl CatchAllHook {
+    /// Returns the entries that are hooked.
+    pub fn slots(&self) -> impl Iterator<Item = &SlotInfo> + '_ {
+        self.data.contexts.iter().map(|c| &c.info)
+    }
+
+    /// Restore all PLT entries. See [`Replacement::restore`].
+    ///
+    /// Every replacement is restored, even if some of them fail. The first
+    /// error is returned, and the failed replacements are kept.
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        Replacement::restore_each(&mut self.replacements, |r| r)
+    }
+}
+
+/// Returns `true` if the entry of `symbol` contains the address of a function
+/// that can be hooked.
+unsafe fn is_function(elf: &elf::Object, symbol: &crate::Symbol, name: &str) -> bool {
+    let slot = symbol.func_address as *const *const c_void;
+
+    if EXCLUDED.contains(&name) || slot::read(slot).is_null() {
+        return false;
+    }
+
+    matches!(
+        elf.relocation_symbol(slot.cast()),
+        Some(s) if s.kind == elf::STT_FUNC || s.kind == elf::STT_GNU_IFUNC
+    )
+}
+
+/// Install a trampoline in every PLT entry of a function in `object`.
+pub(crate) unsafe fn install<F>(object: &ObjectFile, handler: F) -> Result<CatchAllHook>
+where
+    F: Fn(&SlotInfo) + Send + Sync + 'static,
+{
+    let elf = elf::Object::of(object);
+
+    let handler: Arc<Handler> = Arc::new(handler);
+    let mut data = DispatchData {
+        contexts: Vec::new(),
+    };
+
+    let mut protections = Vec::new();
+    for (index, symbol) in object.symbols().enumerate() {
+        let name = String::from_utf8_lossy(symbols::plain_name(symbol.name.to_bytes()));
+        if !matches!(&elf, Some(elf) if is_function(elf, &symbol, &name)) {
+            continue;
+        }
+
+        protections.push(symbol.protection);
+        data.contexts.push(Context {
+            info: SlotInfo {
+                name: name.into_owned(),
+                index,
+                slot: symbol.func_address as *const *const c_void,
+            },
+            handler: Arc::clone(&handler),
+            next: chain::new_link(),
+        });
+    }
+
+    if data.contexts.is_empty() {
+        let msg = "no functions imported by the object".to_string();
+        let error =
+            Error::new(ErrorKind::FunctionNotFound, msg).with_object_path(object.0.path.as_deref());
+        return Err(error);
+    }
+
+    let data = Arc::new(data);
+    let targets: Vec<(*const c_void, Callback, &Link)> = data
+        .contexts
+        .iter()
+        .map(|c| {
+            (
+                c as *const Context as *const c_void,
+                dispatch as Callback,
+                &c.next,
+            )
+        })
+        .collect();
+
+    let trampolines = Arc::new(Trampolines::new(&targets, Box::new(Arc::clone(&data)))?);
+
+    let mut items = Vec::with_capacity(targets.len());
+    for (index, (context, prot)) in data.contexts.iter().zip(protections).enumerate() {
+        let address = trampolines.address(index);
+        items.push(BatchItem {
+            symbol_name: c_symbol_name(&context.info.name)?,
+            slot: context.info.slot,
+            prot,
+            object_path: object.0.path.as_ref(),
+            hook: Hook {
+                address,
+                chained: address,
+                next: Arc::clone(&context.next),
+                data: Some(Box::new(Arc::clone(&trampolines))),
+            },
+        });
+    }
+
+    let replacements = install_batch(items, &ReplaceOptions::default())?;
+    Ok(CatchAllHook { replacements, data })
+}
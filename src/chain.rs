@@ -0,0 +1,987 @@
+//! Registry of the replacements installed in every PLT entry.
+//!
+//! Each entry written by this crate has a chain of layers. The entry points
+//! to the first layer, and every layer has a link to the address of the next
+//! one. The last layer is linked to the address found in the entry before
+//! the first replacement.
+//!
+//! Layers are sorted by priority. Layers with a higher priority are called
+//! first. Layers with the same priority are sorted by installation order,
+//! newest first.
+//!
+//! Suspended layers are kept in the chain, but they are skipped: the
+//! previous active layer (or the entry) is linked to the next one. The link
+//! of a suspended layer is kept updated with the address it would call, so
+//! it can be resumed with a single write.
+//!
+//! Layers are suspended and resumed through a [`Switch`] shared with the
+//! replacement, which can be used from a signal handler. The request is
+//! stored in the switch, and applied only if the registry and the write
+//! lock can be taken without waiting. Otherwise, the thread holding the
+//! registry applies it before releasing the lock, or the next thread that
+//! takes it. The protection of every entry is resolved when its chain is
+//! created, so the entries can be written without reading
+//! `/proc/self/maps`.
+//!
+//! The registry is global to the process, so it can be inspected with
+//! [`active_hooks`], and cleared with [`restore_all`].
+//!
+//! Every chain keeps a reference to the object containing its entry, so the
+//! object is not unloaded (for example, with `dlclose`) while the entry can
+//! still be written. The references are taken before locking the registry,
+//! and released after unlocking it, because they use the lock of the
+//! dynamic loader, and library constructors (which run with that lock) can
+//! install replacements.
+//!
+//! Every layer has its own [`ProtectionPolicy`]. The entry is written with
+//! the strictest policy of the layers in its chain, so a layer can't
+//! weaken the policy of the others.
+
+use std::ffi::{c_int, c_void, CString};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::SystemTime;
+
+use crate::errors::{Error, ErrorKind, RestoreError, Result};
+use crate::options::ProtectionPolicy;
+use crate::slot;
+
+/// Link to the address called by a layer to invoke the next one.
+pub(crate) type Link = Arc<AtomicPtr<c_void>>;
+
+struct Layer {
+    id: usize,
+    hook: usize,
+    chained: usize,
+    priority: i32,
+    protection: ProtectionPolicy,
+    next: Link,
+    active: bool,
+    switch: Arc<Switch>,
+    owner: Option<String>,
+    installed_at: SystemTime,
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        self.switch.removed.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Chain {
+    slot: usize,
+
+    /// Protection of the page, resolved when the chain is created.
+    prot: c_int,
+
+    symbol: CString,
+    object_path: Option<PathBuf>,
+    layers: Vec<Layer>,
+
+    /// Keeps the object loaded until the chain is removed.
+    pin: Option<ObjectPin>,
+}
+
+impl Chain {
+    /// Address in the entry before the first replacement.
+    fn original(&self) -> *const c_void {
+        match self.layers.iter().rev().find(|l| l.active) {
+            Some(layer) => layer.next.load(Ordering::SeqCst),
+            None => unsafe { slot::read(self.slot as *const _) },
+        }
+    }
+
+    /// Strictest policy of the layers.
+    fn protection(&self) -> ProtectionPolicy {
+        self.layers
+            .iter()
+            .map(|l| l.protection)
+            .fold(ProtectionPolicy::KeepWritable, ProtectionPolicy::strictest)
+    }
+
+    fn write(
+        &self,
+        address: *const c_void,
+        protection: ProtectionPolicy,
+        writer: Writer,
+    ) -> Result<()> {
+        let slot = self.slot as *const _;
+        match writer {
+            Writer::Locking => unsafe { slot::write(slot, self.prot, protection, address) },
+            Writer::Held => unsafe { slot::write_held(slot, self.prot, protection, address) }
+                .map_err(|e| slot::unlocked_error(&e)),
+        }
+    }
+
+    /// Returns `true` if a new layer with `priority` would be the first
+    /// active layer, so the entry would be written.
+    fn writes_entry(&self, priority: i32) -> bool {
+        !self
+            .layers
+            .iter()
+            .any(|l| l.active && l.priority > priority)
+    }
+
+    /// Returns the index of the nearest active layer before `index`. It is
+    /// the layer that has to be linked to the layer at `index`. If it is
+    /// `None`, the entry has to be written.
+    fn previous_active(&self, index: usize) -> Option<usize> {
+        self.layers[..index].iter().rposition(|l| l.active)
+    }
+
+    /// Address that the layer at `index` would receive from the previous
+    /// active layer, or from the entry.
+    fn incoming(&self, index: usize) -> *const c_void {
+        match self.previous_active(index) {
+            Some(i) => self.layers[i].next.load(Ordering::SeqCst),
+            None => self.entry_link(),
+        }
+    }
+
+    /// Link the layer (or the entry) before `index` to a function. `address`
+    /// is written in the entry, with `protection`, and `chained` in the
+    /// link of a layer.
+    fn link_incoming(
+        &self,
+        index: usize,
+        address: *const c_void,
+        chained: *const c_void,
+        protection: ProtectionPolicy,
+        writer: Writer,
+    ) -> Result<()> {
+        match self.previous_active(index) {
+            Some(i) => {
+                self.layers[i]
+                    .next
+                    .store(chained as *mut _, Ordering::SeqCst);
+                Ok(())
+            }
+            None => self.write(address, protection, writer),
+        }
+    }
+
+    /// Returns the address in the entry, converted to the address used in
+    /// the links if it is a layer of this chain.
+    fn entry_link(&self) -> *const c_void {
+        let current = unsafe { slot::read(self.slot as *const _) };
+        match self.layers.iter().find(|l| l.hook == current as usize) {
+            Some(layer) => layer.chained as *const _,
+            None => current,
+        }
+    }
+
+    /// Returns the address to write in the entry to call `link`.
+    fn entry_address(&self, link: *const c_void) -> *const c_void {
+        match self.layers.iter().find(|l| l.chained == link as usize) {
+            Some(layer) => layer.hook as *const _,
+            None => link,
+        }
+    }
+
+    /// Update the links of the suspended layers with the address they would
+    /// call if they were active.
+    fn sync_suspended(&self) {
+        let mut current = self.entry_link();
+        for layer in &self.layers {
+            if layer.active {
+                current = layer.next.load(Ordering::SeqCst);
+            } else {
+                layer.next.store(current as *mut _, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// How the entries are written.
+#[derive(Clone, Copy)]
+enum Writer {
+    /// With [`slot::write`], which takes the write lock.
+    Locking,
+
+    /// With [`slot::write_held`]. The write lock is held by [`try_lock`].
+    Held,
+}
+
+static CHAINS: Mutex<Vec<Chain>> = Mutex::new(Vec::new());
+
+/// The locked registry.
+///
+/// The objects of the chains removed while the lock is held are released
+/// after unlocking it. The pending requests of the switches are applied
+/// before unlocking it.
+struct Locked {
+    chains: ManuallyDrop<MutexGuard<'static, Vec<Chain>>>,
+    released: Vec<ObjectPin>,
+
+    /// Write lock taken by [`try_lock`].
+    protection: Option<MutexGuard<'static, ()>>,
+}
+
+impl Locked {
+    fn writer(&self) -> Writer {
+        match self.protection {
+            Some(_) => Writer::Held,
+            None => Writer::Locking,
+        }
+    }
+
+    /// Remove the chain at `index`.
+    fn remove_chain(&mut self, index: usize) {
+        let chain = self.chains.swap_remove(index);
+        self.released.extend(chain.pin);
+    }
+}
+
+impl Deref for Locked {
+    type Target = Vec<Chain>;
+
+    fn deref(&self) -> &Vec<Chain> {
+        &self.chains
+    }
+}
+
+impl DerefMut for Locked {
+    fn deref_mut(&mut self) -> &mut Vec<Chain> {
+        &mut self.chains
+    }
+}
+
+impl Drop for Locked {
+    fn drop(&mut self) {
+        apply_requests(self);
+
+        // `released` is dropped after this function.
+        unsafe { ManuallyDrop::drop(&mut self.chains) };
+        drop(self.protection.take());
+
+        // Requests made after `apply_requests`, which could not take the
+        // lock.
+        if REQUESTS.load(Ordering::SeqCst) {
+            drop(try_lock());
+        }
+    }
+}
+
+/// Requested state of a layer, shared with its replacement.
+pub(crate) struct Switch {
+    /// `true` if the layer has to be active.
+    requested: AtomicBool,
+
+    /// Set when the layer is removed from its chain.
+    removed: AtomicBool,
+}
+
+/// Set when a switch could have a request that is not applied yet.
+static REQUESTS: AtomicBool = AtomicBool::new(false);
+
+/// Create a new switch for [`install`].
+pub(crate) fn new_switch() -> Arc<Switch> {
+    // The page size is cached before a signal handler needs it.
+    #[cfg(unix)]
+    slot::page_size();
+
+    Arc::new(Switch {
+        requested: AtomicBool::new(true),
+        removed: AtomicBool::new(false),
+    })
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Identifier of a layer in a chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LayerId(usize);
+
+/// PLT entry where a layer is installed.
+pub(crate) struct Entry<'a> {
+    pub(crate) slot: *const *const c_void,
+    pub(crate) prot: c_int,
+    pub(crate) protection: ProtectionPolicy,
+    pub(crate) symbol: &'a CString,
+    pub(crate) object_path: Option<&'a PathBuf>,
+}
+
+/// A layer to add in the chain of `entry`.
+pub(crate) struct NewLayer<'a> {
+    pub(crate) entry: Entry<'a>,
+
+    /// Address written in the entry.
+    pub(crate) hook: *const c_void,
+
+    /// Address stored in the link of the previous layer.
+    pub(crate) chained: *const c_void,
+
+    pub(crate) priority: i32,
+
+    /// Updated with the address of the next layer.
+    pub(crate) next: &'a Link,
+
+    /// Used to suspend and resume the layer.
+    pub(crate) switch: &'a Arc<Switch>,
+
+    /// State of the entry when the layer was planned, if it was planned.
+    pub(crate) expected: Option<Expected>,
+}
+
+/// State of an entry found by [`plan`](crate::plan).
+#[derive(Clone, Copy)]
+pub(crate) struct Expected {
+    /// Address in the entry.
+    pub(crate) current: *const c_void,
+
+    /// If the new layer would be the first active one.
+    pub(crate) writes_entry: bool,
+}
+
+/// Add a new layer to the chain of its entry.
+///
+/// The entry is written when the new layer is the first one in the chain.
+pub(crate) fn install(layer: NewLayer) -> Result<LayerId> {
+    let prot = resolve_protection(&layer.entry)?;
+    let mut pin = ObjectPin::of(layer.entry.slot.cast());
+
+    let mut chains = lock();
+    let result = install_locked(&mut chains, &layer, prot, &mut pin);
+    chains.released.extend(pin);
+    result
+}
+
+/// Add several layers, writing their entries in a single [`slot::Batch`].
+///
+/// If a layer can't be installed, the previous ones are removed, and the
+/// error is returned.
+pub(crate) fn install_many(
+    layers: &[NewLayer],
+    protection: ProtectionPolicy,
+) -> Result<Vec<LayerId>> {
+    let mut prots = Vec::with_capacity(layers.len());
+    for layer in layers {
+        prots.push(resolve_protection(&layer.entry)?);
+    }
+
+    let mut pins: Vec<_> = layers
+        .iter()
+        .map(|l| ObjectPin::of(l.entry.slot.cast()))
+        .collect();
+
+    let mut chains = lock();
+    let result = install_many_locked(&mut chains, layers, &prots, &mut pins, protection);
+    chains.released.extend(pins.into_iter().flatten());
+    result
+}
+
+/// Returns the protection of the page of `entry`.
+///
+/// It is read before the pages are made writable by a [`slot::Batch`].
+fn resolve_protection(entry: &Entry) -> Result<c_int> {
+    let _guard = slot::lock_protection();
+    slot::resolve_protection(entry.slot, entry.prot)
+}
+
+fn install_many_locked(
+    chains: &mut Locked,
+    layers: &[NewLayer],
+    prots: &[c_int],
+    pins: &mut [Option<ObjectPin>],
+    protection: ProtectionPolicy,
+) -> Result<Vec<LayerId>> {
+    // The entries are checked before writing any of them, because the
+    // first layers could modify the entries of the next ones.
+    for layer in layers {
+        check_expected(chains, layer)?;
+    }
+
+    // The batch can't be less strict than the chains of its entries.
+    let protection = layers
+        .iter()
+        .filter_map(|l| chains.iter().find(|c| c.slot == l.entry.slot as usize))
+        .fold(protection, |p, c| p.strictest(c.protection()));
+
+    let slots: Vec<_> = layers
+        .iter()
+        .map(|l| (l.entry.slot, l.entry.prot))
+        .collect();
+
+    let _batch = slot::Batch::open(&slots, protection)?;
+
+    let mut ids = Vec::with_capacity(layers.len());
+    for ((layer, &prot), pin) in layers.iter().zip(prots).zip(pins) {
+        match install_locked(chains, layer, prot, pin) {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                for (layer, id) in layers.iter().zip(ids).rev() {
+                    let _ = remove_locked(chains, layer.entry.slot, id, true);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Returns an error if the entry of `layer` is not in the state expected by
+/// its plan.
+fn check_expected(chains: &[Chain], layer: &NewLayer) -> Result<()> {
+    let expected = match &layer.expected {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+
+    let slot = layer.entry.slot;
+    let current = unsafe { slot::read(slot) };
+    let writes_entry = match chains.iter().find(|c| c.slot == slot as usize) {
+        Some(chain) => chain.writes_entry(layer.priority),
+        None => true,
+    };
+
+    if current == expected.current && writes_entry == expected.writes_entry {
+        return Ok(());
+    }
+
+    let symbol = layer.entry.symbol.to_string_lossy();
+    let msg = format!("PLT entry of {symbol} was modified after the plan: {current:?}");
+    let error = Error::new(ErrorKind::StalePlan, msg)
+        .with_symbol(&symbol)
+        .with_object_path(layer.entry.object_path.map(|p| p.as_path()))
+        .with_address(slot);
+    Err(error)
+}
+
+/// Add a layer to the chain of its entry. If the chain is created, it takes
+/// the reference to the object in `pin`, and the resolved protection in
+/// `prot`.
+fn install_locked(
+    chains: &mut Locked,
+    layer: &NewLayer,
+    prot: c_int,
+    pin: &mut Option<ObjectPin>,
+) -> Result<LayerId> {
+    let entry = &layer.entry;
+    let writer = chains.writer();
+
+    let chain_index = match chains.iter().position(|c| c.slot == entry.slot as usize) {
+        Some(i) => i,
+        None => {
+            chains.push(Chain {
+                slot: entry.slot as usize,
+                prot,
+                symbol: entry.symbol.clone(),
+                object_path: entry.object_path.cloned(),
+                layers: Vec::new(),
+                pin: pin.take(),
+            });
+            chains.len() - 1
+        }
+    };
+
+    let chain = &mut chains[chain_index];
+    let protection = chain.protection().strictest(entry.protection);
+
+    let index = chain
+        .layers
+        .iter()
+        .position(|l| l.priority <= layer.priority)
+        .unwrap_or(chain.layers.len());
+
+    // The new layer is linked to the address that the previous active
+    // layer (or the entry itself) was calling. The link is updated before
+    // the new layer is reachable.
+    layer
+        .next
+        .store(chain.incoming(index) as *mut _, Ordering::SeqCst);
+    if let Err(e) = chain.link_incoming(index, layer.hook, layer.chained, protection, writer) {
+        if chain.layers.is_empty() {
+            chains.remove_chain(chain_index);
+        }
+        return Err(e);
+    }
+
+    let id = LayerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    chain.layers.insert(
+        index,
+        Layer {
+            id: id.0,
+            hook: layer.hook as usize,
+            chained: layer.chained as usize,
+            priority: layer.priority,
+            protection: entry.protection,
+            next: Arc::clone(layer.next),
+            active: true,
+            switch: Arc::clone(layer.switch),
+            owner: None,
+            installed_at: SystemTime::now(),
+        },
+    );
+
+    chain.sync_suspended();
+
+    Ok(id)
+}
+
+/// Returns `true` if a new layer with `priority` would be the first active
+/// layer in the chain of `slot`, so [`install`] would write the entry.
+pub(crate) fn writes_entry(slot: *const *const c_void, priority: i32) -> bool {
+    let chains = lock();
+
+    match chains.iter().find(|c| c.slot == slot as usize) {
+        Some(chain) => chain.writes_entry(priority),
+        None => true,
+    }
+}
+
+/// Returns the address in the entry at `slot` before the first replacement,
+/// if the entry has a chain.
+pub(crate) fn original_of(slot: *const *const c_void) -> Option<*const c_void> {
+    let chains = lock();
+
+    chains
+        .iter()
+        .find(|c| c.slot == slot as usize)
+        .map(Chain::original)
+}
+
+/// Create a new link for [`install`].
+pub(crate) fn new_link() -> Link {
+    Arc::new(AtomicPtr::new(ptr::null_mut()))
+}
+
+/// Remove a layer from the chain of `slot`.
+///
+/// If the layer is the first active one, the entry has to contain its hook
+/// address. Otherwise, the layer is kept and [`RestoreError::Conflict`] is
+/// returned, unless `force` is `true`.
+pub(crate) fn remove(
+    slot: *const *const c_void,
+    id: LayerId,
+    force: bool,
+) -> std::result::Result<(), RestoreError> {
+    remove_locked(&mut lock(), slot, id, force)
+}
+
+fn remove_locked(
+    chains: &mut Locked,
+    slot: *const *const c_void,
+    id: LayerId,
+    force: bool,
+) -> std::result::Result<(), RestoreError> {
+    let (chain_index, index) = match find_layer(chains, slot, id) {
+        Some(found) => found,
+        None => return Err(RestoreError::Inactive),
+    };
+
+    let writer = chains.writer();
+    let chain = &mut chains[chain_index];
+
+    if chain.layers[index].active {
+        unlink(chain, index, force, writer)?;
+    }
+
+    chain.layers.remove(index);
+    chain.sync_suspended();
+
+    if chain.layers.is_empty() {
+        chains.remove_chain(chain_index);
+    }
+
+    Ok(())
+}
+
+/// Suspend or resume a layer in the chain of `slot`.
+///
+/// When the layer is suspended, the same checks of [`remove`] are applied
+/// (without `force`).
+///
+/// It never waits for a lock, and it doesn't allocate memory, so it can be
+/// called from a signal handler. If the registry is locked, the request is
+/// applied later (see the [module documentation](self)), and its errors are
+/// discarded.
+pub(crate) fn set_active(
+    slot: *const *const c_void,
+    id: LayerId,
+    switch: &Switch,
+    active: bool,
+) -> std::result::Result<(), RestoreError> {
+    if switch.removed.load(Ordering::SeqCst) {
+        return Err(RestoreError::Inactive);
+    }
+
+    switch.requested.store(active, Ordering::SeqCst);
+    REQUESTS.store(true, Ordering::SeqCst);
+
+    // The lock can be held by another thread, or by the thread interrupted
+    // by a signal handler.
+    let mut chains = match try_lock() {
+        Some(chains) => chains,
+        None => return Ok(()),
+    };
+
+    let (chain_index, index) = match find_layer(&chains, slot, id) {
+        Some(found) => found,
+        None => return Err(RestoreError::Inactive),
+    };
+
+    let writer = chains.writer();
+    let chain = &mut chains[chain_index];
+
+    if chain.layers[index].active == active {
+        return Ok(());
+    }
+
+    let result = set_active_locked(chain, index, active, writer);
+    if result.is_err() {
+        switch.requested.store(!active, Ordering::SeqCst);
+    }
+
+    result
+}
+
+fn set_active_locked(
+    chain: &mut Chain,
+    index: usize,
+    active: bool,
+    writer: Writer,
+) -> std::result::Result<(), RestoreError> {
+    let layer = &chain.layers[index];
+
+    if active {
+        // The link of a suspended layer is already updated by
+        // `sync_suspended`.
+        let hook = layer.hook as *const _;
+        let chained = layer.chained as *const _;
+        chain.link_incoming(index, hook, chained, chain.protection(), writer)?;
+    } else {
+        unlink(chain, index, false, writer)?;
+    }
+
+    chain.layers[index].active = active;
+    chain.sync_suspended();
+
+    Ok(())
+}
+
+/// Apply the requests of the switches that could not take the lock.
+/// Requests that fail are discarded.
+fn apply_requests(chains: &mut Locked) {
+    if !REQUESTS.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let writer = chains.writer();
+    for chain in chains.iter_mut() {
+        for index in 0..chain.layers.len() {
+            let requested = chain.layers[index].switch.requested.load(Ordering::SeqCst);
+            if chain.layers[index].active != requested
+                && set_active_locked(chain, index, requested, writer).is_err()
+            {
+                let switch = &chain.layers[index].switch;
+                switch.requested.store(!requested, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Returns `true` if the layer is in the chain of `slot`, and it is not
+/// suspended.
+pub(crate) fn is_active(slot: *const *const c_void, id: LayerId) -> bool {
+    let chains = lock();
+
+    match find_layer(&chains, slot, id) {
+        Some((chain_index, index)) => chains[chain_index].layers[index].active,
+        None => false,
+    }
+}
+
+/// Link the active layer at `index` to the next one, so it is no longer
+/// reachable.
+fn unlink(
+    chain: &Chain,
+    index: usize,
+    force: bool,
+    writer: Writer,
+) -> std::result::Result<(), RestoreError> {
+    let layer = &chain.layers[index];
+
+    if chain.previous_active(index).is_none() {
+        let current = unsafe { slot::read(chain.slot as *const _) };
+        if current as usize != layer.hook && !force {
+            return Err(RestoreError::Conflict { current });
+        }
+    }
+
+    let next = layer.next.load(Ordering::SeqCst);
+    let address = chain.entry_address(next);
+    chain.link_incoming(index, address, next, chain.protection(), writer)?;
+    Ok(())
+}
+
+/// Remove a layer from the chain of `slot`, without modifying the entry or
+/// the links of the other layers.
+pub(crate) fn forget(slot: *const *const c_void, id: LayerId) {
+    let mut chains = lock();
+
+    if let Some((chain_index, index)) = find_layer(&chains, slot, id) {
+        let chain = &mut chains[chain_index];
+        chain.layers.remove(index);
+        chain.sync_suspended();
+
+        if chain.layers.is_empty() {
+            chains.remove_chain(chain_index);
+        }
+    }
+}
+
+/// Set the owner label of a layer.
+pub(crate) fn set_owner(slot: *const *const c_void, id: LayerId, owner: String) {
+    let mut chains = lock();
+
+    if let Some((chain_index, index)) = find_layer(&chains, slot, id) {
+        chains[chain_index].layers[index].owner = Some(owner);
+    }
+}
+
+/// Lock the registry, and apply the pending requests of the switches.
+fn lock() -> Locked {
+    let mut chains = Locked {
+        chains: ManuallyDrop::new(CHAINS.lock().unwrap_or_else(|e| e.into_inner())),
+        released: Vec::new(),
+        protection: None,
+    };
+
+    apply_requests(&mut chains);
+    chains
+}
+
+/// Lock the registry and the write lock, only if they are not held by any
+/// thread.
+fn try_lock() -> Option<Locked> {
+    let protection = slot::try_lock_protection()?;
+
+    let chains = match CHAINS.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return None,
+    };
+
+    Some(Locked {
+        chains: ManuallyDrop::new(chains),
+        released: Vec::new(),
+        protection: Some(protection),
+    })
+}
+
+fn find_layer(chains: &[Chain], slot: *const *const c_void, id: LayerId) -> Option<(usize, usize)> {
+    let chain_index = chains.iter().position(|c| c.slot == slot as usize)?;
+    let index = chains[chain_index]
+        .layers
+        .iter()
+        .position(|l| l.id == id.0)?;
+
+    Some((chain_index, index))
+}
+
+/// A replacement installed in a PLT entry.
+///
+/// Use [`active_hooks`] to get them.
+#[derive(Clone, Debug)]
+pub struct HookInfo {
+    /// Path of the object file, as given to [`ObjectFile::open_file`].
+    ///
+    /// It is `None` for objects loaded with [`ObjectFile::open_main_program`]
+    /// or [`ObjectFile::open_by_handle`].
+    ///
+    /// [`ObjectFile::open_file`]: crate::ObjectFile::open_file
+    /// [`ObjectFile::open_main_program`]: crate::ObjectFile::open_main_program
+    /// [`ObjectFile::open_by_handle`]: crate::ObjectFile::open_by_handle
+    pub object_path: Option<PathBuf>,
+
+    /// Name of the symbol.
+    pub symbol: CString,
+
+    /// Address of the PLT entry.
+    pub slot: *const *const c_void,
+
+    /// Address in the entry before the first replacement.
+    pub original: *const c_void,
+
+    /// Address installed by the replacement.
+    pub hook: *const c_void,
+
+    /// Priority of the replacement in the chain of the entry.
+    pub priority: i32,
+
+    /// `false` if the replacement is suspended.
+    ///
+    /// See [`Replacement::suspend`](crate::Replacement::suspend).
+    pub active: bool,
+
+    /// Label set with [`Replacement::set_owner`].
+    ///
+    /// [`Replacement::set_owner`]: crate::Replacement::set_owner
+    pub owner: Option<String>,
+
+    /// Time when the replacement was installed.
+    pub installed_at: SystemTime,
+}
+
+// The addresses in `HookInfo` are never dereferenced.
+unsafe impl Send for HookInfo {}
+unsafe impl Sync for HookInfo {}
+
+/// Returns all replacements installed by this crate in the process,
+/// including discarded ones.
+///
+/// Replacements of the same PLT entry are sorted in calling order. Symbol
+/// tables modified by [`ObjectFile::replace_export`] are not included.
+///
+/// [`ObjectFile::replace_export`]: crate::ObjectFile::replace_export
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::ObjectFile;
+///
+/// extern "C" fn broken_getpgrp() -> libc::pid_t {
+///     -1
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let mut replacement = unsafe {
+///     program.replace("getpgrp", broken_getpgrp as *const _).unwrap()
+/// };
+///
+/// replacement.set_owner("example");
+/// assert_eq!(unsafe { libc::getpgrp() }, -1);
+///
+/// let hook = plthook::active_hooks()
+///     .into_iter()
+///     .find(|h| h.owner.as_deref() == Some("example"))
+///     .unwrap();
+///
+/// assert_eq!(hook.symbol.to_str(), Ok("getpgrp"));
+/// assert_eq!(hook.hook, broken_getpgrp as *const _);
+/// # }
+/// ```
+pub fn active_hooks() -> Vec<HookInfo> {
+    let chains = lock();
+
+    let mut hooks = Vec::new();
+    for chain in chains.iter() {
+        let original = chain.original();
+        for layer in &chain.layers {
+            hooks.push(HookInfo {
+                object_path: chain.object_path.clone(),
+                symbol: chain.symbol.clone(),
+                slot: chain.slot as *const _,
+                original,
+                hook: layer.hook as *const _,
+                priority: layer.priority,
+                active: layer.active,
+                owner: layer.owner.clone(),
+                installed_at: layer.installed_at,
+            });
+        }
+    }
+
+    hooks
+}
+
+/// Restore every PLT entry modified by this crate to the address it had
+/// before the first replacement.
+///
+/// The entries are written even if they were modified by someone else, and
+/// discarded replacements are also removed. Existing [`Replacement`] values
+/// become inactive, so they don't modify the entries when they are dropped.
+///
+/// If some entries can't be written, they are kept in the registry, and the
+/// first error is returned.
+///
+/// Symbol tables modified by [`ObjectFile::replace_export`] are not PLT
+/// entries, so they are not restored.
+///
+/// [`Replacement`]: crate::Replacement
+/// [`ObjectFile::replace_export`]: crate::ObjectFile::replace_export
+pub fn restore_all() -> Result<()> {
+    let mut chains = lock();
+
+    let mut result = Ok(());
+    let mut index = 0;
+    while index < chains.len() {
+        let chain = &chains[index];
+        match chain.write(chain.original(), chain.protection(), Writer::Locking) {
+            Ok(()) => chains.remove_chain(index),
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                index += 1;
+            }
+        }
+    }
+
+    result
+}
+
+/// A reference to a loaded object, taken from an address inside it.
+struct ObjectPin(*mut c_void);
+
+// The handle is only used to release the reference.
+unsafe impl Send for ObjectPin {}
+
+impl ObjectPin {
+    /// Returns a reference to the object containing `address`, or `None` if
+    /// the address is not in an object loaded by the dynamic loader.
+    #[cfg(unix)]
+    fn of(address: *const c_void) -> Option<ObjectPin> {
+        let mut info = std::mem::MaybeUninit::<libc::Dl_info>::uninit();
+        if unsafe { libc::dladdr(address, info.as_mut_ptr()) } == 0 {
+            return None;
+        }
+
+        let info = unsafe { info.assume_init() };
+        if info.dli_fname.is_null() {
+            return None;
+        }
+
+        let flags = libc::RTLD_LAZY | libc::RTLD_NOLOAD;
+        let handle = unsafe { libc::dlopen(info.dli_fname, flags) };
+        if handle.is_null() {
+            return None;
+        }
+
+        Some(ObjectPin(handle))
+    }
+
+    #[cfg(windows)]
+    fn of(address: *const c_void) -> Option<ObjectPin> {
+        use winapi::um::libloaderapi::{
+            GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+        };
+
+        let mut module = ptr::null_mut();
+        let flags = GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS;
+        if unsafe { GetModuleHandleExW(flags, address.cast(), &mut module) } == 0 {
+            return None;
+        }
+
+        Some(ObjectPin(module.cast()))
+    }
+}
+
+impl Drop for ObjectPin {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.0) };
+    }
+
+    #[cfg(windows)]
+    fn drop(&mut self) {
+        unsafe { winapi::um::libloaderapi::FreeLibrary(self.0.cast()) };
+    }
+}
@@ -0,0 +1,278 @@
+//! Replace functions with Rust closures.
+//!
+//! Every closure hook has a [trampoline](crate::trampoline) that pushes the
+//! context of the closure in a thread-local stack, and then jumps to a
+//! shim function. The shim is an `extern "C"` function with the same
+//! signature of the replaced function, which pops the context and invokes
+//! the closure.
+//!
+//! A signal handler can call another closure hook after the context is
+//! pushed, but before the shim pops it. The handler pushes and pops its
+//! own context, so the shim still gets the right one.
+//!
+//! If the closure panics, the shim aborts the process.
+//!
+//! Closures are not invoked for nested calls in the same thread (see
+//! [`HookGuard`](crate::HookGuard)). In that case, the trampoline jumps
+//! directly to the next function in the chain. Every closure has a second
+//! trampoline, without this check, which is used by the previous
+//! replacement in the chain of the entry.
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::chain::Link;
+use crate::errors::Result;
+use crate::reentrancy::{self, HookGuard};
+use crate::trampoline::{Callback, Trampolines};
+use crate::Hook;
+
+/// Function types that can be replaced with a closure.
+///
+/// It is implemented for `extern "C" fn` types with up to 8 arguments.
+///
+/// See [`ObjectFile::replace_with_closure`](crate::ObjectFile::replace_with_closure).
+pub trait HookFn: Copy + Send + Sync + 'static {}
+
+/// Closures that can replace a function of type `F`.
+///
+/// It is implemented for closures that receive the original function as
+/// the first argument, followed by the arguments of `F`.
+///
+/// See [`ObjectFile::replace_with_closure`](crate::ObjectFile::replace_with_closure).
+pub trait ClosureHook<F: HookFn>: Send + Sync + 'static {
+    #[doc(hidden)]
+    fn shim() -> *const c_void;
+}
+
+/// Data shared between the trampoline and the shim.
+///
+/// `repr(C)` ensures that the header is at the beginning of the struct,
+/// so the callback can use it without knowing the type of the closure.
+#[repr(C)]
+struct Context<C> {
+    header: Header,
+    closure: C,
+}
+
+struct Header {
+    /// Counter of running calls of the trampolines. It is incremented
+    /// while the closure is running.
+    calls: AtomicPtr<AtomicUsize>,
+
+    /// Address of the shim function.
+    shim: *const c_void,
+
+    /// Link to the next function in the chain of the entry.
+    next: Link,
+}
+
+/// Maximum number of contexts in the stack of a thread.
+///
+/// The shim pops its context before running any other code, so the stack
+/// only grows with signal handlers that interrupt a call before its shim.
+const STACK_SIZE: usize = 32;
+
+/// Contexts pushed by the callbacks, waiting to be popped by the shims.
+///
+/// Every operation updates the length and the item in an order that keeps
+/// the stack consistent if a signal handler pushes and pops a context
+/// between them.
+struct ContextStack {
+    len: Cell<usize>,
+    items: Cell<[*const c_void; STACK_SIZE]>,
+}
+
+impl ContextStack {
+    fn push(&self, context: *const c_void) {
+        let len = self.len.get();
+        if len == STACK_SIZE {
+            // The callback can't fail, and there is no safe way to
+            // continue without the context.
+            std::process::abort();
+        }
+
+        self.len.set(len + 1);
+        atomic::compiler_fence(Ordering::SeqCst);
+        self.item(len).set(context);
+    }
+
+    fn pop(&self) -> *const c_void {
+        let len = self.len.get();
+        let context = self.item(len - 1).get();
+        atomic::compiler_fence(Ordering::SeqCst);
+        self.len.set(len - 1);
+        context
+    }
+
+    fn item(&self, index: usize) -> &Cell<*const c_void> {
+        let items: &Cell<[*const c_void]> = &self.items;
+        &items.as_slice_of_cells()[index]
+    }
+}
+
+thread_local! {
+    static CONTEXTS: ContextStack = const {
+        ContextStack {
+            len: Cell::new(0),
+            items: Cell::new([ptr::null(); STACK_SIZE]),
+        }
+    };
+}
+
+/// Callback for the trampolines. It returns the address of the shim, or
+/// the address of the next function if the thread is already running a
+/// hook.
+unsafe extern "C" fn enter(context: *const c_void) -> *const c_void {
+    let header = &*(context as *const Header);
+
+    if reentrancy::in_hook() {
+        return header.next.load(Ordering::SeqCst);
+    }
+
+    enter_chained(context)
+}
+
+/// Callback for the trampolines used by the previous replacement in the
+/// chain. It always returns the address of the shim.
+///
+/// The counter of running calls is incremented before the trampoline
+/// releases it, so the context is valid until the shim returns.
+unsafe extern "C" fn enter_chained(context: *const c_void) -> *const c_void {
+    let header = &*(context as *const Header);
+    (*header.calls.load(Ordering::SeqCst)).fetch_add(1, Ordering::SeqCst);
+    CONTEXTS.with(|c| c.push(context));
+    header.shim
+}
+
+/// A call running in a closure.
+///
+/// It has to be created at the beginning of the shim, before any other
+/// function can use `CONTEXTS`.
+struct Call<C: 'static> {
+    context: &'static Context<C>,
+    _guard: Option<HookGuard>,
+}
+
+impl<C> Call<C> {
+    unsafe fn current() -> Self {
+        let context = CONTEXTS.with(|c| c.pop()) as *const Context<C>;
+        Call {
+            context: &*context,
+            _guard: HookGuard::enter(),
+        }
+    }
+
+    fn next(&self) -> *const c_void {
+        self.context.header.next.load(Ordering::SeqCst)
+    }
+}
+
+impl<C> Drop for Call<C> {
+    fn drop(&mut self) {
+        // The context can't be used after this.
+        let calls = self.context.header.calls.load(Ordering::SeqCst);
+        unsafe { (*calls).fetch_sub(1, Ordering::SeqCst) };
+    }
+}
+
+macro_rules! impl_hook_fn {
+    ($($arg:ident: $ty:ident),*) => {
+        impl<R: 'static, $($ty: 'static),*> HookFn for extern "C" fn($($ty),*) -> R {}
+
+        impl<C, R: 'static, $($ty: 'static),*> ClosureHook<extern "C" fn($($ty),*) -> R> for C
+        where
+            C: Fn(extern "C" fn($($ty),*) -> R, $($ty),*) -> R + Send + Sync + 'static,
+        {
+            fn shim() -> *const c_void {
+                unsafe extern "C" fn shim<C, R, $($ty),*>($($arg: $ty),*) -> R
+                where
+                    C: Fn(extern "C" fn($($ty),*) -> R, $($ty),*) -> R + 'static,
+                {
+                    let call = Call::<C>::current();
+                    let next: extern "C" fn($($ty),*) -> R = std::mem::transmute(call.next());
+
+                    // A panic can't unwind through the trampoline and the
+                    // C caller.
+                    let closure = &call.context.closure;
+                    match panic::catch_unwind(AssertUnwindSafe(|| closure(next, $($arg),*))) {
+                        Ok(ret) => ret,
+                        Err(_) => std::process::abort(),
+                    }
+                }
+
+                shim::<C, R, $($ty),*> as *const c_void
+            }
+        }
+    };
+}
+
+impl_hook_fn!();
+impl_hook_fn!(a1: A1);
+impl_hook_fn!(a1: A1, a2: A2);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3, a4: A4);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7);
+impl_hook_fn!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8);
+
+/// Memory used by a closure hook.
+///
+/// The context is owned by the trampolines, so it is released when there
+/// are no calls running in the closure (see
+/// [trampolines](crate::trampoline#releasing-the-trampolines)).
+pub(crate) struct ClosureData {
+    _trampolines: Trampolines,
+}
+
+// The closure is `Send + Sync`, and the header is only accessed with atomic
+// operations.
+unsafe impl<C: Send> Send for Context<C> {}
+
+impl ClosureData {
+    /// Create the trampolines for `closure`, and returns the hook to
+    /// install them.
+    pub(crate) fn hook<F, C>(closure: C) -> Result<Hook>
+    where
+        F: HookFn,
+        C: ClosureHook<F>,
+    {
+        let next = crate::chain::new_link();
+
+        let context = Box::new(Context {
+            header: Header {
+                calls: AtomicPtr::new(ptr::null_mut()),
+                shim: C::shim(),
+                next: Arc::clone(&next),
+            },
+            closure,
+        });
+
+        let header = &context.header as *const Header;
+        let ptr = header as *const c_void;
+        let targets = [(ptr, enter as Callback, &next), (ptr, enter_chained, &next)];
+        let trampolines = Trampolines::new(&targets, context)?;
+
+        // The trampolines are not installed yet, so the header is not
+        // used by any other thread.
+        let calls = trampolines.calls() as *mut AtomicUsize;
+        unsafe { (*header).calls.store(calls, Ordering::SeqCst) };
+
+        let address = trampolines.address(0);
+        let chained = trampolines.address(1);
+
+        Ok(Hook {
+            address,
+            chained,
+            next,
+            data: Some(Box::new(ClosureData {
+                _trampolines: trampolines,
+            })),
+        })
+    }
+}
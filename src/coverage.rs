@@ -0,0 +1,100 @@
+//! Check if a replacement can see every call to a function.
+//!
+//! Replacing a PLT entry only affects the calls that go through it. Calls
+//! inside the object that defines the function, or calls through other
+//! entries of the GOT, are not affected. The information is read from the
+//! dynamic section of the object, so the code is not disassembled.
+
+use std::ffi::CStr;
+
+use crate::elf::{self, Table};
+use crate::errors::{Error, ErrorKind, Result};
+use crate::ObjectFile;
+
+/// How the calls to a function are resolved in an object.
+///
+/// It is returned by [`ObjectFile::coverage_check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    /// The object defines the function itself.
+    pub defined: bool,
+
+    /// The object has a `JUMP_SLOT` entry for the function, used by the
+    /// PLT.
+    pub plt_entry: bool,
+
+    /// The object has a `GLOB_DAT` entry for the function in the GOT, used
+    /// to get its address, or to call it without the PLT (`-fno-plt`).
+    pub got_entry: bool,
+
+    /// The object is linked with `-z now`, so the entries are resolved when
+    /// the object is loaded.
+    pub bind_now: bool,
+}
+
+/// A reason why a replacement may not see every call to a function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageWarning {
+    /// The object defines the function, so its own calls may go directly
+    /// to the function, without the PLT.
+    DefinedLocally,
+
+    /// The function has both a PLT entry and a GOT entry. A replacement
+    /// only writes the first one, so the calls through the GOT (and the
+    /// function pointers taken by the object) are missed.
+    PltAndGot,
+
+    /// The object is linked with `-z now`, and the function is only called
+    /// through its GOT entry, like code built with `-fno-plt`. A
+    /// replacement sees every call, but it also changes the address of the
+    /// function seen by the object.
+    GotOnly,
+
+    /// The object has no entry for the function, so it can't be replaced.
+    NotImported,
+}
+
+impl Coverage {
+    /// Returns the reasons why a replacement may be incomplete.
+    pub fn warnings(&self) -> Vec<CoverageWarning> {
+        let mut warnings = Vec::new();
+
+        if self.defined {
+            warnings.push(CoverageWarning::DefinedLocally);
+        }
+
+        match (self.plt_entry, self.got_entry) {
+            (true, true) => warnings.push(CoverageWarning::PltAndGot),
+            (false, true) if self.bind_now => warnings.push(CoverageWarning::GotOnly),
+            (false, false) => warnings.push(CoverageWarning::NotImported),
+            _ => (),
+        }
+
+        warnings
+    }
+
+    /// Returns `true` if there are no [warnings](Self::warnings).
+    pub fn is_complete(&self) -> bool {
+        self.warnings().is_empty()
+    }
+}
+
+/// Returns the coverage of the function `name` in `object`.
+pub(crate) fn check(object: &ObjectFile, name: &CStr) -> Result<Coverage> {
+    let elf = match elf::Object::of(object) {
+        Some(elf) => elf,
+        None => {
+            let msg = "Could not read the dynamic section of the object".to_string();
+            return Err(object.error_context(Error::new(ErrorKind::InternalError, msg), name));
+        }
+    };
+
+    let tables = elf.relocation_tables(name);
+
+    Ok(Coverage {
+        defined: matches!(elf.find_symbol(name), Some(s) if s.defined),
+        plt_entry: tables.contains(&Table::Plt),
+        got_entry: tables.contains(&Table::Dynamic),
+        bind_now: elf.bind_now(),
+    })
+}
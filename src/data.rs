@@ -0,0 +1,103 @@
+//! Replace pointers to data symbols.
+//!
+//! Data symbols imported by an object (like `environ` or `stdout`) are
+//! accessed through `R_*_GLOB_DAT` entries, which contain the address of the
+//! variable. These entries are also enumerated by `plthook_enum_with_prot`,
+//! so the type of the symbol is read from the `.dynsym` section to
+//! distinguish them from function pointers.
+
+use std::ffi::{c_void, CStr};
+use std::marker::PhantomData;
+use std::result;
+
+use crate::elf;
+use crate::errors::{Error, ErrorKind, RestoreError, Result};
+use crate::{symbols, ObjectFile, Replacement, RestorePolicy, Symbol};
+
+/// Returns the entry for the data symbol `wanted`.
+pub(crate) fn find(object: &ObjectFile, wanted: &CStr) -> Result<Symbol> {
+    let mut found = false;
+
+    for symbol in symbols::find_all(object, wanted) {
+        found = true;
+
+        let slot = symbol.func_address as *const c_void;
+        let is_data = elf::Object::containing(slot)
+            .and_then(|o| o.relocation_symbol(slot).map(|s| s.kind == elf::STT_OBJECT))
+            .unwrap_or(false);
+
+        if is_data {
+            return Ok(symbol);
+        }
+    }
+
+    let name = wanted.to_string_lossy();
+    let error = if found {
+        let msg = format!("not a data symbol: {name}");
+        Error::new(ErrorKind::InvalidArgument, msg)
+    } else {
+        let msg = format!("no such symbol: {name}");
+        Error::new(ErrorKind::FunctionNotFound, msg)
+    };
+
+    Err(object.error_context(error, wanted))
+}
+
+/// A replacement of the address of a data symbol.
+///
+/// It is created by [`ObjectFile::replace_data`]. The address is restored
+/// when this value is dropped, like [`Replacement`].
+pub struct DataReplacement<T> {
+    replacement: Replacement,
+    _type: PhantomData<fn() -> *mut T>,
+}
+
+impl<T> DataReplacement<T> {
+    pub(crate) fn new(replacement: Replacement) -> Self {
+        DataReplacement {
+            replacement,
+            _type: PhantomData,
+        }
+    }
+
+    /// Returns the address of the original variable.
+    ///
+    /// If there are other replacements in the same entry, this is the address
+    /// installed by the next one. See [`Replacement::original_address`].
+    pub fn original(&self) -> *mut T {
+        self.replacement.original_address() as *mut T
+    }
+
+    /// Discard this replacement, so the original address will not be restored
+    /// when it is dropped. See [`Replacement::discard`].
+    pub fn discard(&mut self) {
+        self.replacement.discard();
+    }
+
+    /// Set the policy used when this replacement is dropped. See
+    /// [`Replacement::set_restore_policy`].
+    pub fn set_restore_policy(&mut self, policy: RestorePolicy) {
+        self.replacement.set_restore_policy(policy);
+    }
+
+    /// Suspend this replacement. See [`Replacement::suspend`].
+    pub fn suspend(&mut self) -> result::Result<(), RestoreError> {
+        self.replacement.suspend()
+    }
+
+    /// Resume this replacement. See [`Replacement::resume`].
+    pub fn resume(&mut self) -> result::Result<(), RestoreError> {
+        self.replacement.resume()
+    }
+
+    /// Returns `true` if the replacement is installed and not suspended.
+    /// See [`Replacement::is_active`].
+    pub fn is_active(&self) -> bool {
+        self.replacement.is_active()
+    }
+
+    /// Restore the original address. See [`Replacement::restore`].
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        self.replacement.restore()
+    }
+}
@@ -0,0 +1,760 @@
+//! Replace functions by patching their code (x86_64 Linux).
+//!
+//! PLT hooks only see the calls made through the PLT entries of an object.
+//! Calls inside a library (like calls to static functions, or objects
+//! built with `-fno-plt`) are not affected by them. A detour replaces the
+//! first instructions of the function with a jump, so every call is
+//! redirected.
+//!
+//! The jump goes to a relay, allocated near the function, which jumps to
+//! the address stored in a pointer-sized entry. This entry is managed like
+//! a PLT entry, so detours are installed in a chain, and they return a
+//! [`Replacement`] with the same interface of the PLT hooks.
+//!
+//! The instructions overwritten by the jump are copied to a trampoline,
+//! followed by a jump to the rest of the function. The trampoline is the
+//! original address of the first replacement (see
+//! [`Replacement::original_address`]).
+//!
+//! This module is available with the `detour` feature, on Linux for x86_64.
+//!
+//! # Limitations
+//!
+//! Only the common instructions of the function prologues can be copied to
+//! the trampoline. If the first 5 bytes of the function contain any other
+//! instruction, an error with [`ErrorKind::NotImplemented`] is returned.
+//!
+//! The code of the function is not modified atomically, so it should not
+//! be running in other threads while the detour is installed or removed.
+//! Jumps from the rest of the function to its first 5 bytes are not
+//! detected.
+//!
+//! When the last detour of a function is removed, its code is restored,
+//! but the trampoline is never released, because other threads may still
+//! be running it. If the jump was overwritten by someone else, the code is
+//! not restored.
+//!
+//! # Example
+//!
+//! ```
+//! use plthook::detour;
+//!
+//! #[inline(never)]
+//! extern "C" fn triangle(n: u32) -> u32 {
+//!     (1..=n).fold(0, u32::wrapping_add)
+//! }
+//!
+//! extern "C" fn identity(n: u32) -> u32 {
+//!     n
+//! }
+//!
+//! // Call through a pointer, so the compiler can't inline the function.
+//! let call = |n| {
+//!     let f: extern "C" fn(u32) -> u32 = unsafe { std::ptr::read_volatile(&(triangle as _)) };
+//!     f(n)
+//! };
+//!
+//! let replacement = unsafe { detour::replace(triangle as *const _, identity as *const _).unwrap() };
+//! assert_eq!(call(4), 4);
+//!
+//! let original: extern "C" fn(u32) -> u32 =
+//!     unsafe { std::mem::transmute(replacement.original_address()) };
+//! assert_eq!(original(4), 10);
+//!
+//! drop(replacement);
+//! assert_eq!(call(4), 10);
+//! ```
+
+use std::convert::{TryFrom, TryInto};
+use std::ffi::{c_void, CStr, CString, OsStr};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::{io, ptr, thread};
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::{install_batch, slot, BatchItem, Hook, ReplaceOptions, Replacement};
+
+/// Size of the jump written at the beginning of the function
+/// (`jmp rel32`).
+const JUMP_SIZE: usize = 5;
+
+/// Offset of the trampoline in the code page. The relay is at offset `0`.
+const TRAMPOLINE_OFFSET: usize = 16;
+
+/// Maximum distance to the function for `rel32` jumps and displacements.
+const MAX_DISTANCE: usize = i32::MAX as usize - (1 << 20);
+
+/// Replace the function at `function` with `hook`.
+///
+/// The returned [`Replacement`] restores the function when it is dropped.
+///
+/// # Safety
+///
+/// `function` has to be the address of a function, and `hook` a function
+/// with the same signature. See also the [limitations](self#limitations).
+pub unsafe fn replace(function: *const c_void, hook: *const c_void) -> Result<Replacement> {
+    replace_with_options(function, hook, &ReplaceOptions::default())
+}
+
+/// Like [`replace`], but with the priority from `options`.
+///
+/// See [`ObjectFile::replace_with_priority`](crate::ObjectFile::replace_with_priority).
+/// The protection policy has no effect, because the entry used by the relay
+/// is always writable, and the code of the function is always restored to
+/// its previous protection.
+///
+/// # Safety
+///
+/// See [`replace`].
+pub unsafe fn replace_with_options(
+    function: *const c_void,
+    hook: *const c_void,
+    options: &ReplaceOptions,
+) -> Result<Replacement> {
+    if function.is_null() || hook.is_null() {
+        let msg = "function and hook addresses can't be null".to_string();
+        return Err(Error::new(ErrorKind::InvalidArgument, msg).with_address(function));
+    }
+
+    let patch = Patch::for_function(function as usize)?;
+
+    let item = BatchItem {
+        symbol_name: patch.symbol.clone(),
+        slot: patch.entry,
+        prot: libc::PROT_READ | libc::PROT_WRITE,
+        object_path: patch.object_path.as_ref(),
+        hook: Hook {
+            data: Some(Box::new(Arc::clone(&patch))),
+            ..Hook::new(hook)
+        },
+    };
+
+    let mut replacements = install_batch(vec![item], options)?;
+    Ok(replacements.remove(0))
+}
+
+/// Patches installed in the functions. There is one for every function,
+/// shared by all of its detours.
+static PATCHES: Mutex<Vec<(usize, Weak<Patch>)>> = Mutex::new(Vec::new());
+
+/// A function whose first instructions are replaced by a jump to a relay.
+struct Patch {
+    function: usize,
+
+    /// Bytes overwritten by the jump.
+    original: [u8; JUMP_SIZE],
+
+    /// Jump written at the beginning of the function.
+    jump: [u8; JUMP_SIZE],
+
+    /// Entry with the address used by the relay.
+    entry: *const *const c_void,
+
+    symbol: CString,
+    object_path: Option<PathBuf>,
+}
+
+// The fields are never modified after `Patch::install`. The entry is only
+// written by the chain of the registry, like a PLT entry.
+unsafe impl Send for Patch {}
+unsafe impl Sync for Patch {}
+
+impl Patch {
+    /// Returns the patch of `function`, or creates a new one.
+    fn for_function(function: usize) -> Result<Arc<Patch>> {
+        loop {
+            let mut patches = PATCHES.lock().unwrap_or_else(|e| e.into_inner());
+
+            match patches.iter().find(|(f, _)| *f == function) {
+                Some((_, patch)) => {
+                    if let Some(patch) = patch.upgrade() {
+                        return Ok(patch);
+                    }
+                }
+
+                None => {
+                    let patch = Arc::new(unsafe { Patch::install(function)? });
+                    patches.push((function, Arc::downgrade(&patch)));
+                    return Ok(patch);
+                }
+            }
+
+            // The last detour was removed, but the code of the function is
+            // not restored yet.
+            drop(patches);
+            thread::yield_now();
+        }
+    }
+
+    /// Copy the first instructions of `function` to a trampoline, and
+    /// replace them with a jump to a relay.
+    unsafe fn install(function: usize) -> Result<Patch> {
+        let (symbol, object_path) = describe(function);
+        let context = |error: Error| {
+            error
+                .with_symbol(&symbol.to_string_lossy())
+                .with_object_path(object_path.as_deref())
+                .with_address(function as *const c_void)
+        };
+
+        let prologue = read_prologue(function);
+
+        let page_size = slot::page_size();
+        let memory = match allocate_near(function, page_size) {
+            Ok(m) => m,
+            Err(e) => {
+                let msg = format!("Could not allocate trampoline: {e}");
+                return Err(context(
+                    Error::new(ErrorKind::OutOfMemory, msg).with_source(e),
+                ));
+            }
+        };
+
+        let relay = memory as usize;
+        let trampoline = relay + TRAMPOLINE_OFFSET;
+        let entry = relay + page_size;
+
+        let (copied, mut code) = match relocate(&prologue, function, trampoline) {
+            Ok(r) => r,
+            Err(e) => {
+                libc::munmap(memory, 2 * page_size);
+                return Err(context(e));
+            }
+        };
+
+        // Jump to the rest of the function.
+        code.extend_from_slice(&[0xff, 0x25, 0, 0, 0, 0]);
+        code.extend_from_slice(&(function + copied).to_ne_bytes());
+
+        // The relay jumps to the address in the entry: `jmp [rip + disp32]`.
+        let mut relay_code = vec![0xff, 0x25];
+        relay_code.extend_from_slice(&((entry - (relay + 6)) as i32).to_ne_bytes());
+
+        ptr::copy_nonoverlapping(relay_code.as_ptr(), relay as *mut u8, relay_code.len());
+        ptr::copy_nonoverlapping(code.as_ptr(), trampoline as *mut u8, code.len());
+        *(entry as *mut usize) = trampoline;
+
+        if libc::mprotect(memory, page_size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            let error = io::Error::last_os_error();
+            libc::munmap(memory, 2 * page_size);
+            return Err(context(slot::protection_error(memory, error)));
+        }
+
+        let mut jump = [0; JUMP_SIZE];
+        jump[0] = 0xe9;
+        jump[1..].copy_from_slice(&(relay.wrapping_sub(function + JUMP_SIZE) as i32).to_ne_bytes());
+
+        let mut original = [0; JUMP_SIZE];
+        original.copy_from_slice(&prologue[..JUMP_SIZE]);
+
+        // The instructions copied to the trampoline can't be modified
+        // before the jump is written.
+        match write_code(function, Some(&prologue[..copied]), &jump) {
+            Ok(true) => (),
+            Ok(false) => {
+                libc::munmap(memory, 2 * page_size);
+                let msg = "the code was modified while the detour was installed".to_string();
+                return Err(context(Error::new(ErrorKind::InternalError, msg)));
+            }
+            Err(e) => {
+                libc::munmap(memory, 2 * page_size);
+                return Err(context(e));
+            }
+        }
+
+        Ok(Patch {
+            function,
+            original,
+            jump,
+            entry: entry as *const *const c_void,
+            symbol,
+            object_path,
+        })
+    }
+}
+
+impl Drop for Patch {
+    fn drop(&mut self) {
+        let mut patches = PATCHES.lock().unwrap_or_else(|e| e.into_inner());
+
+        // If the jump was overwritten by someone else (like another detour
+        // library), the code is not restored. The relay is never unmapped,
+        // so a jump to it that was copied elsewhere is still valid.
+        //
+        // The entry contains the address of the trampoline, so the code
+        // can be restored while other threads are using the relay.
+        let _ = unsafe { write_code(self.function, Some(&self.jump), &self.original) };
+
+        patches.retain(|(f, _)| *f != self.function);
+    }
+}
+
+/// Returns the name of the symbol at `function`, and the path of its
+/// object.
+fn describe(function: usize) -> (CString, Option<PathBuf>) {
+    let mut info = MaybeUninit::<libc::Dl_info>::uninit();
+    let found = unsafe { libc::dladdr(function as *const c_void, info.as_mut_ptr()) } != 0;
+
+    let mut symbol = None;
+    let mut object_path = None;
+
+    if found {
+        let info = unsafe { info.assume_init() };
+
+        if !info.dli_sname.is_null() && info.dli_saddr as usize == function {
+            symbol = Some(unsafe { CStr::from_ptr(info.dli_sname) }.to_owned());
+        }
+
+        if !info.dli_fname.is_null() {
+            let path = unsafe { CStr::from_ptr(info.dli_fname) };
+            object_path = Some(PathBuf::from(OsStr::from_bytes(path.to_bytes())));
+        }
+    }
+
+    let symbol = symbol.unwrap_or_else(|| {
+        CString::new(format!("{:?}", function as *const c_void)).unwrap_or_default()
+    });
+
+    (symbol, object_path)
+}
+
+/// Write `bytes` in the code at `address`, restoring the protection of its
+/// pages.
+///
+/// If `expected` is given, the code is only written if it contains those
+/// bytes. The code is compared while holding the lock used by other
+/// writers. Returns `false` if the code was not written.
+pub(crate) unsafe fn write_code(
+    address: usize,
+    expected: Option<&[u8]>,
+    bytes: &[u8],
+) -> Result<bool> {
+    let _guard = slot::lock_protection();
+
+    if let Some(expected) = expected {
+        if std::slice::from_raw_parts(address as *const u8, expected.len()) != expected {
+            return Ok(false);
+        }
+    }
+
+    let page_size = slot::page_size();
+    let start = address & !(page_size - 1);
+    let end = (address + bytes.len() + page_size - 1) & !(page_size - 1);
+
+    // The bytes can cross a page boundary, and each page can have a
+    // different protection.
+    let mut pages = Vec::with_capacity((end - start) / page_size);
+    for page in (start..end).step_by(page_size) {
+        match slot::maps_protection(page) {
+            Some(prot) => pages.push((page, prot)),
+            None => {
+                let msg = format!("Could not get the process memory permission at {page:#x}");
+                let error = Error::new(ErrorKind::InternalError, msg);
+                return Err(error.with_address(page as *const ()));
+            }
+        }
+    }
+
+    for (index, &(page, prot)) in pages.iter().enumerate() {
+        if libc::mprotect(page as *mut _, page_size, prot | libc::PROT_WRITE) != 0 {
+            let error = io::Error::last_os_error();
+            for &(page, prot) in &pages[..index] {
+                libc::mprotect(page as *mut _, page_size, prot);
+            }
+            return Err(slot::protection_error(page as *const c_void, error));
+        }
+    }
+
+    // If the bytes are in a single aligned word, it is written at once, so
+    // other threads never see a partial jump.
+    let word = address & !7;
+    if address + bytes.len() <= word + 8 {
+        let mut value = ptr::read(word as *const [u8; 8]);
+        value[address - word..][..bytes.len()].copy_from_slice(bytes);
+        let atomic = &*(word as *const std::sync::atomic::AtomicU64);
+        atomic.store(
+            u64::from_ne_bytes(value),
+            std::sync::atomic::Ordering::SeqCst,
+        );
+    } else {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len());
+    }
+
+    let mut result = Ok(true);
+    for &(page, prot) in &pages {
+        if libc::mprotect(page as *mut _, page_size, prot) != 0 && result.is_ok() {
+            let error = io::Error::last_os_error();
+            result = Err(slot::protection_error(page as *const c_void, error));
+        }
+    }
+
+    result
+}
+
+/// Allocate two pages at less than 2 GiB from `address`, so they can be
+/// reached with `rel32` jumps. The first page is for the code, and the
+/// second one for the entry.
+unsafe fn allocate_near(address: usize, page_size: usize) -> io::Result<*mut c_void> {
+    const STEP: usize = 1 << 24;
+
+    let len = 2 * page_size;
+    let base = address & !(STEP - 1);
+
+    for i in 1..(MAX_DISTANCE / STEP) {
+        for hint in [base.checked_sub(i * STEP), base.checked_add(i * STEP)] {
+            let hint = match hint {
+                Some(h) => h,
+                None => continue,
+            };
+
+            let memory = libc::mmap(
+                hint as *mut c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+
+            // The hint may be refused (like below `vm.mmap_min_addr`)
+            // without affecting the others.
+            if memory == libc::MAP_FAILED {
+                continue;
+            }
+
+            if (memory as usize).abs_diff(address) < MAX_DISTANCE {
+                return Ok(memory);
+            }
+
+            libc::munmap(memory, len);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        "no free memory near the function",
+    ))
+}
+
+/// An instruction decoded by [`decode`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Instruction {
+    pub(crate) len: usize,
+
+    /// Offset of a displacement relative to the next instruction: a
+    /// RIP-relative operand, or the target of a `rel32` branch.
+    pub(crate) rel32: Option<usize>,
+
+    /// `true` for `jmp rel8` and `jcc rel8`.
+    pub(crate) rel8: bool,
+
+    /// `true` if the next instruction is not executed after this one
+    /// (`ret` and unconditional jumps).
+    pub(crate) ends_flow: bool,
+}
+
+/// Result of [`decode`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Decoded {
+    Instruction(Instruction),
+
+    /// `code` ends before the end of the instruction.
+    Incomplete,
+
+    /// The instruction is not supported.
+    Unsupported,
+}
+
+/// Decode the length and the relative operands of the instruction at the
+/// beginning of `code`.
+///
+/// Only the instructions usually found in function prologues are
+/// supported. It returns [`Decoded::Unsupported`] for any other
+/// instruction.
+pub(crate) fn decode(code: &[u8]) -> Decoded {
+    macro_rules! byte {
+        ($i:expr) => {
+            match code.get($i) {
+                Some(&b) => b,
+                None => return Decoded::Incomplete,
+            }
+        };
+    }
+
+    let mut i = 0;
+    let mut operand16 = false;
+
+    loop {
+        match byte!(i) {
+            0x66 => operand16 = true,
+            0x67 => return Decoded::Unsupported,
+            0xf0 | 0xf2 | 0xf3 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => (),
+            _ => break,
+        }
+        i += 1;
+    }
+
+    let mut rex_w = false;
+    if byte!(i) & 0xf0 == 0x40 {
+        rex_w = code[i] & 0x08 != 0;
+        i += 1;
+    }
+
+    let imm32 = if operand16 { 2 } else { 4 };
+    let opcode = byte!(i);
+    i += 1;
+
+    let mut insn = Instruction {
+        len: 0,
+        rel32: None,
+        rel8: false,
+        ends_flow: false,
+    };
+
+    // ModR/M byte and size of the immediate.
+    let (modrm, imm) = match opcode {
+        0x0f => {
+            let opcode = byte!(i);
+            i += 1;
+
+            match opcode {
+                0x05 | 0x0b | 0x31 | 0x77 | 0xa0 | 0xa1 | 0xa2 | 0xa8 | 0xa9 | 0xc8..=0xcf => {
+                    (false, 0)
+                }
+                0x80..=0x8f => {
+                    insn.rel32 = Some(i);
+                    (false, 4)
+                }
+                0x38 => {
+                    i += 1;
+                    (true, 0)
+                }
+                0x3a => {
+                    i += 1;
+                    (true, 1)
+                }
+                0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => (true, 1),
+                0x0f | 0x04 | 0x0a | 0x0c | 0x24..=0x27 | 0x36 | 0x39 | 0x3b..=0x3f => {
+                    return Decoded::Unsupported
+                }
+                _ => (true, 0),
+            }
+        }
+
+        0x00..=0x3f => match opcode & 7 {
+            0..=3 => (true, 0),
+            4 => (false, 1),
+            5 => (false, imm32),
+            _ => return Decoded::Unsupported,
+        },
+        0x50..=0x5f | 0x90..=0x99 | 0x9c..=0x9f => (false, 0),
+        0x63 | 0x84..=0x8f | 0xd0..=0xd3 | 0xd8..=0xdf | 0xfe => (true, 0),
+        0x68 => (false, imm32),
+        0x69 | 0x81 | 0xc7 => (true, imm32),
+        0x6a | 0xa8 | 0xb0..=0xb7 | 0xcd => (false, 1),
+        0x6b | 0x80 | 0x83 | 0xc0 | 0xc1 | 0xc6 => (true, 1),
+        0x70..=0x7f => {
+            insn.rel8 = true;
+            (false, 1)
+        }
+        0xa9 => (false, imm32),
+        0xb8..=0xbf => (false, if rex_w { 8 } else { imm32 }),
+        0xc2 => {
+            insn.ends_flow = true;
+            (false, 2)
+        }
+        0xc3 => {
+            insn.ends_flow = true;
+            (false, 0)
+        }
+        0xc8 => (false, 3),
+        0xc9 | 0xcc | 0xf4 | 0xf5 | 0xf8..=0xfd => (false, 0),
+        0xe8 => {
+            insn.rel32 = Some(i);
+            (false, 4)
+        }
+        0xe9 => {
+            insn.rel32 = Some(i);
+            insn.ends_flow = true;
+            (false, 4)
+        }
+        0xeb => {
+            insn.rel8 = true;
+            insn.ends_flow = true;
+            (false, 1)
+        }
+        0xf6 | 0xf7 => {
+            let reg = (byte!(i) >> 3) & 7;
+            match (opcode, reg) {
+                (0xf6, 0 | 1) => (true, 1),
+                (0xf7, 0 | 1) => (true, imm32),
+                _ => (true, 0),
+            }
+        }
+        0xff => {
+            let reg = (byte!(i) >> 3) & 7;
+            insn.ends_flow = reg == 4 || reg == 5;
+            (true, 0)
+        }
+        _ => return Decoded::Unsupported,
+    };
+
+    if modrm {
+        let modrm = byte!(i);
+        i += 1;
+
+        let mode = modrm >> 6;
+        let rm = modrm & 7;
+
+        if mode != 3 {
+            if rm == 4 {
+                let sib = byte!(i);
+                i += 1;
+                if mode == 0 && sib & 7 == 5 {
+                    i += 4;
+                }
+            } else if mode == 0 && rm == 5 {
+                insn.rel32 = Some(i);
+                i += 4;
+            }
+
+            match mode {
+                1 => i += 1,
+                2 => i += 4,
+                _ => (),
+            }
+        }
+    }
+
+    insn.len = i + imm;
+    if insn.rel8 && insn.len != 2 {
+        return Decoded::Unsupported;
+    }
+
+    if insn.len > code.len() {
+        return Decoded::Incomplete;
+    }
+
+    Decoded::Instruction(insn)
+}
+
+/// Copy the instructions at the beginning of `code`, which is at
+/// `source`, to a trampoline at `target`, until there are enough bytes for
+/// the jump.
+///
+/// It returns the number of bytes copied from `code`, and the code of the
+/// trampoline. The relative operands are adjusted for the new address, and
+/// the `rel8` jumps are converted to `rel32`.
+pub(crate) fn relocate(code: &[u8], source: usize, target: usize) -> Result<(usize, Vec<u8>)> {
+    let mut offset = 0;
+    let mut output = Vec::new();
+    let mut branches = Vec::new();
+
+    while offset < JUMP_SIZE {
+        let insn = match decode(&code[offset..]) {
+            Decoded::Instruction(insn) => insn,
+            Decoded::Incomplete | Decoded::Unsupported => {
+                let msg = format!(
+                    "unsupported instruction at {:#x}: {:02x?}",
+                    source + offset,
+                    &code[offset..][..8.min(code.len() - offset)]
+                );
+                return Err(Error::new(ErrorKind::NotImplemented, msg));
+            }
+        };
+
+        let bytes = &code[offset..offset + insn.len];
+        let next = source + offset + insn.len;
+        let new_address = target + output.len();
+
+        let (mut new_bytes, disp_offset, destination) = if insn.rel8 {
+            let destination = next.wrapping_add(bytes[1] as i8 as usize);
+            if bytes[0] == 0xeb {
+                (vec![0xe9, 0, 0, 0, 0], 1, destination)
+            } else {
+                (vec![0x0f, bytes[0] + 0x10, 0, 0, 0, 0], 2, destination)
+            }
+        } else if let Some(disp_offset) = insn.rel32 {
+            let disp = i32::from_ne_bytes(bytes[disp_offset..][..4].try_into().unwrap());
+            let destination = next.wrapping_add(disp as usize);
+            (bytes.to_vec(), disp_offset, destination)
+        } else {
+            output.extend_from_slice(bytes);
+            offset += insn.len;
+
+            if insn.ends_flow && offset < JUMP_SIZE {
+                return Err(too_short(source));
+            }
+            continue;
+        };
+
+        // The copied bytes are checked when all instructions are decoded.
+        branches.push((source + offset, destination));
+
+        // The displacement is relative to the end of the instruction, which
+        // can have an immediate after it.
+        let new_next = new_address + new_bytes.len();
+        let disp = destination.wrapping_sub(new_next) as isize;
+        let disp = match i32::try_from(disp) {
+            Ok(d) => d,
+            Err(_) => {
+                let msg = format!("relative operand at {:#x} is out of range", source + offset);
+                return Err(Error::new(ErrorKind::NotImplemented, msg));
+            }
+        };
+
+        new_bytes[disp_offset..][..4].copy_from_slice(&disp.to_ne_bytes());
+        output.extend_from_slice(&new_bytes);
+        offset += insn.len;
+
+        if insn.ends_flow && offset < JUMP_SIZE {
+            return Err(too_short(source));
+        }
+    }
+
+    // A branch to the copied instructions would run the jump, or a part of
+    // it, instead of the original code.
+    for (address, destination) in branches {
+        if destination >= source && destination < source + offset {
+            let msg = format!("jump to the patched bytes at {address:#x}");
+            return Err(Error::new(ErrorKind::NotImplemented, msg));
+        }
+    }
+
+    Ok((offset, output))
+}
+
+/// Read the instructions at the beginning of `function` that have to be
+/// copied to the trampoline.
+///
+/// The bytes are read one at a time, so the memory after the last
+/// instruction is never read. The reading stops at the first unsupported
+/// instruction, which is reported by [`relocate`].
+unsafe fn read_prologue(function: usize) -> Vec<u8> {
+    let mut code = Vec::new();
+    let mut start = 0;
+
+    while start < JUMP_SIZE {
+        code.push(ptr::read((function + code.len()) as *const u8));
+
+        match decode(&code[start..]) {
+            Decoded::Instruction(insn) => {
+                start += insn.len;
+                if insn.ends_flow {
+                    break;
+                }
+            }
+            Decoded::Incomplete => (),
+            Decoded::Unsupported => break,
+        }
+    }
+
+    code
+}
+
+fn too_short(function: usize) -> Error {
+    let msg = format!("function at {function:#x} is too short to be patched");
+    Error::new(ErrorKind::InvalidArgument, msg)
+}
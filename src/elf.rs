@@ -0,0 +1,413 @@
+//! Read the dynamic section of ELF objects loaded in memory.
+//!
+//! The `plthook` library does not expose the symbol table of the objects,
+//! so it is read from the dynamic section of the object, found with
+//! `dl_iterate_phdr`. `dladdr1` would give the `link_map` directly, but it
+//! is only available in glibc.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::{mem, slice};
+
+/// Symbol type for data objects (`STT_OBJECT`).
+pub(crate) const STT_OBJECT: u8 = 1;
+
+/// Symbol type for functions (`STT_FUNC`).
+pub(crate) const STT_FUNC: u8 = 2;
+
+/// Symbol type for indirect functions (`STT_GNU_IFUNC`).
+pub(crate) const STT_GNU_IFUNC: u8 = 10;
+
+const DT_NULL: isize = 0;
+const DT_PLTRELSZ: isize = 2;
+const DT_HASH: isize = 4;
+const DT_STRTAB: isize = 5;
+const DT_SYMTAB: isize = 6;
+const DT_RELA: isize = 7;
+const DT_RELASZ: isize = 8;
+const DT_REL: isize = 17;
+const DT_RELSZ: isize = 18;
+const DT_PLTREL: isize = 20;
+const DT_JMPREL: isize = 23;
+const DT_BIND_NOW: isize = 24;
+const DT_FLAGS: isize = 30;
+const DT_GNU_HASH: isize = 0x6fff_fef5;
+const DT_FLAGS_1: isize = 0x6fff_fffb;
+
+const DF_BIND_NOW: usize = 0x8;
+const DF_1_NOW: usize = 0x1;
+
+/// Section index of undefined symbols.
+const SHN_UNDEF: u16 = 0;
+
+// Not all fields of the C structs are used.
+
+#[repr(C)]
+struct Dyn {
+    d_tag: isize,
+    d_val: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+#[allow(dead_code)]
+#[repr(C)]
+struct Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+#[cfg(target_pointer_width = "32")]
+#[allow(dead_code)]
+#[repr(C)]
+struct Sym {
+    st_name: u32,
+    st_value: u32,
+    st_size: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+/// `Elf_Rel` and the first two fields of `Elf_Rela`.
+#[repr(C)]
+struct Rel {
+    r_offset: usize,
+    r_info: usize,
+}
+
+/// An object loaded in the process.
+pub(crate) struct Object {
+    load_address: usize,
+    name: *const c_char,
+    dynamic: *const Dyn,
+
+    /// Address ranges of the `PT_LOAD` segments.
+    segments: Vec<(usize, usize)>,
+}
+
+/// A symbol from the `.dynsym` section.
+pub(crate) struct DynSymbol {
+    pub(crate) kind: u8,
+
+    /// `true` if the symbol is defined in the object.
+    pub(crate) defined: bool,
+}
+
+/// Relocation tables of an object.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Table {
+    /// `DT_JMPREL`, with the `JUMP_SLOT` entries used by the PLT.
+    Plt,
+
+    /// `DT_RELA` or `DT_REL`, with the `GLOB_DAT` entries of the GOT.
+    Dynamic,
+}
+
+impl Object {
+    /// Returns the object that contains `address`.
+    pub(crate) fn containing(address: *const c_void) -> Option<Object> {
+        loaded().into_iter().find(|object| object.contains(address))
+    }
+
+    /// Returns `true` if `address` is in a loadable segment of the object.
+    pub(crate) fn contains(&self, address: *const c_void) -> bool {
+        let address = address as usize;
+
+        self.segments
+            .iter()
+            .any(|&(start, end)| start <= address && address < end)
+    }
+
+    /// Returns the object opened as `object`.
+    ///
+    /// The object is found from any of its PLT entries, so it returns `None`
+    /// if there are no entries.
+    pub(crate) fn of(object: &crate::ObjectFile) -> Option<Object> {
+        crate::symbols::iterator(object).find_map(|s| Object::containing(s.func_address.cast()))
+    }
+
+    /// Address ranges of the `PT_LOAD` segments.
+    pub(crate) fn segments(&self) -> &[(usize, usize)] {
+        &self.segments
+    }
+
+    /// Returns `true` if both values refer to the same object.
+    pub(crate) fn is(&self, other: &Object) -> bool {
+        self.dynamic == other.dynamic
+    }
+
+    /// Name of the object, as given to the dynamic loader. It is empty for
+    /// the main program.
+    pub(crate) fn name(&self) -> &CStr {
+        if self.name.is_null() {
+            return Default::default();
+        }
+
+        unsafe { CStr::from_ptr(self.name) }
+    }
+
+    /// Returns the symbol used by the relocation at `address`.
+    pub(crate) fn relocation_symbol(&self, address: *const c_void) -> Option<DynSymbol> {
+        let offset = (address as usize).wrapping_sub(self.load_address());
+
+        let info = self
+            .relocations()
+            .find(|rel| rel.r_offset == offset)?
+            .r_info;
+
+        self.symbol(symbol_index(info))
+    }
+
+    /// Returns the symbol `name` in the `.dynsym` section.
+    pub(crate) fn find_symbol(&self, name: &CStr) -> Option<DynSymbol> {
+        let index = self.symbol_index_of(name)?;
+        self.symbol(index)
+    }
+
+    /// Returns the symbols `name` defined by the object, with the address of
+    /// their `st_value` field. There can be many of them, with different
+    /// versions.
+    pub(crate) fn defined_values(&self, name: &CStr) -> Vec<(*const *const c_void, DynSymbol)> {
+        (1..self.symbol_count())
+            .filter(|&i| self.symbol_name(i) == Some(name))
+            .filter_map(|i| {
+                let sym = self.raw_symbol(i)?;
+                let symbol = self.symbol(i)?;
+                let value = &sym.st_value as *const _ as *const *const c_void;
+                Some((value, symbol)).filter(|(_, s)| s.defined)
+            })
+            .collect()
+    }
+
+    /// Returns the tables with relocations for the symbol `name`.
+    pub(crate) fn relocation_tables(&self, name: &CStr) -> Vec<Table> {
+        let mut tables = Vec::new();
+
+        for (table, rel) in self.relocations_with_table() {
+            if tables.contains(&table) {
+                continue;
+            }
+
+            let index = symbol_index(rel.r_info);
+            if index != 0 && self.symbol_name(index) == Some(name) {
+                tables.push(table);
+            }
+        }
+
+        tables
+    }
+
+    /// Returns `true` if the relocations of the object are resolved when it
+    /// is loaded (`-z now`).
+    pub(crate) fn bind_now(&self) -> bool {
+        self.dynamic_value(DT_BIND_NOW).is_some()
+            || self.dynamic_value(DT_FLAGS).unwrap_or(0) & DF_BIND_NOW != 0
+            || self.dynamic_value(DT_FLAGS_1).unwrap_or(0) & DF_1_NOW != 0
+    }
+
+    /// Returns the symbol at `index` in the `.dynsym` section.
+    fn symbol(&self, index: usize) -> Option<DynSymbol> {
+        if index == 0 {
+            return None;
+        }
+
+        let sym = self.raw_symbol(index)?;
+
+        Some(DynSymbol {
+            kind: sym.st_info & 0xf,
+            defined: sym.st_shndx != SHN_UNDEF,
+        })
+    }
+
+    fn raw_symbol(&self, index: usize) -> Option<&Sym> {
+        let symtab = self.dynamic_ptr(DT_SYMTAB)? as *const Sym;
+        Some(unsafe { &*symtab.add(index) })
+    }
+
+    fn symbol_name(&self, index: usize) -> Option<&CStr> {
+        let strtab = self.dynamic_ptr(DT_STRTAB)? as *const c_char;
+        let sym = self.raw_symbol(index)?;
+        Some(unsafe { CStr::from_ptr(strtab.add(sym.st_name as usize)) })
+    }
+
+    /// Returns the index of the symbol `name` in the `.dynsym` section.
+    fn symbol_index_of(&self, name: &CStr) -> Option<usize> {
+        (1..self.symbol_count()).find(|&i| self.symbol_name(i) == Some(name))
+    }
+
+    /// Returns the number of symbols in the `.dynsym` section.
+    ///
+    /// The size of the section is not in the dynamic section, so it is
+    /// computed from the hash tables.
+    fn symbol_count(&self) -> usize {
+        if let Some(hash) = self.dynamic_ptr(DT_HASH) {
+            // The second word is the number of entries in the chain, which
+            // is the number of symbols.
+            return unsafe { *(hash as *const u32).add(1) as usize };
+        }
+
+        match self.dynamic_ptr(DT_GNU_HASH) {
+            Some(hash) => unsafe { gnu_hash_symbol_count(hash as *const u32) },
+            None => 0,
+        }
+    }
+
+    /// Returns all entries in the relocation tables.
+    fn relocations(&self) -> impl Iterator<Item = &Rel> + '_ {
+        self.relocations_with_table().map(|(_, rel)| rel)
+    }
+
+    /// Returns all entries in the relocation tables, with their table.
+    fn relocations_with_table(&self) -> impl Iterator<Item = (Table, &Rel)> + '_ {
+        let jmprel_entry_size = match self.dynamic_value(DT_PLTREL) {
+            Some(v) if v == DT_RELA as usize => rela_size(),
+            _ => mem::size_of::<Rel>(),
+        };
+
+        let tables = [
+            (Table::Plt, DT_JMPREL, DT_PLTRELSZ, jmprel_entry_size),
+            (Table::Dynamic, DT_RELA, DT_RELASZ, rela_size()),
+            (Table::Dynamic, DT_REL, DT_RELSZ, mem::size_of::<Rel>()),
+        ];
+
+        IntoIterator::into_iter(tables).flat_map(move |(kind, table, size, entry_size)| {
+            let start = self.dynamic_ptr(table).unwrap_or(0);
+            let count = match start {
+                0 => 0,
+                _ => self.dynamic_value(size).unwrap_or(0) / entry_size,
+            };
+
+            (0..count).map(move |i| {
+                let rel = unsafe { &*((start + i * entry_size) as *const Rel) };
+                (kind, rel)
+            })
+        })
+    }
+
+    /// Address added to the offsets in the object.
+    pub(crate) fn load_address(&self) -> usize {
+        self.load_address
+    }
+
+    fn dynamic_value(&self, tag: isize) -> Option<usize> {
+        let mut entry = self.dynamic;
+        loop {
+            let dyn_ = unsafe { &*entry };
+            match dyn_.d_tag {
+                DT_NULL => return None,
+                t if t == tag => return Some(dyn_.d_val),
+                _ => entry = unsafe { entry.add(1) },
+            }
+        }
+    }
+
+    /// Returns the address in a `d_ptr` entry.
+    ///
+    /// The dynamic loader adds the load address to these entries, except on
+    /// RISC-V, where the dynamic section is read-only.
+    fn dynamic_ptr(&self, tag: isize) -> Option<usize> {
+        let ptr = self.dynamic_value(tag)?;
+
+        if cfg!(any(target_arch = "riscv64", target_arch = "riscv32")) && ptr < self.load_address()
+        {
+            return Some(ptr + self.load_address());
+        }
+
+        Some(ptr)
+    }
+}
+
+/// Returns the objects loaded in the process, in the order of the list of
+/// the dynamic loader, which is the order used to search symbols. Objects
+/// without a dynamic section are skipped.
+pub(crate) fn loaded() -> Vec<Object> {
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> c_int {
+        let objects = &mut *(data as *mut Vec<Object>);
+        let info = &*info;
+
+        let load_address = info.dlpi_addr as usize;
+        let phdrs = slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+
+        let dynamic = match phdrs.iter().find(|p| p.p_type == libc::PT_DYNAMIC) {
+            Some(phdr) => (load_address + phdr.p_vaddr as usize) as *const Dyn,
+            None => return 0,
+        };
+
+        let segments = phdrs
+            .iter()
+            .filter(|phdr| phdr.p_type == libc::PT_LOAD)
+            .map(|phdr| {
+                let start = load_address + phdr.p_vaddr as usize;
+                (start, start + phdr.p_memsz as usize)
+            })
+            .collect();
+
+        objects.push(Object {
+            load_address,
+            name: info.dlpi_name,
+            dynamic,
+            segments,
+        });
+
+        0
+    }
+
+    let mut objects = Vec::new();
+    unsafe {
+        libc::dl_iterate_phdr(Some(callback), &mut objects as *mut _ as *mut c_void);
+    }
+
+    objects
+}
+
+/// Returns the number of symbols covered by a `DT_GNU_HASH` table.
+///
+/// The table has the number of buckets, the index of the first hashed
+/// symbol, and the size of the bloom filter, followed by the bloom filter,
+/// the buckets, and the chains. The last symbol is found by following the
+/// chain of the highest bucket until an entry with the lowest bit set.
+unsafe fn gnu_hash_symbol_count(hash: *const u32) -> usize {
+    let nbuckets = *hash as usize;
+    let symoffset = *hash.add(1) as usize;
+    let bloom_size = *hash.add(2) as usize;
+
+    let bloom_words = bloom_size * mem::size_of::<usize>() / mem::size_of::<u32>();
+    let buckets = hash.add(4 + bloom_words);
+    let chains = buckets.add(nbuckets);
+
+    let last = (0..nbuckets)
+        .map(|i| *buckets.add(i) as usize)
+        .max()
+        .unwrap_or(0);
+    if last < symoffset {
+        return symoffset;
+    }
+
+    let mut index = last;
+    while *chains.add(index - symoffset) & 1 == 0 {
+        index += 1;
+    }
+
+    index + 1
+}
+
+fn rela_size() -> usize {
+    mem::size_of::<usize>() * 3
+}
+
+#[cfg(target_pointer_width = "64")]
+fn symbol_index(info: usize) -> usize {
+    info >> 32
+}
+
+#[cfg(target_pointer_width = "32")]
+fn symbol_index(info: usize) -> usize {
+    info >> 8
+}
@@ -1,12 +1,19 @@
 /// A specialized [`Result`] type for this crate.
 ///
 /// [`Result`]: ::std::result::Result
-use std::{ffi::c_int, fmt};
+use std::{
+    borrow::Cow,
+    ffi::{c_int, c_void},
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 /// Error categories from the `plthook` library.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum ErrorKind {
     FileNotFound,
     InvalidFileFormat,
@@ -15,6 +22,13 @@ pub enum ErrorKind {
     OutOfMemory,
     InternalError,
     NotImplemented,
+    /// The PLT entry is in a read-only page, and the
+    /// [`ProtectionPolicy`](crate::ProtectionPolicy) doesn't allow changing
+    /// it. There is no equivalent code in the C library.
+    PermissionDenied,
+    /// The PLT entry was modified after a [`HookPlan`](crate::HookPlan) was
+    /// created. There is no equivalent code in the C library.
+    StalePlan,
     UnknownError(c_int),
 }
 
@@ -28,6 +42,8 @@ impl fmt::Display for ErrorKind {
             ErrorKind::OutOfMemory => fmt.write_str("OutOfMemory"),
             ErrorKind::InternalError => fmt.write_str("InternalError"),
             ErrorKind::NotImplemented => fmt.write_str("NotImplemented"),
+            ErrorKind::PermissionDenied => fmt.write_str("PermissionDenied"),
+            ErrorKind::StalePlan => fmt.write_str("StalePlan"),
             ErrorKind::UnknownError(x) => write!(fmt, "Error#{x}"),
         }
     }
@@ -50,15 +66,96 @@ impl From<c_int> for ErrorKind {
 }
 
 /// Errors from the `plthook` library.
+///
+/// Besides the kind and the message, errors can have the context where they
+/// happened: the symbol, the object file, and the address of the PLT entry
+/// or the memory page. Errors caused by the operating system (like a failed
+/// `mprotect` call) have the [`io::Error`] as their [source].
+///
+/// [source]: std::error::Error::source
 #[derive(Clone, Debug)]
 pub struct Error {
     kind: ErrorKind,
-    message: String,
+    message: Cow<'static, str>,
+
+    // Allocated only when the context is set, so errors can be created
+    // without allocating memory (see `PreparedHook`).
+    context: Option<Box<Context>>,
+}
+
+/// Context of an [`Error`].
+#[derive(Clone, Debug, Default)]
+struct Context {
+    symbol: Option<String>,
+    object_path: Option<PathBuf>,
+    address: Option<usize>,
+    source: Option<Arc<io::Error>>,
+    suggestions: Vec<String>,
+    defined_locally: bool,
 }
 
 impl Error {
     pub(crate) fn new(kind: ErrorKind, message: String) -> Error {
-        Error { kind, message }
+        Error {
+            kind,
+            message: Cow::Owned(message),
+            context: None,
+        }
+    }
+
+    /// Create an error with a fixed message, without allocating memory.
+    pub(crate) fn new_static(kind: ErrorKind, message: &'static str) -> Error {
+        Error {
+            kind,
+            message: Cow::Borrowed(message),
+            context: None,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        self.context.get_or_insert_with(Default::default)
+    }
+
+    /// Set the symbol related to this error, if it is not already set.
+    pub(crate) fn with_symbol(mut self, symbol: &str) -> Error {
+        let context = self.context_mut();
+        if context.symbol.is_none() {
+            context.symbol = Some(symbol.to_owned());
+        }
+        self
+    }
+
+    /// Set the object file related to this error, if it is not already set.
+    pub(crate) fn with_object_path(mut self, path: Option<&Path>) -> Error {
+        let context = self.context_mut();
+        if context.object_path.is_none() {
+            context.object_path = path.map(Path::to_owned);
+        }
+        self
+    }
+
+    /// Set the address related to this error.
+    pub(crate) fn with_address<T>(mut self, address: *const T) -> Error {
+        self.context_mut().address = Some(address as usize);
+        self
+    }
+
+    /// Set the error from the operating system that caused this error.
+    pub(crate) fn with_source(mut self, source: io::Error) -> Error {
+        self.context_mut().source = Some(Arc::new(source));
+        self
+    }
+
+    /// Set the names suggested for a function that was not found.
+    pub(crate) fn with_suggestions(mut self, suggestions: Vec<String>) -> Error {
+        self.context_mut().suggestions = suggestions;
+        self
+    }
+
+    /// Set if the function that was not found is defined by the object.
+    pub(crate) fn with_defined_locally(mut self, defined: bool) -> Error {
+        self.context_mut().defined_locally = defined;
+        self
     }
 
     /// Returns the kind of this error.
@@ -70,6 +167,50 @@ impl Error {
     pub fn message(&self) -> &str {
         &self.message
     }
+
+    /// Returns the name of the symbol that caused this error.
+    pub fn symbol(&self) -> Option<&str> {
+        self.context.as_ref()?.symbol.as_deref()
+    }
+
+    /// Returns the path of the object file that caused this error, as given
+    /// to [`ObjectFile::open_file`].
+    ///
+    /// [`ObjectFile::open_file`]: crate::ObjectFile::open_file
+    pub fn object_path(&self) -> Option<&Path> {
+        self.context.as_ref()?.object_path.as_deref()
+    }
+
+    /// Returns the address of the PLT entry, or the memory page, that
+    /// caused this error.
+    pub fn address(&self) -> Option<*const c_void> {
+        self.context.as_ref()?.address.map(|a| a as *const c_void)
+    }
+
+    /// Returns the imported names that are close to the function that was
+    /// not found, like `__isoc99_sscanf` for `sscanf`, or `open64` for
+    /// `open`. They are sorted by relevance.
+    pub fn suggestions(&self) -> &[String] {
+        match &self.context {
+            Some(context) => &context.suggestions,
+            None => &[],
+        }
+    }
+
+    /// Returns `true` if the function that was not found is defined by the
+    /// object itself, instead of being imported. The calls from the object
+    /// to the function don't use the PLT, so they can't be replaced.
+    ///
+    /// This is only detected on Linux.
+    pub fn is_defined_locally(&self) -> bool {
+        matches!(&self.context, Some(c) if c.defined_locally)
+    }
+
+    /// Returns the error code from the operating system, if this error was
+    /// caused by a system call.
+    pub fn os_error(&self) -> Option<i32> {
+        self.context.as_ref()?.source.as_ref()?.raw_os_error()
+    }
 }
 
 impl fmt::Display for Error {
@@ -78,4 +219,61 @@ impl fmt::Display for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.context.as_ref()?.source {
+            Some(e) => Some(&**e),
+            None => None,
+        }
+    }
+}
+
+/// Errors from [`Replacement::restore`].
+///
+/// [`Replacement::restore`]: crate::Replacement::restore
+#[derive(Clone, Debug)]
+pub enum RestoreError {
+    /// The entry does not contain the address installed by the replacement,
+    /// so it was modified after [`ObjectFile::replace`]. `current` is the
+    /// address found in the entry.
+    ///
+    /// [`ObjectFile::replace`]: crate::ObjectFile::replace
+    Conflict { current: *const c_void },
+
+    /// The replacement was already restored or discarded.
+    Inactive,
+
+    /// The entry could not be written.
+    Plthook(Error),
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreError::Conflict { current } => {
+                write!(fmt, "entry was modified by another hook: {current:?}")
+            }
+            RestoreError::Inactive => fmt.write_str("replacement is not active"),
+            RestoreError::Plthook(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RestoreError::Plthook(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The address in `Conflict` is never dereferenced.
+unsafe impl Send for RestoreError {}
+unsafe impl Sync for RestoreError {}
+
+impl From<Error> for RestoreError {
+    fn from(error: Error) -> Self {
+        RestoreError::Plthook(error)
+    }
+}
@@ -0,0 +1,312 @@
+//! Replace a function in every object loaded in the process.
+//!
+//! Objects are found with `dl_iterate_phdr` (see `elf::loaded`), and
+//! opened with `plthook_open_by_address`, so objects loaded with `dlopen`
+//! are also included.
+
+use std::ffi::{c_void, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use crate::errors::{Error, ErrorKind, RestoreError, Result};
+use crate::{c_symbol_name, elf, ffi, ObjectFile, Replacement};
+
+/// Objects excluded by [`replace_everywhere`].
+///
+/// By default, the dynamic loader and the object containing the new
+/// function are excluded. The latter is useful when the hook is in a shared
+/// library (for example, loaded with `LD_PRELOAD`) that calls the original
+/// function through its own PLT.
+///
+/// # Example
+///
+/// ```
+/// use plthook::ObjectFilter;
+///
+/// let filter = ObjectFilter::default()
+///     .exclude_path("libpthread.so.0")
+///     .exclude_hook_object(false);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ObjectFilter {
+    paths: Vec<PathBuf>,
+    loader: bool,
+    hook_object: bool,
+}
+
+impl Default for ObjectFilter {
+    fn default() -> Self {
+        ObjectFilter {
+            paths: Vec::new(),
+            loader: true,
+            hook_object: true,
+        }
+    }
+}
+
+impl ObjectFilter {
+    /// Exclude the object at `path`.
+    ///
+    /// If `path` has a single component, like `libc.so.6`, it is compared
+    /// with the file name of the objects. Otherwise, it is compared with
+    /// the full path.
+    pub fn exclude_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.paths.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set if the dynamic loader (like `ld-linux-x86-64.so.2`) is excluded.
+    pub fn exclude_loader(mut self, exclude: bool) -> Self {
+        self.loader = exclude;
+        self
+    }
+
+    /// Set if the object containing the new function is excluded.
+    pub fn exclude_hook_object(mut self, exclude: bool) -> Self {
+        self.hook_object = exclude;
+        self
+    }
+
+    fn excludes(&self, object: &LoadedObject, hook: *const c_void) -> bool {
+        if self.loader && object.is_loader() {
+            return true;
+        }
+
+        if self.hook_object && object.contains(hook as usize) {
+            return true;
+        }
+
+        self.paths.iter().any(|path| {
+            if path.components().count() == 1 {
+                object.path.file_name() == Some(path.as_os_str())
+            } else {
+                object.path == *path
+            }
+        })
+    }
+}
+
+/// Replacements created by [`replace_everywhere`].
+///
+/// All PLT entries are restored when this value is dropped.
+pub struct ReplacementSet {
+    replacements: Vec<(PathBuf, Replacement)>,
+}
+
+impl ReplacementSet {
+    /// Returns the number of replacements.
+    pub fn len(&self) -> usize {
+        self.replacements.len()
+    }
+
+    /// Returns `true` if no entry was replaced.
+    pub fn is_empty(&self) -> bool {
+        self.replacements.is_empty()
+    }
+
+    /// Returns the paths of the patched objects. An object can appear more
+    /// than once if several entries were replaced.
+    ///
+    /// The path of the main program is taken from
+    /// [`std::env::current_exe`].
+    pub fn objects(&self) -> impl Iterator<Item = &Path> + '_ {
+        self.replacements.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Returns the replacements of every patched object.
+    pub fn replacements(&self) -> impl Iterator<Item = &Replacement> + '_ {
+        self.replacements.iter().map(|(_, r)| r)
+    }
+
+    /// Returns the replacements of every patched object.
+    pub fn replacements_mut(&mut self) -> impl Iterator<Item = &mut Replacement> + '_ {
+        self.replacements.iter_mut().map(|(_, r)| r)
+    }
+
+    /// Restore all PLT entries. See [`Replacement::restore`].
+    ///
+    /// Every replacement is restored, even if some of them fail. The first
+    /// error is returned, and the failed replacements are kept in the set.
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        Replacement::restore_each(&mut self.replacements, |(_, r)| r)
+    }
+}
+
+/// Replace the function `symbol_name` in every loaded object that imports
+/// it, except the ones excluded by `filter`.
+///
+/// Objects that don't import the function are ignored. If a PLT entry can't
+/// be written, the entries already replaced are restored, and the error is
+/// returned.
+///
+/// The vDSO is never patched.
+///
+/// This function is available on Linux.
+///
+/// # Safety
+///
+/// See [`ObjectFile::replace`].
+///
+/// # Example
+///
+/// ```
+/// use plthook::ObjectFilter;
+///
+/// extern "C" fn broken_getpgrp() -> libc::pid_t {
+///     -1
+/// }
+///
+/// // The hook is in the main program, which is also the caller.
+/// let filter = ObjectFilter::default().exclude_hook_object(false);
+///
+/// let replacements = unsafe {
+///     plthook::replace_everywhere("getpgrp", broken_getpgrp as *const _, filter).unwrap()
+/// };
+///
+/// assert_eq!(unsafe { libc::getpgrp() }, -1);
+///
+/// for path in replacements.objects() {
+///     println!("Patched {}", path.display());
+/// }
+///
+/// drop(replacements);
+/// assert_ne!(unsafe { libc::getpgrp() }, -1);
+/// ```
+pub unsafe fn replace_everywhere(
+    symbol_name: &str,
+    func_address: *const c_void,
+    filter: ObjectFilter,
+) -> Result<ReplacementSet> {
+    let symbol_name = c_symbol_name(symbol_name)?;
+
+    patch_objects(func_address, &filter, |object| {
+        let replacement = object.replace_if_imported(&symbol_name, func_address)?;
+        Ok(replacement.into_iter().collect())
+    })
+}
+
+/// Replace every PLT entry that points to the function at `original`, in
+/// every loaded object, except the ones excluded by `filter`. See
+/// [`ObjectFile::replace_target`].
+///
+/// An object can have several entries for the same function, so the set
+/// can have several replacements for the same object.
+///
+/// The vDSO is never patched.
+///
+/// This function is available on Linux.
+///
+/// # Safety
+///
+/// See [`ObjectFile::replace`].
+pub unsafe fn replace_target_everywhere(
+    original: *const c_void,
+    func_address: *const c_void,
+    filter: ObjectFilter,
+) -> Result<ReplacementSet> {
+    patch_objects(func_address, &filter, |object| {
+        object.replace_target(original, func_address)
+    })
+}
+
+/// Call `patch` for every loaded object not excluded by `filter`.
+///
+/// Objects where `patch` returns [`ErrorKind::FunctionNotFound`] are
+/// ignored. For other errors, the entries already replaced are restored.
+unsafe fn patch_objects<F>(
+    func_address: *const c_void,
+    filter: &ObjectFilter,
+    mut patch: F,
+) -> Result<ReplacementSet>
+where
+    F: FnMut(&ObjectFile) -> Result<Vec<Replacement>>,
+{
+    let mut set = ReplacementSet {
+        replacements: Vec::new(),
+    };
+
+    for object in loaded_objects() {
+        if object.is_vdso() || filter.excludes(&object, func_address) {
+            continue;
+        }
+
+        // Objects without a PLT section can't be opened.
+        let object_file = match object.open() {
+            Ok(o) => o,
+            Err(_) => continue,
+        };
+
+        match patch(&object_file) {
+            Ok(replacements) => {
+                for replacement in replacements {
+                    set.replacements.push((object.path.clone(), replacement));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::FunctionNotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(set)
+}
+
+/// An object found with `dl_iterate_phdr`.
+struct LoadedObject {
+    path: PathBuf,
+    elf: elf::Object,
+}
+
+impl LoadedObject {
+    /// `first` is `true` for the first object in the list, which is the
+    /// main program.
+    fn new(elf: elf::Object, first: bool) -> LoadedObject {
+        let name = elf.name().to_bytes();
+        let path = if name.is_empty() && first {
+            std::env::current_exe().unwrap_or_default()
+        } else {
+            PathBuf::from(OsStr::from_bytes(name))
+        };
+
+        LoadedObject { path, elf }
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.elf.contains(address as *const c_void)
+    }
+
+    fn is_loader(&self) -> bool {
+        let base = unsafe { libc::getauxval(libc::AT_BASE) } as usize;
+        base != 0 && base == self.elf.load_address()
+    }
+
+    fn is_vdso(&self) -> bool {
+        let vdso = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) } as usize;
+        vdso != 0 && self.contains(vdso)
+    }
+
+    fn open(&self) -> Result<ObjectFile> {
+        let address = match self.elf.segments().first() {
+            Some(&(start, _)) => start as *mut c_void,
+            None => {
+                let msg = "object has no loadable segments".to_string();
+                let error = Error::new(ErrorKind::FileNotFound, msg);
+                return Err(error.with_object_path(Some(&self.path)));
+            }
+        };
+
+        let c_object =
+            ffi::exts::open(|object| unsafe { ffi::plthook_open_by_address(object, address) })?;
+        Ok(ObjectFile::new(c_object, Some(self.path.clone())))
+    }
+}
+
+/// Returns all objects loaded in the process. The first one is the main
+/// program.
+fn loaded_objects() -> Vec<LoadedObject> {
+    elf::loaded()
+        .into_iter()
+        .enumerate()
+        .map(|(index, object)| LoadedObject::new(object, index == 0))
+        .collect()
+}
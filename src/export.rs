@@ -0,0 +1,197 @@
+//! Replace functions in the symbol table of the object that defines them.
+//!
+//! The dynamic loader computes the address of a symbol as the load address
+//! of the object plus the `st_value` field of the symbol in `.dynsym`. When
+//! this field is modified, the bindings resolved after the change (objects
+//! loaded later, lazy binding, and `dlsym`) get the new address. The entries
+//! that were already resolved are not modified.
+
+use std::ffi::{c_void, CStr};
+use std::result;
+
+use crate::elf;
+use crate::errors::{Error, ErrorKind, RestoreError, Result};
+use crate::options::ProtectionPolicy;
+use crate::{slot, ObjectFile, RestorePolicy};
+
+/// A `st_value` field modified by [`ObjectFile::replace_export`].
+struct Value {
+    field: *const *const c_void,
+    original: *const c_void,
+    written: *const c_void,
+}
+
+/// A replacement of a function in the symbol table of the object that
+/// defines it.
+///
+/// It is created by [`ObjectFile::replace_export`]. The symbol table is
+/// restored when this value is dropped. If the symbol was modified by
+/// someone else, the [`RestorePolicy`] decides what to do.
+///
+/// It is not tracked by [`active_hooks`](crate::active_hooks) nor
+/// [`restore_all`](crate::restore_all).
+///
+/// This type is available on Linux.
+pub struct ExportReplacement {
+    load_address: usize,
+    values: Vec<Value>,
+    active: bool,
+    policy: RestorePolicy,
+}
+
+// Methods with `&self` only read the fields, and the `st_value` words are
+// read with `slot::read`. They are only written through `&mut self`.
+unsafe impl Send for ExportReplacement {}
+unsafe impl Sync for ExportReplacement {}
+
+impl ExportReplacement {
+    /// Returns the address of the function defined by the object.
+    pub fn original_address(&self) -> *const c_void {
+        let original = self.values[0].original as usize;
+        original.wrapping_add(self.load_address) as *const c_void
+    }
+
+    /// Discard this replacement, so the symbol table will not be restored
+    /// when it is dropped.
+    pub fn discard(&mut self) {
+        self.active = false;
+    }
+
+    /// Set the policy used when this replacement is dropped and the symbol
+    /// was modified by someone else. See
+    /// [`Replacement::set_restore_policy`](crate::Replacement::set_restore_policy).
+    pub fn set_restore_policy(&mut self, policy: RestorePolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns `true` if the symbol table has not been restored.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Restore the original value of the symbol.
+    ///
+    /// If the symbol table was modified after [`ObjectFile::replace_export`],
+    /// [`RestoreError::Conflict`] is returned with the current address of the
+    /// symbol, and the table is not modified.
+    ///
+    /// Bindings resolved while the replacement was active are not modified.
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        if !self.active {
+            return Err(RestoreError::Inactive);
+        }
+
+        for value in &self.values {
+            let current = unsafe { slot::read(value.field) };
+            if current != value.written {
+                let current = (current as usize).wrapping_add(self.load_address);
+                return Err(RestoreError::Conflict {
+                    current: current as *const c_void,
+                });
+            }
+        }
+
+        for value in &self.values {
+            unsafe { write(value.field, value.original)? };
+        }
+
+        self.active = false;
+        Ok(())
+    }
+}
+
+impl Drop for ExportReplacement {
+    fn drop(&mut self) {
+        let current = match self.restore() {
+            Ok(()) | Err(RestoreError::Inactive) | Err(RestoreError::Plthook(_)) => return,
+            Err(RestoreError::Conflict { current }) => current,
+        };
+
+        self.active = false;
+
+        match self.policy {
+            RestorePolicy::Force => {
+                for value in &self.values {
+                    let _ = unsafe { write(value.field, value.original) };
+                }
+            }
+
+            RestorePolicy::Skip => (),
+
+            RestorePolicy::Panic => {
+                if !std::thread::panicking() {
+                    panic!(
+                        "symbol at {:?} was modified by someone else: {:?}",
+                        self.values[0].field, current
+                    );
+                }
+            }
+        }
+    }
+}
+
+unsafe fn write(field: *const *const c_void, value: *const c_void) -> Result<()> {
+    // The protection of the page is read from `/proc/self/maps`.
+    slot::write(field, 0, ProtectionPolicy::Restore, value)
+}
+
+/// Write `func_address` in the symbols `name` defined by `object`.
+pub(crate) unsafe fn replace(
+    object: &ObjectFile,
+    name: &CStr,
+    func_address: *const c_void,
+) -> Result<ExportReplacement> {
+    let elf = match elf::Object::of(object) {
+        Some(elf) => elf,
+        None => {
+            let msg = "Could not find the symbol table of the object".to_string();
+            let error = Error::new(ErrorKind::InternalError, msg);
+            return Err(object.error_context(error, name));
+        }
+    };
+
+    let defined = elf.defined_values(name);
+    if defined.is_empty() {
+        let msg = format!("{} is not defined by the object", name.to_string_lossy());
+        let error = Error::new(ErrorKind::FunctionNotFound, msg);
+        return Err(object.error_context(error, name));
+    }
+
+    // The value of an indirect function is the address of its resolver.
+    for (_, symbol) in &defined {
+        let msg = match symbol.kind {
+            elf::STT_FUNC => continue,
+            elf::STT_GNU_IFUNC => "is an indirect function (STT_GNU_IFUNC)",
+            _ => "is not a function",
+        };
+
+        let msg = format!("{} {msg}", name.to_string_lossy());
+        let error = Error::new(ErrorKind::InvalidArgument, msg);
+        return Err(object.error_context(error, name));
+    }
+
+    let load_address = elf.load_address();
+    let written = (func_address as usize).wrapping_sub(load_address) as *const c_void;
+
+    let mut replacement = ExportReplacement {
+        load_address,
+        values: Vec::with_capacity(defined.len()),
+        active: true,
+        policy: RestorePolicy::default(),
+    };
+
+    for (field, _) in defined {
+        let original = slot::read(field);
+        if let Err(e) = write(field, written) {
+            return Err(object.error_context(e, name));
+        }
+
+        replacement.values.push(Value {
+            field,
+            original,
+            written,
+        });
+    }
+
+    Ok(replacement)
+}
@@ -11,6 +11,9 @@ extern "C" {
 
     pub(crate) fn plthook_open_by_handle(object: *mut plthook_t, handle: *const c_void) -> c_int;
 
+    #[cfg(target_os = "linux")]
+    pub(crate) fn plthook_open_by_address(object: *mut plthook_t, address: *mut c_void) -> c_int;
+
     pub(crate) fn plthook_close(object: plthook_t) -> c_void;
 
     #[cfg(not(windows))]
@@ -22,6 +25,9 @@ extern "C" {
         prot: *mut c_int,
     ) -> c_int;
 
+    // Entries are written by the `slot` module, so this function is only
+    // used to test the C API.
+    #[cfg(test)]
     pub(crate) fn plthook_replace(
         object: plthook_t,
         funcname: *const c_char,
@@ -57,6 +63,33 @@ pub(crate) mod exts {
     use crate::errors::{Error, ErrorKind, Result};
     use std::ffi::{c_char, c_int, CStr};
     use std::mem::MaybeUninit;
+    use std::sync::{Mutex, MutexGuard};
+
+    // Lock to serialize the calls to the `plthook` library.
+    //
+    // The library keeps the message of the last error in a static buffer, so
+    // the lock has to be held until the message is copied by `check`. Every
+    // function of the library has to be called with this lock, even if its
+    // errors are not checked, because it can overwrite the message of a
+    // failed call in another thread.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    pub(crate) type Guard = MutexGuard<'static, ()>;
+
+    pub(crate) fn lock() -> Guard {
+        LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Call a function from the `plthook` library, and check its response
+    // while the lock is held.
+    pub(crate) fn call<F>(f: F) -> Result<()>
+    where
+        F: FnOnce() -> c_int,
+    {
+        let guard = lock();
+        let ret = f();
+        check(&guard, ret)
+    }
 
     // Wrapper for the `plthook_open` function.
     //
@@ -64,9 +97,22 @@ pub(crate) mod exts {
     //
     // `filename` has be a `NULL`-terminated string, or `NULL`.
     pub(crate) unsafe fn open_cstr(filename: *const c_char) -> Result<plthook_t> {
+        open(|object| super::plthook_open(object, filename))
+    }
+
+    // Call one of the `plthook_open` functions.
+    //
+    // The protection of the pages can't be changed while the library reads
+    // it from `/proc/self/maps`. See `slot::lock_protection`.
+    pub(crate) fn open<F>(f: F) -> Result<plthook_t>
+    where
+        F: FnOnce(*mut plthook_t) -> c_int,
+    {
+        let _protection = crate::slot::lock_protection();
+
         let mut c_object = MaybeUninit::uninit();
-        check(super::plthook_open(c_object.as_mut_ptr(), filename))?;
-        Ok(c_object.assume_init())
+        call(|| f(c_object.as_mut_ptr()))?;
+        Ok(unsafe { c_object.assume_init() })
     }
 
     // Wrapper for the `plthook_open` function.
@@ -106,22 +152,24 @@ pub(crate) mod exts {
         };
 
         if success == 0 {
-            return Err(Error::new(ErrorKind::FileNotFound, String::new()));
+            let error = std::io::Error::last_os_error();
+            let msg = format!("GetModuleHandleExW error: {error}");
+            return Err(Error::new(ErrorKind::FileNotFound, msg).with_source(error));
         }
 
         let mut object = MaybeUninit::uninit();
-        unsafe {
-            check(super::plthook_open_by_handle(
-                object.as_mut_ptr(),
-                handle.assume_init() as *const _,
-            ))?
-        };
+        call(|| unsafe {
+            super::plthook_open_by_handle(object.as_mut_ptr(), handle.assume_init() as *const _)
+        })?;
 
         Ok(unsafe { object.assume_init() })
     }
 
     // Check if the response from a C function succeeded.
-    pub(crate) fn check(ret: c_int) -> Result<()> {
+    //
+    // The guard proves that the lock is held by the caller, so the message
+    // can't be modified by another thread while it is copied.
+    fn check(_guard: &Guard, ret: c_int) -> Result<()> {
         if ret == 0 {
             return Ok(());
         }
@@ -51,6 +51,41 @@
 //! [`ObjectFile::replace`] replaces an entry in the PLT table, and returns a
 //! reference to the previous value.
 //!
+//! The entry is restored when the [`Replacement`] is dropped. Multiple
+//! replacements of the same entry are tracked in a chain, so they can be
+//! removed in any order. All replacements in the process can be listed
+//! with [`active_hooks`], and removed with [`restore_all`].
+//!
+//! Other functions to install replacements are:
+//!
+//! * [`ObjectFile::replace_with_options`]: priority in the chain, and
+//!   memory protection policy.
+//! * [`ObjectFile::replace_many`]: many functions at once.
+//! * [`ObjectFile::replace_family`]: a function and the names imported
+//!   instead of it, like `open64` for `open`.
+//! * [`ObjectFile::replace_target`]: every entry that points to an address.
+//! * [`plan`]: entries that would be written, without modifying them.
+//! * [`ObjectFile::prepare`]: an entry resolved in advance, which can be
+//!   replaced from a signal handler.
+//!
+//! Hooks can use [`HookGuard`] to detect nested calls from the functions
+//! they invoke.
+//!
+//! # Platform-specific features
+//!
+//! | Feature                                  | Platform                 | Entry point                          |
+//! |------------------------------------------|--------------------------|--------------------------------------|
+//! | Closures as hooks                        | Linux, x86_64 or aarch64 | [`ObjectFile::replace_with_closure`] |
+//! | Hooks invoked inside a [`HookGuard`]     | Linux, x86_64 or aarch64 | [`ObjectFile::replace_guarded`]      |
+//! | Replacements visible to a single thread  | Linux, x86_64 or aarch64 | [`ScopedHook`]                       |
+//! | A handler for every imported function    | Linux, x86_64 or aarch64 | [`ObjectFile::hook_all`]             |
+//! | Data symbols, like `environ`             | Linux                    | [`ObjectFile::replace_data`]         |
+//! | Calls that a replacement would not see   | Linux                    | [`ObjectFile::coverage_check`]       |
+//! | Replacements in every loaded object      | Linux                    | [`replace_everywhere`]               |
+//! | Symbol table of the defining object      | Linux                    | [`ObjectFile::replace_export`]       |
+//! | Next definition in the loaded objects    | Linux                    | [`next_definition`]                  |
+//! | Patching the code of a function          | Linux, x86_64            | `detour` module (`detour` feature)   |
+//!
 //! # Errors
 //!
 //! Errors are wrapped by the [`Error`] type. When an error is returned from
@@ -62,22 +97,118 @@
 //! [`ObjectFile`]: crate::ObjectFile
 //! [`ObjectFile::symbols`]: crate::ObjectFile::symbols
 //! [`ObjectFile::replace`]: crate::ObjectFile::replace
+//! [`ObjectFile::replace_data`]: crate::ObjectFile::replace_data
+//! [`ObjectFile::replace_guarded`]: crate::ObjectFile::replace_guarded
+//! [`ObjectFile::replace_with_closure`]: crate::ObjectFile::replace_with_closure
+//! [`ObjectFile::replace_with_options`]: crate::ObjectFile::replace_with_options
+//! [`ObjectFile::replace_many`]: crate::ObjectFile::replace_many
+//! [`ObjectFile::replace_family`]: crate::ObjectFile::replace_family
+//! [`ObjectFile::replace_target`]: crate::ObjectFile::replace_target
+//! [`ObjectFile::coverage_check`]: crate::ObjectFile::coverage_check
+//! [`ObjectFile::hook_all`]: crate::ObjectFile::hook_all
+//! [`ObjectFile::replace_export`]: crate::ObjectFile::replace_export
+//! [`plan`]: crate::plan()
+//! [`ObjectFile::prepare`]: crate::ObjectFile::prepare
+//! [`Replacement`]: crate::Replacement
+//! [`active_hooks`]: crate::active_hooks
+//! [`restore_all`]: crate::restore_all
+//! [`HookGuard`]: crate::HookGuard
+//! [`ScopedHook`]: crate::ScopedHook
+//! [`replace_everywhere`]: crate::replace_everywhere
+//! [`next_definition`]: crate::next_definition
 //! [`Error`]: crate::Error
 
+mod aliases;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod catch_all;
+mod chain;
+#[cfg(target_os = "linux")]
+mod coverage;
+#[cfg(target_os = "linux")]
+mod data;
+#[cfg(all(feature = "detour", target_os = "linux", target_arch = "x86_64"))]
+pub mod detour;
+#[cfg(target_os = "linux")]
+mod elf;
 mod errors;
+#[cfg(target_os = "linux")]
+mod everywhere;
+#[cfg(target_os = "linux")]
+mod export;
 mod ffi;
+#[cfg(target_os = "linux")]
+mod lookup;
+mod options;
+mod plan;
+mod prepared;
+mod reentrancy;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod scoped;
+mod slot;
+mod suggest;
 mod symbols;
 
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod closure;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod trampoline;
+
 #[cfg(test)]
 mod tests;
 
-use std::ffi::{c_void, CString};
-use std::mem::MaybeUninit;
-use std::path::Path;
+use std::any::Any;
+use std::ffi::{c_int, c_void, CStr, CString};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::ptr;
-use std::rc::Rc;
+use std::result;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-pub use errors::{Error, ErrorKind, Result};
+pub use aliases::FamilyReplacement;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use catch_all::{CatchAllHook, SlotInfo};
+pub use chain::{active_hooks, restore_all, HookInfo};
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use closure::{ClosureHook, HookFn};
+#[cfg(target_os = "linux")]
+pub use coverage::{Coverage, CoverageWarning};
+#[cfg(target_os = "linux")]
+pub use data::DataReplacement;
+pub use errors::{Error, ErrorKind, RestoreError, Result};
+#[cfg(target_os = "linux")]
+pub use everywhere::{replace_everywhere, replace_target_everywhere, ObjectFilter, ReplacementSet};
+#[cfg(target_os = "linux")]
+pub use export::ExportReplacement;
+#[cfg(target_os = "linux")]
+pub use lookup::next_definition;
+pub use options::{ProtectionPolicy, ReplaceOptions};
+pub use plan::{plan, HookPlan, HookSpec, PlanFailure, PlannedWrite};
+pub use prepared::PreparedHook;
+pub use reentrancy::{in_hook, HookGuard};
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use scoped::ScopedHook;
 pub use symbols::Symbol;
 
 /// An [object file] loaded in memory.
@@ -85,23 +216,24 @@ pub use symbols::Symbol;
 /// Please see the [top-level documentation](crate) for more details.
 ///
 /// [object file]: https://en.wikipedia.org/wiki/Object_file
-pub struct ObjectFile(Rc<ObjectFileInner>);
+pub struct ObjectFile(Arc<ObjectFileInner>);
 
 /// Wrapper for the C object.
 struct ObjectFileInner {
     c_object: ffi::plthook_t,
+    path: Option<PathBuf>,
 }
 
 impl ObjectFile {
     /// New instance from the raw C object.
-    fn new(c_object: ffi::plthook_t) -> ObjectFile {
-        ObjectFile(Rc::new(ObjectFileInner { c_object }))
+    fn new(c_object: ffi::plthook_t, path: Option<PathBuf>) -> ObjectFile {
+        ObjectFile(Arc::new(ObjectFileInner { c_object, path }))
     }
 
     /// Load the object for the main program.
     pub fn open_main_program() -> Result<Self> {
         let res = unsafe { ffi::exts::open_cstr(ptr::null()) };
-        res.map(ObjectFile::new)
+        res.map(|o| ObjectFile::new(o, None))
     }
 
     /// Load an object from a file.
@@ -110,26 +242,32 @@ impl ObjectFile {
         use std::ffi::OsStr;
         use std::os::unix::ffi::OsStrExt;
 
-        let filename_bytes = AsRef::<OsStr>::as_ref(filename.as_ref()).as_bytes();
+        let path = filename.as_ref();
+        let filename_bytes = AsRef::<OsStr>::as_ref(path).as_bytes();
         let filename = match CString::new(filename_bytes) {
             Ok(f) => f,
             Err(_) => {
                 // If the string in filename can't be converted to a C string
                 // we assume that it can't be possible to create a file with
                 // that name.
-                return Err(Error::new(ErrorKind::FileNotFound, String::new()));
+                let msg = "invalid file name".to_string();
+                let error = Error::new(ErrorKind::FileNotFound, msg);
+                return Err(error.with_object_path(Some(path)));
             }
         };
 
         let res = unsafe { ffi::exts::open_cstr(filename.as_ptr()) };
-        res.map(ObjectFile::new)
+        res.map(|o| ObjectFile::new(o, Some(path.to_owned())))
+            .map_err(|e| open_error(e, path))
     }
 
     /// Load an object from a file.
     #[cfg(windows)]
     pub fn open_file<P: AsRef<Path>>(filename: P) -> Result<Self> {
-        let res = ffi::exts::open_path_win32(filename.as_ref());
-        res.map(ObjectFile::new)
+        let path = filename.as_ref();
+        let res = ffi::exts::open_path_win32(path);
+        res.map(|o| ObjectFile::new(o, Some(path.to_owned())))
+            .map_err(|e| open_error(e, path))
     }
 
     /// Load a dynamic loaded shared object.
@@ -144,15 +282,14 @@ impl ObjectFile {
     ///
     /// [`dlopen`]: https://docs.rs/libc/*/libc/fn.dlopen.html
     pub unsafe fn open_by_handle(handle: *const c_void) -> Result<Self> {
-        let mut object = MaybeUninit::uninit();
-        ffi::exts::check(ffi::plthook_open_by_handle(object.as_mut_ptr(), handle))?;
-
-        Ok(ObjectFile::new(object.assume_init()))
+        let object = ffi::exts::open(|object| ffi::plthook_open_by_handle(object, handle))?;
+        Ok(ObjectFile::new(object, None))
     }
 
     /// Replace the address of a symbol in the PLT section, and returns a
     /// reference to the previous entry. When this reference is dropped, the
-    /// entry is restored to the previous value.
+    /// entry is restored to the previous value, according to its
+    /// [`RestorePolicy`].
     ///
     /// The reference to the previous entry can be used to invoke the original
     /// function.
@@ -162,7 +299,10 @@ impl ObjectFile {
     /// The caller has to verify that the new address for the symbol is
     /// valid.
     ///
-    /// The function is not thread-safe.
+    /// The entry is written with an atomic store, so other threads can call
+    /// the function while it is replaced. They will see either the previous
+    /// address or the new one. Thus, the new function has to be safe to call
+    /// from any thread.
     ///
     /// # Example
     ///
@@ -195,134 +335,1500 @@ impl ObjectFile {
         symbol_name: &str,
         func_address: *const c_void,
     ) -> Result<Replacement> {
-        let symbol_name = match CString::new(symbol_name) {
-            Ok(s) => s,
-            Err(_) => {
-                // If the name is not a valid C string, we assume that
-                // there is no symbol with that name.
-                return Err(Error::new(ErrorKind::FunctionNotFound, String::new()));
-            }
+        self.replace_with_priority(symbol_name, func_address, 0)
+    }
+
+    /// Like [`replace`](Self::replace), but with a priority to sort the
+    /// replacements of the same PLT entry.
+    ///
+    /// Every entry keeps a chain with all the replacements installed on it.
+    /// Replacements with a higher priority are called first, and each one
+    /// can invoke the next one with the address returned by
+    /// [`Replacement::original_address`]. Replacements with the same
+    /// priority are called in reverse installation order. [`replace`] uses
+    /// the priority `0`.
+    ///
+    /// When a replacement is removed from the chain, the previous one is
+    /// linked to the next one, regardless of the order of removal.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    ///
+    /// extern "C" fn outer_abs(_: i32) -> i32 {
+    ///     1
+    /// }
+    ///
+    /// extern "C" fn inner_abs(_: i32) -> i32 {
+    ///     2
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    ///
+    /// let outer = unsafe {
+    ///     program.replace_with_priority("abs", outer_abs as *const _, 10).unwrap()
+    /// };
+    ///
+    /// let inner = unsafe { program.replace("abs", inner_abs as *const _).unwrap() };
+    ///
+    /// // `outer_abs` has a higher priority, so it is called first.
+    /// assert_eq!(unsafe { libc::abs(-5) }, 1);
+    /// assert_eq!(outer.original_address(), inner_abs as *const _);
+    ///
+    /// // When `outer` is removed, `inner_abs` is called.
+    /// drop(outer);
+    /// assert_eq!(unsafe { libc::abs(-5) }, 2);
+    ///
+    /// drop(inner);
+    /// assert_eq!(unsafe { libc::abs(-5) }, 5);
+    /// # }
+    /// ```
+    ///
+    /// [`replace`]: Self::replace
+    pub unsafe fn replace_with_priority(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+        priority: i32,
+    ) -> Result<Replacement> {
+        let options = ReplaceOptions::new().priority(priority);
+        self.replace_with_options(symbol_name, func_address, &options)
+    }
+
+    /// Like [`replace`](Self::replace), but with the priority, the memory
+    /// protection policy, and the reentrancy guard from `options`.
+    ///
+    /// See [`ReplaceOptions`] and [`ProtectionPolicy`].
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
+    pub unsafe fn replace_with_options(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+        options: &ReplaceOptions,
+    ) -> Result<Replacement> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let hook = Hook::with_options(func_address, options)?;
+        self.install(symbol_name, hook, options)
+    }
+
+    /// Like [`replace`](Self::replace), but `func_address` is checked before
+    /// it is installed.
+    ///
+    /// The address has to be in an executable mapping of the process (read
+    /// from `/proc/self/maps`, on Linux), and it can't be the address of the
+    /// PLT entry. Otherwise, an error with [`ErrorKind::InvalidArgument`] is
+    /// returned, instead of crashing at the first call to the function.
+    ///
+    /// On other platforms, only null addresses and the address of the entry
+    /// are rejected.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace). The signature of the function can't
+    /// be checked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::{ErrorKind, ObjectFile};
+    ///
+    /// static NOT_CODE: u64 = 0;
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let hook = &NOT_CODE as *const u64 as *const _;
+    /// let error = match unsafe { program.replace_checked("getpid", hook) } {
+    ///     Ok(_) => panic!("data is not a valid hook"),
+    ///     Err(e) => e,
+    /// };
+    ///
+    /// assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    /// assert_ne!(unsafe { libc::getpid() }, 0);
+    /// # }
+    /// ```
+    pub unsafe fn replace_checked(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<Replacement> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let symbol = match symbols::find(self, &symbol_name) {
+            Some(symbol) => symbol,
+            None => return Err(self.function_not_found(&symbol_name)),
         };
 
-        let mut old_addr = MaybeUninit::uninit();
-        ffi::exts::check(ffi::plthook_replace(
-            self.0.c_object,
-            symbol_name.as_ptr(),
-            func_address,
-            old_addr.as_mut_ptr(),
-        ))?;
+        let slot = symbol.func_address as *const *const c_void;
+        if let Err(e) = slot::check_hook(slot, func_address) {
+            return Err(self.error_context(e, &symbol_name));
+        }
 
-        Ok(Replacement {
-            restore_ref: Some(RestoreRef {
-                object: Rc::clone(&self.0),
+        let options = ReplaceOptions::default();
+        self.install_symbol(symbol_name, symbol, Hook::new(func_address), &options)
+    }
+
+    /// Replace several functions at once.
+    ///
+    /// This is equivalent to calling [`replace_with_options`] for every
+    /// function in `hooks`, but the PLT section is scanned only once, and
+    /// the memory protection of every page containing the entries is
+    /// changed only once. This is much faster when hundreds of functions
+    /// are replaced.
+    ///
+    /// The replacements are returned in the same order as `hooks`. If a
+    /// function is not found, or an entry can't be written, no function is
+    /// replaced.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::{ObjectFile, ReplaceOptions};
+    ///
+    /// extern "C" fn broken_getpid() -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// extern "C" fn broken_getppid() -> libc::pid_t {
+    ///     -2
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let replacements = unsafe {
+    ///     program
+    ///         .replace_many(
+    ///             &[
+    ///                 ("getpid", broken_getpid as *const _),
+    ///                 ("getppid", broken_getppid as *const _),
+    ///             ],
+    ///             &ReplaceOptions::new(),
+    ///         )
+    ///         .unwrap()
+    /// };
+    ///
+    /// assert_eq!(unsafe { libc::getpid() }, -1);
+    /// assert_eq!(unsafe { libc::getppid() }, -2);
+    ///
+    /// drop(replacements);
+    /// assert_ne!(unsafe { libc::getpid() }, -1);
+    /// # }
+    /// ```
+    ///
+    /// [`replace_with_options`]: Self::replace_with_options
+    pub unsafe fn replace_many(
+        &self,
+        hooks: &[(&str, *const c_void)],
+        options: &ReplaceOptions,
+    ) -> Result<Vec<Replacement>> {
+        let mut names = Vec::with_capacity(hooks.len());
+        for (name, _) in hooks {
+            names.push(c_symbol_name(name)?);
+        }
+
+        let mut symbols = Vec::with_capacity(hooks.len());
+        for (symbol, name) in symbols::find_many(self, &names).into_iter().zip(&names) {
+            match symbol {
+                Some(symbol) => symbols.push(symbol),
+                None => return Err(self.function_not_found(name)),
+            }
+        }
+
+        let path = self.0.path.as_ref();
+        let mut items = Vec::with_capacity(hooks.len());
+        for ((symbol_name, symbol), &(_, addr)) in names.into_iter().zip(symbols).zip(hooks) {
+            items.push(BatchItem {
                 symbol_name,
-            }),
-            address: old_addr.assume_init(),
-        })
+                slot: symbol.func_address as *const *const c_void,
+                prot: symbol.protection,
+                object_path: path,
+                hook: Hook::with_options(addr, options)?,
+            });
+        }
+
+        install_batch(items, options)
     }
 
-    /// Returns an iterator to get all symbols in the PLT section.
+    /// Replace a function, and the names imported instead of it because of
+    /// the redirections in the glibc headers.
+    ///
+    /// For example, a program built with `-D_FILE_OFFSET_BITS=64` imports
+    /// `open64` when it calls `open`, and `sscanf` is imported as
+    /// `__isoc99_sscanf`. This function replaces every name in the family of
+    /// `symbol_name` imported by the object, and returns the names that
+    /// were replaced. If `symbol_name` is not in a known family, only that
+    /// name is replaced.
+    ///
+    /// Names with a different signature are not replaced. They are
+    /// reported by [`FamilyReplacement::missed`]. These names are the
+    /// fortified functions (like `__printf_chk` for `printf`), `__open_2`,
+    /// the `64` variants on 32-bit platforms, and the `stat` functions of
+    /// glibc before 2.33 (`__xstat`, `__fxstat`, `__lxstat` and
+    /// `__fxstatat`, and their `64` variants), which receive a version
+    /// number before the arguments of `stat`.
+    ///
+    /// Programs built with glibc before 2.33 call `stat` through `__xstat`,
+    /// so `replace_family("stat", ..)` doesn't replace these calls, and
+    /// returns an error if the program doesn't import any other name in
+    /// the family. To hook them, replace `__xstat` with
+    /// [`replace`](Self::replace) and a function that receives the version.
+    ///
+    /// If no name in the family is imported, an error with
+    /// [`ErrorKind::FunctionNotFound`] is returned.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
     ///
     /// # Example
     ///
     /// ```
-    /// # let _fn = || -> Result<(), plthook::Error> {
+    /// # #[cfg(target_os = "linux")] {
     /// use plthook::ObjectFile;
+    /// use std::ffi::{c_char, c_int};
     ///
-    /// let object = ObjectFile::open_main_program()?;
-    /// for symbol in object.symbols() {
-    ///     println!("{:?} {:?}", symbol.func_address, symbol.name);
+    /// extern "C" fn broken_open(_: *const c_char, _: c_int, _: libc::mode_t) -> c_int {
+    ///     -1
     /// }
-    /// # Ok(()) };
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// if let Ok(family) = unsafe { program.replace_family("open", broken_open as *const _) } {
+    ///     for name in family.names() {
+    ///         println!("replaced {}", name);
+    ///     }
+    /// }
+    /// # }
     /// ```
-    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
-        symbols::iterator(self)
-    }
-}
+    pub unsafe fn replace_family(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<FamilyReplacement> {
+        let single = [symbol_name];
+        let family = aliases::family(symbol_name).unwrap_or(&single);
 
-impl Drop for ObjectFileInner {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::plthook_close(self.c_object);
+        let mut names = Vec::with_capacity(family.len());
+        for name in family {
+            names.push(c_symbol_name(name)?);
         }
-    }
-}
 
-/// A replacement of an entry in the PLT section.
-///
-/// The address in the PLT entry is restored when this value is dropped.
-pub struct Replacement {
-    restore_ref: Option<RestoreRef>,
-    address: *const c_void,
-}
+        let symbols = symbols::find_many(self, &names);
 
-/// Reference to restore a symbol when `Replacement` is dropped.
-struct RestoreRef {
-    object: Rc<ObjectFileInner>,
-    symbol_name: CString,
-}
+        let mut items = Vec::new();
+        let mut replaced = Vec::new();
+        let mut missed = Vec::new();
 
-impl Replacement {
-    /// Returns the original address of the PLT entry.
+        for ((name, c_name), symbol) in family.iter().zip(names).zip(symbols) {
+            let symbol = match symbol {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+
+            if !aliases::same_signature(name) {
+                missed.push(name.to_string());
+                continue;
+            }
+
+            replaced.push(name.to_string());
+            items.push(BatchItem {
+                symbol_name: c_name,
+                slot: symbol.func_address as *const *const c_void,
+                prot: symbol.protection,
+                object_path: self.0.path.as_ref(),
+                hook: Hook::new(func_address),
+            });
+        }
+
+        if items.is_empty() {
+            let c_name = c_symbol_name(symbol_name)?;
+            return Err(self.function_not_found(&c_name));
+        }
+
+        let replacements = install_batch(items, &ReplaceOptions::default())?;
+        Ok(FamilyReplacement::new(
+            replaced.into_iter().zip(replacements).collect(),
+            missed,
+        ))
+    }
+
+    /// Replace every PLT entry that points to the function at `original`,
+    /// whatever the name used to import it.
     ///
-    /// This address can be used to invoke the function replaced by
-    /// [`ObjectFile::replace`].
+    /// This is useful when the address of the function is known (for
+    /// example, from `dlsym`), but the object may import it with different
+    /// names or versions. Entries that were already replaced by this crate
+    /// are compared with the address they had before the first replacement.
+    ///
+    /// With lazy binding, entries that were never called point to the
+    /// resolver of the dynamic loader, so they are not found.
+    ///
+    /// If no entry points to `original`, an error with
+    /// [`ErrorKind::FunctionNotFound`] is returned.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
     ///
     /// # Example
     ///
     /// ```
     /// # #[cfg(target_os = "linux")] {
     /// use plthook::ObjectFile;
-    /// use std::mem;
     ///
-    /// extern "C" fn broken_getpid() -> libc::pid_t {
+    /// extern "C" fn broken_getpgrp() -> libc::pid_t {
     ///     -1
     /// }
     ///
+    /// let original = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"getpgrp\0".as_ptr().cast()) };
+    ///
     /// let program = ObjectFile::open_main_program().unwrap();
+    /// let replacements = unsafe {
+    ///     program
+    ///         .replace_target(original, broken_getpgrp as *const _)
+    ///         .unwrap()
+    /// };
     ///
-    /// let pid = unsafe { libc::getpid() };
-    /// assert_ne!(pid, -1);
+    /// assert_eq!(unsafe { libc::getpgrp() }, -1);
     ///
-    /// // Replace getpid with our broken function.
+    /// drop(replacements);
+    /// assert_ne!(unsafe { libc::getpgrp() }, -1);
+    /// # }
+    /// ```
+    pub unsafe fn replace_target(
+        &self,
+        original: *const c_void,
+        func_address: *const c_void,
+    ) -> Result<Vec<Replacement>> {
+        let mut items = Vec::new();
+
+        for symbol in self.symbols() {
+            let slot = symbol.func_address as *const *const c_void;
+            let target = chain::original_of(slot).unwrap_or_else(|| slot::read(slot));
+            if target != original {
+                continue;
+            }
+
+            let name = String::from_utf8_lossy(symbols::plain_name(symbol.name.to_bytes()));
+            items.push(BatchItem {
+                symbol_name: c_symbol_name(&name)?,
+                slot,
+                prot: symbol.protection,
+                object_path: self.0.path.as_ref(),
+                hook: Hook::new(func_address),
+            });
+        }
+
+        if items.is_empty() {
+            let msg = format!("no PLT entry points to {original:?}");
+            let error = Error::new(ErrorKind::FunctionNotFound, msg)
+                .with_address(original)
+                .with_object_path(self.0.path.as_deref());
+            return Err(error);
+        }
+
+        install_batch(items, &ReplaceOptions::default())
+    }
+
+    /// Replace the function `symbol_name` defined by this object, in its
+    /// symbol table (`.dynsym`).
     ///
-    /// let replacement = unsafe {
-    ///     program.replace("getpid", broken_getpid as *const _).unwrap()
-    /// };
+    /// [`replace`](Self::replace) modifies the entries of the objects that
+    /// import a function. This function modifies the object that exports
+    /// it, so the bindings resolved later get `func_address`: objects loaded
+    /// with `dlopen`, entries resolved with lazy binding, and `dlsym`. The
+    /// entries that were already resolved are not modified, so both
+    /// functions can be used together.
     ///
-    /// let libc_getpid: extern "C" fn() -> libc::pid_t = unsafe {
-    ///     mem::transmute(replacement.original_address())
+    /// Indirect functions (`STT_GNU_IFUNC`), like many string functions of
+    /// glibc, can't be replaced, and an error with
+    /// [`ErrorKind::InvalidArgument`] is returned. If the function is not
+    /// defined by the object, an error with [`ErrorKind::FunctionNotFound`]
+    /// is returned.
+    ///
+    /// The symbol table is restored when the returned
+    /// [`ExportReplacement`] is dropped.
+    ///
+    /// The symbol table is not a PLT entry, so the replacement is not
+    /// tracked in the chains of the entries: it is not listed by
+    /// [`active_hooks`], and it is not restored by [`restore_all`]. There is
+    /// no order between this replacement and the replacements of the PLT
+    /// entries for the same function: a replacement of an entry calls the
+    /// address that the entry had when it was installed, and this function
+    /// only changes the bindings resolved later.
+    ///
+    /// This function is available on Linux.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    ///
+    /// extern "C" fn broken_getpgrp() -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// let name = b"getpgrp\0".as_ptr().cast();
+    /// let dlsym_getpgrp = || unsafe { libc::dlsym(libc::RTLD_DEFAULT, name) };
+    ///
+    /// let object = ObjectFile::open_file("libc.so.6").unwrap();
+    /// let replacement = unsafe {
+    ///     object.replace_export("getpgrp", broken_getpgrp as *const _).unwrap()
     /// };
     ///
-    /// assert_eq!(unsafe { libc::getpid() }, -1);
-    /// assert_eq!(unsafe { (libc_getpid)() }, pid);
+    /// assert_eq!(dlsym_getpgrp() as usize, broken_getpgrp as usize);
     ///
     /// drop(replacement);
-    /// assert_eq!(unsafe { libc::getpid() }, pid);
+    /// assert_ne!(dlsym_getpgrp() as usize, broken_getpgrp as usize);
     /// # }
     /// ```
-    pub fn original_address(&self) -> *const c_void {
-        self.address
-    }
-
-    /// Discard this replacement, so the original address will not be restored
-    /// when this replacement is dropped.
-    pub fn discard(&mut self) {
-        self.restore_ref = None;
+    #[cfg(target_os = "linux")]
+    pub unsafe fn replace_export(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<ExportReplacement> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+        export::replace(self, &symbol_name, func_address)
     }
-}
 
-impl Drop for Replacement {
-    fn drop(&mut self) {
-        unsafe {
-            if let Some(restore_ref) = self.restore_ref.take() {
-                let _ = ffi::exts::check(ffi::plthook_replace(
-                    restore_ref.object.c_object,
-                    restore_ref.symbol_name.as_ptr(),
-                    self.address,
-                    ptr::null_mut(),
-                ));
-            }
-        };
+    /// Resolve the PLT entry of `symbol_name`, so it can be replaced later
+    /// from a signal handler, or after `fork()`.
+    ///
+    /// The entry is not modified until [`PreparedHook::apply`] is called.
+    /// The memory protection of the entry is resolved here, so it returns
+    /// an error if it can't be determined.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
+    ///
+    /// [`PreparedHook::apply`] and [`PreparedHook::revert`] change the memory
+    /// protection of the page without the lock used by other replacements.
+    /// While they are running, no other thread can modify an entry in the
+    /// same page, with another [`PreparedHook`] or with any other
+    /// replacement of this crate. Otherwise, the page can be made read-only
+    /// while the entry is being written, and the process is killed by a
+    /// `SIGSEGV`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    ///
+    /// extern "C" fn broken_getpgrp() -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let hook = unsafe { program.prepare("getpgrp", broken_getpgrp as *const _).unwrap() };
+    ///
+    /// // These calls can be done from a signal handler.
+    /// hook.apply().unwrap();
+    /// assert_eq!(unsafe { libc::getpgrp() }, -1);
+    ///
+    /// hook.revert().unwrap();
+    /// assert_ne!(unsafe { libc::getpgrp() }, -1);
+    /// # }
+    /// ```
+    pub unsafe fn prepare(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<PreparedHook> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let symbol = match symbols::find(self, &symbol_name) {
+            Some(symbol) => symbol,
+            None => return Err(self.function_not_found(&symbol_name)),
+        };
+
+        // The protection is resolved here, because `apply` can't report
+        // why it is unknown.
+        let slot = symbol.func_address as *const *const c_void;
+        let prot = match slot::resolve_protection(slot, symbol.protection) {
+            Ok(prot) => prot,
+            Err(e) => return Err(self.error_context(e, &symbol_name)),
+        };
+
+        Ok(PreparedHook::new(slot, prot, func_address))
+    }
+
+    /// Replace a function with a Rust closure.
+    ///
+    /// `F` is the type of the function, like `extern "C" fn(c_int) -> c_int`.
+    /// The closure receives the original function as the first argument,
+    /// followed by the arguments of the function.
+    ///
+    /// The closure is invoked from a trampoline generated at runtime. The
+    /// closure is released when the [`Replacement`] is dropped, after all
+    /// calls running in it are finished. The trampoline is never released,
+    /// because other threads may have read its address before it was
+    /// removed; after the drop, it jumps to the next function in the chain.
+    /// If the replacement is discarded, or it can't be restored, the
+    /// closure is never released.
+    ///
+    /// While the closure is running, nested calls to closure hooks and
+    /// [guarded](Self::replace_guarded) hooks in the same thread go directly
+    /// to the next function in their chains. See [`HookGuard`].
+    ///
+    /// A panic can't unwind through the caller of the replaced function, so
+    /// the process is aborted if the closure panics.
+    ///
+    /// This function is available on Linux for x86_64 and aarch64.
+    ///
+    /// # Safety
+    ///
+    /// `F` has to be the actual type of the replaced function.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))] {
+    /// use plthook::ObjectFile;
+    /// use std::ffi::{c_char, c_int};
+    ///
+    /// type AtoiFn = extern "C" fn(*const c_char) -> c_int;
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    ///
+    /// let factor = 3;
+    /// let replacement = unsafe {
+    ///     program.replace_with_closure::<AtoiFn, _>(
+    ///         "atoi",
+    ///         move |original: AtoiFn, nptr: *const c_char| original(nptr) * factor,
+    ///     )
+    ///     .unwrap()
+    /// };
+    ///
+    /// let param = b"5\0".as_ptr().cast();
+    /// assert_eq!(unsafe { libc::atoi(param) }, 15);
+    ///
+    /// drop(replacement);
+    /// assert_eq!(unsafe { libc::atoi(param) }, 5);
+    /// # }
+    /// ```
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub unsafe fn replace_with_closure<F, C>(
+        &self,
+        symbol_name: &str,
+        closure: C,
+    ) -> Result<Replacement>
+    where
+        F: closure::HookFn,
+        C: closure::ClosureHook<F>,
+    {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let hook = closure::ClosureData::hook(closure)?;
+        self.install(symbol_name, hook, &ReplaceOptions::default())
+    }
+
+    /// Like [`replace`](Self::replace), but `func_address` is invoked inside
+    /// a [`HookGuard`].
+    ///
+    /// The entry contains the address of a trampoline. If the thread is
+    /// already running a hook (like another guarded hook, or a closure
+    /// installed with [`replace_with_closure`](Self::replace_with_closure)),
+    /// the trampoline jumps to the next function in the chain. Otherwise, it
+    /// calls `func_address` inside a guard, so nested calls from the
+    /// function go to the original functions.
+    ///
+    /// To leave the guard, the return address of the call is replaced while
+    /// the function is running. Thus, the function must return normally: if
+    /// it unwinds, or calls `longjmp`, the thread is kept inside the guard.
+    /// This is not compatible with shadow stacks.
+    ///
+    /// This is equivalent to [`replace_with_options`] with
+    /// [`ReplaceOptions::guarded`]. It is available on Linux for x86_64 and
+    /// aarch64.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))] {
+    /// use plthook::ObjectFile;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static CALLS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// extern "C" fn counted_getpgrp() -> libc::pid_t {
+    ///     CALLS.fetch_add(1, Ordering::SeqCst);
+    ///
+    ///     // Nested call. It goes to the original function.
+    ///     unsafe { libc::getpgrp() }
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let replacement = unsafe {
+    ///     program.replace_guarded("getpgrp", counted_getpgrp as *const _).unwrap()
+    /// };
+    ///
+    /// unsafe { libc::getpgrp() };
+    /// assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    /// # }
+    /// ```
+    ///
+    /// [`replace_with_options`]: Self::replace_with_options
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub unsafe fn replace_guarded(
+        &self,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<Replacement> {
+        let options = ReplaceOptions::new().guarded(true);
+        self.replace_with_options(symbol_name, func_address, &options)
+    }
+
+    /// Invoke `handler` before every call to a function imported by the
+    /// object.
+    ///
+    /// Every PLT entry of a function is replaced by a small trampoline. On
+    /// each call, the trampoline invokes `handler` with the [`SlotInfo`] of
+    /// the entry, and then jumps to the next function in the chain of the
+    /// entry, with the registers and the stack of the original call.
+    ///
+    /// The handler is not invoked for calls made while the thread is running
+    /// a hook (see [`HookGuard`]), so it can call any function. Entries of
+    /// data symbols are not modified.
+    ///
+    /// A panic can't unwind through the caller of the hooked function, so
+    /// the process is aborted if the handler panics.
+    ///
+    /// The trampolines preserve the registers used to pass arguments. On
+    /// x86_64, this includes the whole extended state (like `__m256`
+    /// values), which is saved in the stack of the thread. On aarch64,
+    /// functions that receive SVE values can't be hooked with this function.
+    ///
+    /// With lazy binding, entries that were never called point to the
+    /// resolver of the dynamic loader, which overwrites the trampoline the
+    /// first time the function is called. For these entries, the handler
+    /// is only invoked on the first call, and
+    /// [`CatchAllHook::restore`] returns a [`RestoreError::Conflict`]. To
+    /// hook every call, the object has to be linked with `-z now`, or the
+    /// program started with `LD_BIND_NOW=1`.
+    ///
+    /// All entries are restored when the returned [`CatchAllHook`] is
+    /// dropped.
+    ///
+    /// This function is available on Linux for x86_64 and aarch64.
+    ///
+    /// # Safety
+    ///
+    /// See [`replace`](Self::replace). The handler can be invoked from any
+    /// thread, and in any context where the functions of the object are
+    /// called, like signal handlers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))] {
+    /// use plthook::ObjectFile;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static CALLS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let hook = unsafe {
+    ///     program
+    ///         .hook_all(|slot| {
+    ///             if slot.name() == "getppid" {
+    ///                 CALLS.fetch_add(1, Ordering::SeqCst);
+    ///             }
+    ///         })
+    ///         .unwrap()
+    /// };
+    ///
+    /// let ppid = unsafe { libc::getppid() };
+    /// assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    ///
+    /// drop(hook);
+    /// assert_eq!(unsafe { libc::getppid() }, ppid);
+    /// assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    /// # }
+    /// ```
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub unsafe fn hook_all<F>(&self, handler: F) -> Result<CatchAllHook>
+    where
+        F: Fn(&SlotInfo) + Send + Sync + 'static,
+    {
+        catch_all::install(self, handler)
+    }
+
+    /// Replace the address of the data symbol `symbol_name` with `data`.
+    ///
+    /// Data symbols imported by the object, like `environ` or `stdout`, are
+    /// accessed through an entry that contains the address of the variable.
+    /// After this replacement, the object will use `data` as the variable.
+    ///
+    /// Only symbols with the `STT_OBJECT` type are accepted. If the symbol
+    /// is a function, an error with [`ErrorKind::InvalidArgument`] is
+    /// returned.
+    ///
+    /// This function is available on Linux.
+    ///
+    /// # Safety
+    ///
+    /// `T` has to be the type of the variable, and `data` has to be valid
+    /// while the replacement is active.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    /// use std::ffi::c_char;
+    /// use std::ptr;
+    ///
+    /// extern "C" {
+    ///     static environ: *const *const c_char;
+    /// }
+    ///
+    /// let empty_env: [*const c_char; 1] = [ptr::null()];
+    /// let mut other_environ = empty_env.as_ptr();
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let mut replacement = unsafe {
+    ///     program
+    ///         .replace_data::<*const *const c_char>("environ", &mut other_environ)
+    ///         .unwrap()
+    /// };
+    ///
+    /// assert!(unsafe { (*environ).is_null() });
+    ///
+    /// replacement.restore().unwrap();
+    /// assert!(!unsafe { (*environ).is_null() });
+    /// # }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub unsafe fn replace_data<T>(
+        &self,
+        symbol_name: &str,
+        data: *mut T,
+    ) -> Result<DataReplacement<T>> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let symbol = data::find(self, &symbol_name)?;
+        let hook = Hook::new(data.cast());
+        let replacement =
+            self.install_symbol(symbol_name, symbol, hook, &ReplaceOptions::default())?;
+
+        Ok(DataReplacement::new(replacement))
+    }
+
+    /// Check if replacing `symbol_name` would affect every call to the
+    /// function from this object.
+    ///
+    /// Calls that don't use the PLT entry, like calls inside the object that
+    /// defines the function, or calls through another entry of the GOT, are
+    /// not affected by [`replace`](Self::replace). See [`CoverageWarning`]
+    /// for the detected cases.
+    ///
+    /// This function is available on Linux.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    /// let coverage = program.coverage_check("getpid").unwrap();
+    ///
+    /// for warning in coverage.warnings() {
+    ///     println!("getpid: {:?}", warning);
+    /// }
+    /// # }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn coverage_check(&self, symbol_name: &str) -> Result<Coverage> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        coverage::check(self, &symbol_name)
+    }
+
+    /// Add `hook` to the chain of the PLT entry for `symbol_name`.
+    unsafe fn install(
+        &self,
+        symbol_name: CString,
+        hook: Hook,
+        options: &ReplaceOptions,
+    ) -> Result<Replacement> {
+        let symbol = match symbols::find(self, &symbol_name) {
+            Some(symbol) => symbol,
+            None => return Err(self.function_not_found(&symbol_name)),
+        };
+
+        self.install_symbol(symbol_name, symbol, hook, options)
+    }
+
+    /// Like [`replace`](Self::replace), but returns `None` if the function
+    /// is not imported by the object.
+    ///
+    /// The suggestions of [`function_not_found`](Self::function_not_found)
+    /// are expensive, so this is used when the error would be discarded.
+    #[cfg(target_os = "linux")]
+    pub(crate) unsafe fn replace_if_imported(
+        &self,
+        symbol_name: &CStr,
+        func_address: *const c_void,
+    ) -> Result<Option<Replacement>> {
+        let symbol = match symbols::find(self, symbol_name) {
+            Some(symbol) => symbol,
+            None => return Ok(None),
+        };
+
+        let hook = Hook::new(func_address);
+        let options = ReplaceOptions::default();
+        self.install_symbol(symbol_name.to_owned(), symbol, hook, &options)
+            .map(Some)
+    }
+
+    /// Add `hook` to the chain of the entry of `symbol`.
+    ///
+    /// The data of the hook is released when the replacement is restored.
+    unsafe fn install_symbol(
+        &self,
+        symbol_name: CString,
+        symbol: Symbol,
+        hook: Hook,
+        options: &ReplaceOptions,
+    ) -> Result<Replacement> {
+        let slot = symbol.func_address as *const *const c_void;
+
+        let entry = chain::Entry {
+            slot,
+            prot: symbol.protection,
+            protection: options.protection,
+            symbol: &symbol_name,
+            object_path: self.0.path.as_ref(),
+        };
+
+        let switch = chain::new_switch();
+        let layer = chain::install(chain::NewLayer {
+            entry,
+            hook: hook.address,
+            chained: hook.chained,
+            priority: options.priority,
+            next: &hook.next,
+            switch: &switch,
+            expected: None,
+        })
+        .map_err(|e| self.error_context(e, &symbol_name))?;
+
+        Ok(Replacement {
+            restore_ref: Some(RestoreRef {
+                symbol_name,
+                slot,
+                layer,
+                switch,
+            }),
+            next: hook.next,
+            policy: RestorePolicy::default(),
+            hook_data: hook.data,
+        })
+    }
+
+    /// Returns the error for a function that is not in the PLT section.
+    ///
+    /// The error has the imported names that are close to `symbol_name`.
+    pub(crate) fn function_not_found(&self, symbol_name: &CStr) -> Error {
+        let name = symbol_name.to_string_lossy();
+        let suggestions = suggest::near_misses(self, &name);
+        let defined = suggest::defines(self, symbol_name);
+
+        let mut msg = format!("no such function: {name}");
+        if defined {
+            msg.push_str(" (it is defined by the object, not imported)");
+        }
+        if !suggestions.is_empty() {
+            msg.push_str(&format!(" (did you mean {}?)", suggestions.join(", ")));
+        }
+
+        let error = Error::new(ErrorKind::FunctionNotFound, msg)
+            .with_suggestions(suggestions)
+            .with_defined_locally(defined);
+
+        self.error_context(error, symbol_name)
+    }
+
+    /// Add the symbol and the path of this object to `error`.
+    pub(crate) fn error_context(&self, error: Error, symbol_name: &CStr) -> Error {
+        error
+            .with_symbol(&symbol_name.to_string_lossy())
+            .with_object_path(self.0.path.as_deref())
+    }
+
+    /// Returns an iterator to get all symbols in the PLT section.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # let _fn = || -> Result<(), plthook::Error> {
+    /// use plthook::ObjectFile;
+    ///
+    /// let object = ObjectFile::open_main_program()?;
+    /// for symbol in object.symbols() {
+    ///     println!("{:?} {:?}", symbol.func_address, symbol.name);
+    /// }
+    /// # Ok(()) };
+    /// ```
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        symbols::iterator(self)
+    }
+}
+
+impl Drop for ObjectFileInner {
+    fn drop(&mut self) {
+        let _guard = ffi::exts::lock();
+        unsafe {
+            ffi::plthook_close(self.c_object);
+        }
+    }
+}
+
+// The C object is never modified after `plthook_open`, and the calls to the
+// library are serialized with `ffi::exts::lock`.
+unsafe impl Send for ObjectFileInner {}
+unsafe impl Sync for ObjectFileInner {}
+
+/// A replacement of an entry in the PLT section.
+///
+/// The address in the PLT entry is restored when this value is dropped. If
+/// the entry was modified by someone else, it is not written, unless the
+/// [`RestorePolicy`] is changed with
+/// [`set_restore_policy`](Self::set_restore_policy).
+pub struct Replacement {
+    restore_ref: Option<RestoreRef>,
+    next: chain::Link,
+    policy: RestorePolicy,
+    hook_data: Option<HookData>,
+}
+
+/// Memory used by the hook function, like the trampoline of a closure.
+///
+/// It is released when the replacement is dropped, only if the hook is
+/// removed from the PLT entry.
+type HookData = Box<dyn Any + Send + Sync>;
+
+/// A function to install in the chain of a PLT entry.
+struct Hook {
+    /// Address written in the entry.
+    address: *const c_void,
+
+    /// Address used by the previous replacement in the chain to call this
+    /// one. It is the same as `address`, except for hooks that have to
+    /// distinguish calls from the entry and calls from the chain.
+    chained: *const c_void,
+
+    /// Link to the next function in the chain.
+    next: chain::Link,
+
+    data: Option<HookData>,
+}
+
+impl Hook {
+    fn new(address: *const c_void) -> Hook {
+        Hook {
+            address,
+            chained: address,
+            next: chain::new_link(),
+            data: None,
+        }
+    }
+
+    /// Hook for `address`, with a guarded trampoline if it is enabled in
+    /// `options`.
+    fn with_options(address: *const c_void, options: &ReplaceOptions) -> Result<Hook> {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        if options.guarded {
+            return Hook::guarded(address);
+        }
+
+        // The options are only used on platforms with trampolines.
+        let _ = options;
+        Ok(Hook::new(address))
+    }
+
+    /// Hook that invokes `address` from a guarded trampoline. The previous
+    /// replacement in the chain invokes `address` directly.
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn guarded(address: *const c_void) -> Result<Hook> {
+        let next = chain::new_link();
+        let trampolines = trampoline::Trampolines::guarded(address, &next)?;
+
+        Ok(Hook {
+            address: trampolines.address(0),
+            chained: address,
+            next,
+            data: Some(Box::new(trampolines)),
+        })
+    }
+}
+
+/// Add the path to an error from [`ObjectFile::open_file`].
+///
+/// If the file can't be accessed, the error from the operating system is
+/// used as the source of the error.
+fn open_error(error: Error, path: &Path) -> Error {
+    let error = error.with_object_path(Some(path));
+
+    // The dynamic loader doesn't set `errno`, so the file is checked again
+    // to get the error from the system. Names without a directory are
+    // searched by the loader, and they are not checked.
+    if error.kind() == ErrorKind::FileNotFound
+        && std::error::Error::source(&error).is_none()
+        && path.components().count() > 1
+    {
+        if let Err(source) = std::fs::metadata(path) {
+            return error.with_source(source);
+        }
+    }
+
+    error
+}
+
+/// Convert `symbol_name` to a C string.
+///
+/// Names with a nul byte are reported as [`ErrorKind::InvalidArgument`].
+fn c_symbol_name(symbol_name: &str) -> Result<CString> {
+    match CString::new(symbol_name) {
+        Ok(s) => Ok(s),
+        Err(_) => {
+            let msg = format!("invalid symbol name: {symbol_name:?}");
+            Err(Error::new(ErrorKind::InvalidArgument, msg).with_symbol(symbol_name))
+        }
+    }
+}
+
+/// A hook to install with [`install_batch`].
+struct BatchItem<'a> {
+    symbol_name: CString,
+    slot: *const *const c_void,
+    prot: c_int,
+    object_path: Option<&'a PathBuf>,
+    hook: Hook,
+}
+
+/// Add every hook in `items` to the chain of its entry, writing the entries
+/// in a single batch. If a hook can't be installed, none of them is.
+unsafe fn install_batch(
+    items: Vec<BatchItem>,
+    options: &ReplaceOptions,
+) -> Result<Vec<Replacement>> {
+    install_planned(items, Vec::new(), options)
+}
+
+/// Like [`install_batch`], but the entry of every item has to be in the
+/// state of the same index in `expected`, which is checked with the lock of
+/// the chains. Items without an expected state are not checked.
+unsafe fn install_planned(
+    items: Vec<BatchItem>,
+    expected: Vec<chain::Expected>,
+    options: &ReplaceOptions,
+) -> Result<Vec<Replacement>> {
+    let switches: Vec<_> = items.iter().map(|_| chain::new_switch()).collect();
+    let layers: Vec<_> = items
+        .iter()
+        .zip(&switches)
+        .enumerate()
+        .map(|(index, (item, switch))| chain::NewLayer {
+            entry: chain::Entry {
+                slot: item.slot,
+                prot: item.prot,
+                protection: options.protection,
+                symbol: &item.symbol_name,
+                object_path: item.object_path,
+            },
+            hook: item.hook.address,
+            chained: item.hook.chained,
+            priority: options.priority,
+            next: &item.hook.next,
+            switch,
+            expected: expected.get(index).copied(),
+        })
+        .collect();
+
+    let ids = chain::install_many(&layers, options.protection)?;
+    drop(layers);
+
+    let replacements = items
+        .into_iter()
+        .zip(ids)
+        .zip(switches)
+        .map(|((item, layer), switch)| Replacement {
+            restore_ref: Some(RestoreRef {
+                symbol_name: item.symbol_name,
+                slot: item.slot,
+                layer,
+                switch,
+            }),
+            next: item.hook.next,
+            policy: RestorePolicy::default(),
+            hook_data: item.hook.data,
+        })
+        .collect();
+
+    Ok(replacements)
+}
+
+// The addresses in a replacement are only accessed with atomic operations,
+// and the chains of the entries are protected by a lock. The switch is only
+// accessed with atomic operations.
+unsafe impl Send for Replacement {}
+unsafe impl Sync for Replacement {}
+
+/// Reference to restore a symbol when `Replacement` is dropped.
+struct RestoreRef {
+    symbol_name: CString,
+    slot: *const *const c_void,
+    layer: chain::LayerId,
+    switch: Arc<chain::Switch>,
+}
+
+/// Action to take when a [`Replacement`] is dropped, but its PLT entry does
+/// not contain the address installed by [`ObjectFile::replace`].
+///
+/// This happens when another library writes the same entry after us.
+/// Replacements created by this crate are tracked in a chain, so they don't
+/// conflict with each other.
+///
+/// The default policy is `Skip`, so the hook of the other library is never
+/// removed without asking for it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Write the original address, discarding the current value.
+    Force,
+
+    /// Keep the current value in the entry. The memory used by the hook
+    /// (like the trampoline of a closure) is never released, because the
+    /// other library may still call it.
+    #[default]
+    Skip,
+
+    /// Panic. If the thread is already panicking, the entry is kept as
+    /// `Skip` would do.
+    Panic,
+}
+
+impl Replacement {
+    /// Returns the original address of the PLT entry.
+    ///
+    /// This address can be used to invoke the function replaced by
+    /// [`ObjectFile::replace`].
+    ///
+    /// If there are other replacements in the same entry, this is the address
+    /// of the next replacement in the chain (see
+    /// [`ObjectFile::replace_with_priority`]). The returned value may change
+    /// when other replacements are installed or removed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    /// use std::mem;
+    ///
+    /// extern "C" fn broken_getpid() -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    ///
+    /// let pid = unsafe { libc::getpid() };
+    /// assert_ne!(pid, -1);
+    ///
+    /// // Replace getpid with our broken function.
+    ///
+    /// let replacement = unsafe {
+    ///     program.replace("getpid", broken_getpid as *const _).unwrap()
+    /// };
+    ///
+    /// let libc_getpid: extern "C" fn() -> libc::pid_t = unsafe {
+    ///     mem::transmute(replacement.original_address())
+    /// };
+    ///
+    /// assert_eq!(unsafe { libc::getpid() }, -1);
+    /// assert_eq!(unsafe { (libc_getpid)() }, pid);
+    ///
+    /// drop(replacement);
+    /// assert_eq!(unsafe { libc::getpid() }, pid);
+    /// # }
+    /// ```
+    pub fn original_address(&self) -> *const c_void {
+        self.next.load(Ordering::SeqCst)
+    }
+
+    /// Discard this replacement, so the original address will not be restored
+    /// when this replacement is dropped.
+    ///
+    /// The replacement is kept in the chain of the PLT entry, so the address
+    /// returned by [`original_address`](Self::original_address) is still
+    /// valid if other replacements are removed later.
+    pub fn discard(&mut self) {
+        self.restore_ref = None;
+        self.leak_hook_data();
+    }
+
+    /// Set a label to identify the owner of this replacement in the list
+    /// returned by [`active_hooks`].
+    pub fn set_owner<S: Into<String>>(&mut self, owner: S) {
+        if let Some(restore_ref) = &self.restore_ref {
+            chain::set_owner(restore_ref.slot, restore_ref.layer, owner.into());
+        }
+    }
+
+    /// Set the policy used when this replacement is dropped and the PLT
+    /// entry was modified by someone else.
+    pub fn set_restore_policy(&mut self, policy: RestorePolicy) {
+        self.policy = policy;
+    }
+
+    /// Suspend this replacement, so the function is called like if the
+    /// replacement was not installed.
+    ///
+    /// The replacement is kept in the chain of the PLT entry, and the entry
+    /// address is cached, so it can be resumed with
+    /// [`resume`](Self::resume) without scanning the PLT section again.
+    ///
+    /// If the entry was modified by someone else, [`RestoreError::Conflict`]
+    /// is returned, like in [`restore`](Self::restore).
+    ///
+    /// This function does not allocate memory, and it never waits for a
+    /// lock, so it can be called from a signal handler. If the replacements
+    /// are being modified by another thread (or by the thread interrupted
+    /// by the signal handler), the replacement is suspended by that thread
+    /// when it finishes, and errors are not reported. If the entry can't be
+    /// written, the error has a fixed message, without the address of the
+    /// entry nor the error from the operating system.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::ObjectFile;
+    ///
+    /// extern "C" fn broken_getpgid(_: libc::pid_t) -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    ///
+    /// let mut replacement = unsafe {
+    ///     program.replace("getpgid", broken_getpgid as *const _).unwrap()
+    /// };
+    ///
+    /// assert_eq!(unsafe { libc::getpgid(0) }, -1);
+    ///
+    /// replacement.suspend().unwrap();
+    /// assert!(!replacement.is_active());
+    /// assert_ne!(unsafe { libc::getpgid(0) }, -1);
+    ///
+    /// replacement.resume().unwrap();
+    /// assert!(replacement.is_active());
+    /// assert_eq!(unsafe { libc::getpgid(0) }, -1);
+    /// # }
+    /// ```
+    pub fn suspend(&mut self) -> result::Result<(), RestoreError> {
+        match &self.restore_ref {
+            Some(r) => chain::set_active(r.slot, r.layer, &r.switch, false),
+            None => Err(RestoreError::Inactive),
+        }
+    }
+
+    /// Resume a replacement suspended with [`suspend`](Self::suspend).
+    ///
+    /// It does nothing if the replacement is not suspended. Like `suspend`,
+    /// it can be called from a signal handler.
+    pub fn resume(&mut self) -> result::Result<(), RestoreError> {
+        match &self.restore_ref {
+            Some(r) => chain::set_active(r.slot, r.layer, &r.switch, true),
+            None => Err(RestoreError::Inactive),
+        }
+    }
+
+    /// Returns `true` if the replacement is installed in the PLT entry, and
+    /// it is not suspended.
+    ///
+    /// It returns `false` after the replacement is restored or discarded,
+    /// or after a call to [`restore_all`].
+    pub fn is_active(&self) -> bool {
+        match &self.restore_ref {
+            Some(r) => chain::is_active(r.slot, r.layer),
+            None => false,
+        }
+    }
+
+    /// Restore the original address in the PLT entry.
+    ///
+    /// If this replacement is the first one in the chain of the entry, the
+    /// entry is written only if it still contains the address installed by
+    /// [`ObjectFile::replace`]. Otherwise, [`RestoreError::Conflict`] is
+    /// returned with the current value, and the replacement is kept, so it
+    /// can be restored again, discarded, or dropped.
+    ///
+    /// If there are other replacements before this one, they are linked to
+    /// the next one, and the entry is not modified.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(target_os = "linux")] {
+    /// use plthook::{ObjectFile, RestoreError};
+    ///
+    /// extern "C" fn broken_getppid() -> libc::pid_t {
+    ///     -1
+    /// }
+    ///
+    /// let program = ObjectFile::open_main_program().unwrap();
+    ///
+    /// let mut replacement = unsafe {
+    ///     program.replace("getppid", broken_getppid as *const _).unwrap()
+    /// };
+    ///
+    /// assert_eq!(unsafe { libc::getppid() }, -1);
+    ///
+    /// match replacement.restore() {
+    ///     Ok(()) => (),
+    ///     Err(RestoreError::Conflict { current }) => {
+    ///         eprintln!("getppid was replaced by {:?}", current);
+    ///     }
+    ///     Err(e) => panic!("{}", e),
+    /// }
+    ///
+    /// assert!(unsafe { libc::getppid() } > 0);
+    /// # }
+    /// ```
+    pub fn restore(&mut self) -> result::Result<(), RestoreError> {
+        let restore_ref = match &self.restore_ref {
+            Some(r) => r,
+            None => return Err(RestoreError::Inactive),
+        };
+
+        restore_ref.remove(false)?;
+        self.restore_ref = None;
+        Ok(())
+    }
+}
+
+impl Replacement {
+    /// Keep the memory used by the hook function, because it can be
+    /// reachable after this replacement is dropped.
+    fn leak_hook_data(&mut self) {
+        if let Some(data) = self.hook_data.take() {
+            mem::forget(data);
+        }
+    }
+
+    /// Restore the replacements in `items`, and remove them from the
+    /// vector.
+    ///
+    /// Every replacement is restored, even if some of them fail. The first
+    /// error is returned, and the failed replacements are kept.
+    pub(crate) fn restore_each<T>(
+        items: &mut Vec<T>,
+        replacement: impl Fn(&mut T) -> &mut Replacement,
+    ) -> result::Result<(), RestoreError> {
+        let mut result = Ok(());
+        items.retain_mut(|item| match replacement(item).restore() {
+            Ok(()) | Err(RestoreError::Inactive) => false,
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                true
+            }
+        });
+
+        result
+    }
+}
+
+impl RestoreRef {
+    /// Remove the replacement from the chain of the PLT entry.
+    fn remove(&self, force: bool) -> result::Result<(), RestoreError> {
+        chain::remove(self.slot, self.layer, force)
+    }
+}
+
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        let current = match self.restore() {
+            Ok(()) | Err(RestoreError::Inactive) => return,
+            Err(RestoreError::Conflict { current }) => current,
+            Err(RestoreError::Plthook(_)) => {
+                self.leak_hook_data();
+                return;
+            }
+        };
+
+        let restore_ref = match self.restore_ref.take() {
+            Some(r) => r,
+            None => return,
+        };
+
+        // The hook is still reachable from the entry, unless it is removed
+        // with `Force`.
+        match self.policy {
+            RestorePolicy::Force => {
+                if restore_ref.remove(true).is_err() {
+                    self.leak_hook_data();
+                }
+            }
+
+            RestorePolicy::Skip => {
+                chain::forget(restore_ref.slot, restore_ref.layer);
+                self.leak_hook_data();
+            }
+
+            RestorePolicy::Panic => {
+                chain::forget(restore_ref.slot, restore_ref.layer);
+                self.leak_hook_data();
+
+                if !std::thread::panicking() {
+                    panic!(
+                        "PLT entry for {:?} was modified by another hook: {:?}",
+                        restore_ref.symbol_name, current
+                    );
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,155 @@
+//! Find the definitions of a symbol in the loaded objects.
+//!
+//! The dynamic loader searches the objects in the order of its list of
+//! loaded objects: the main program, the libraries in `LD_PRELOAD`, and then
+//! their dependencies, followed by the objects loaded with `dlopen`.
+//! [`next_definition`] walks that list from any object. The scope of the
+//! objects (`RTLD_GLOBAL` or `RTLD_LOCAL`) is not available, so all of them
+//! are searched.
+
+use std::ffi::{c_void, CString};
+
+use crate::elf;
+use crate::errors::{Error, ErrorKind, Result};
+use crate::ObjectFile;
+
+/// Returns the address of the first definition of `symbol_name` in the
+/// objects loaded after `after`, in the order of the list of loaded objects
+/// of the dynamic loader.
+///
+/// For the objects loaded when the program starts, this is the same lookup
+/// done by `dlsym(RTLD_NEXT, symbol_name)` from `after`. However, this is a
+/// search in the order of the list, not the `RTLD_NEXT` semantics: objects
+/// loaded by `dlopen` with `RTLD_LOCAL` are not in the global scope, so
+/// `RTLD_NEXT` never searches them, but this function does. If `version`
+/// is given, the symbol is searched with `dlvsym`.
+///
+/// The address is found from the definitions in the objects, so it does not
+/// depend on the replacements installed in the PLT entries. This is useful
+/// for hooks in a library loaded with `LD_PRELOAD`, to find the real
+/// implementation of a function that may also be defined by the library.
+///
+/// This function is available on Linux. Versions are only supported with
+/// glibc.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::ObjectFile;
+///
+/// extern "C" fn broken_getpgrp() -> libc::pid_t {
+///     -1
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let replacement = unsafe { program.replace("getpgrp", broken_getpgrp as *const _).unwrap() };
+///
+/// let getpgrp = plthook::next_definition("getpgrp", None, &program).unwrap();
+/// let getpgrp: extern "C" fn() -> libc::pid_t = unsafe { std::mem::transmute(getpgrp) };
+///
+/// assert_eq!(unsafe { libc::getpgrp() }, -1);
+/// assert_ne!(getpgrp(), -1);
+/// # drop(replacement);
+/// # }
+/// ```
+pub fn next_definition(
+    symbol_name: &str,
+    version: Option<&str>,
+    after: &ObjectFile,
+) -> Result<*const c_void> {
+    let name = crate::c_symbol_name(symbol_name)?;
+    let version = match version.map(CString::new) {
+        None => None,
+        Some(Ok(v)) => Some(v),
+        Some(Err(_)) => {
+            let msg = format!("invalid symbol version: {version:?}");
+            return Err(Error::new(ErrorKind::InvalidArgument, msg).with_symbol(symbol_name));
+        }
+    };
+
+    // A single snapshot of the list, so every object is visited once even
+    // if other threads load or unload objects.
+    let loaded = elf::loaded();
+    let position =
+        elf::Object::of(after).and_then(|start| loaded.iter().position(|o| o.is(&start)));
+
+    let position = match position {
+        Some(position) => position,
+        None => {
+            let msg = "Could not find the object in the list of loaded objects".to_string();
+            let error = Error::new(ErrorKind::InternalError, msg)
+                .with_symbol(symbol_name)
+                .with_object_path(after.0.path.as_deref());
+            return Err(error);
+        }
+    };
+
+    for object in &loaded[position + 1..] {
+        if let Some(address) = definition(object, &name, version.as_ref())? {
+            return Ok(address);
+        }
+    }
+
+    let msg = match &version {
+        Some(v) => format!("no definition of {symbol_name}@{}", v.to_string_lossy()),
+        None => format!("no definition of {symbol_name}"),
+    };
+
+    let error = Error::new(ErrorKind::FunctionNotFound, msg)
+        .with_symbol(symbol_name)
+        .with_object_path(after.0.path.as_deref());
+    Err(error)
+}
+
+/// Returns the address of `name` if it is defined by `object`.
+///
+/// A handle for the object is used with `dlsym`, which also searches its
+/// dependencies, so the result is discarded if it is in another object.
+fn definition(
+    object: &elf::Object,
+    name: &CString,
+    version: Option<&CString>,
+) -> Result<Option<*const c_void>> {
+    // The main program has an empty name. Objects that can't be opened with
+    // `dlopen`, like the vDSO, are skipped.
+    if object.name().to_bytes().is_empty() {
+        return Ok(None);
+    }
+
+    let handle =
+        unsafe { libc::dlopen(object.name().as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD) };
+
+    if handle.is_null() {
+        return Ok(None);
+    }
+
+    let address = lookup(handle, name, version);
+    unsafe { libc::dlclose(handle) };
+
+    let address = address? as *const c_void;
+    if address.is_null() {
+        return Ok(None);
+    }
+
+    if object.contains(address) {
+        Ok(Some(address))
+    } else {
+        Ok(None)
+    }
+}
+
+fn lookup(handle: *mut c_void, name: &CString, version: Option<&CString>) -> Result<*mut c_void> {
+    match version {
+        None => Ok(unsafe { libc::dlsym(handle, name.as_ptr()) }),
+
+        #[cfg(target_env = "gnu")]
+        Some(version) => Ok(unsafe { libc::dlvsym(handle, name.as_ptr(), version.as_ptr()) }),
+
+        #[cfg(not(target_env = "gnu"))]
+        Some(_) => {
+            let msg = "symbol versions are only supported with glibc".to_string();
+            Err(Error::new(ErrorKind::NotImplemented, msg))
+        }
+    }
+}
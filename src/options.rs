@@ -0,0 +1,127 @@
+//! Options for [`ObjectFile::replace_with_options`].
+//!
+//! [`ObjectFile::replace_with_options`]: crate::ObjectFile::replace_with_options
+
+/// What to do with the memory protection of a PLT entry when it is written.
+///
+/// The pages containing the entries are usually read-only after the
+/// relocations are resolved (RELRO).
+///
+/// The default policy is `Restore`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtectionPolicy {
+    /// Make the page writable, write the entry, and then restore the
+    /// previous protection.
+    #[default]
+    Restore,
+
+    /// Make the page writable, and keep it writable after the entry is
+    /// written. This avoids the `mprotect` calls when the same entries are
+    /// patched repeatedly.
+    KeepWritable,
+
+    /// Never change the protection of a page. Writing an entry in a
+    /// read-only page returns an error with
+    /// [`ErrorKind::PermissionDenied`](crate::ErrorKind::PermissionDenied).
+    RefuseReadOnly,
+}
+
+impl ProtectionPolicy {
+    /// Returns the strictest of both policies. `RefuseReadOnly` is stricter
+    /// than `Restore`, and `Restore` is stricter than `KeepWritable`.
+    pub(crate) fn strictest(self, other: ProtectionPolicy) -> ProtectionPolicy {
+        fn rank(policy: ProtectionPolicy) -> u8 {
+            match policy {
+                ProtectionPolicy::KeepWritable => 0,
+                ProtectionPolicy::Restore => 1,
+                ProtectionPolicy::RefuseReadOnly => 2,
+            }
+        }
+
+        if rank(other) > rank(self) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Options to replace a function.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::{ObjectFile, ProtectionPolicy, ReplaceOptions};
+///
+/// extern "C" fn broken_getpgrp() -> libc::pid_t {
+///     -1
+/// }
+///
+/// let options = ReplaceOptions::new()
+///     .priority(10)
+///     .protection(ProtectionPolicy::KeepWritable);
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let replacement = unsafe {
+///     program.replace_with_options("getpgrp", broken_getpgrp as *const _, &options).unwrap()
+/// };
+///
+/// assert_eq!(unsafe { libc::getpgrp() }, -1);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceOptions {
+    pub(crate) priority: i32,
+    pub(crate) protection: ProtectionPolicy,
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub(crate) guarded: bool,
+}
+
+impl ReplaceOptions {
+    /// Create options with the default values.
+    pub fn new() -> Self {
+        ReplaceOptions::default()
+    }
+
+    /// Priority of the replacement in the chain of the PLT entry. See
+    /// [`ObjectFile::replace_with_priority`].
+    ///
+    /// The default priority is `0`.
+    ///
+    /// [`ObjectFile::replace_with_priority`]: crate::ObjectFile::replace_with_priority
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Policy for the memory protection of the PLT entry.
+    ///
+    /// Every replacement keeps its own policy. The entry is written with the
+    /// strictest policy of the replacements installed on it, so a
+    /// replacement with `KeepWritable` can't make writable an entry used by
+    /// a replacement with `RefuseReadOnly`.
+    pub fn protection(mut self, policy: ProtectionPolicy) -> Self {
+        self.protection = policy;
+        self
+    }
+
+    /// Invoke the function inside a [`HookGuard`](crate::HookGuard). See
+    /// [`ObjectFile::replace_guarded`].
+    ///
+    /// It is disabled by default. This option is available on Linux for
+    /// x86_64 and aarch64.
+    ///
+    /// [`ObjectFile::replace_guarded`]: crate::ObjectFile::replace_guarded
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub fn guarded(mut self, guarded: bool) -> Self {
+        self.guarded = guarded;
+        self
+    }
+}
@@ -0,0 +1,302 @@
+//! Plans to install replacements without modifying the memory.
+//!
+//! A [`HookSpec`] lists the functions to replace. [`plan`] resolves their
+//! PLT entries and checks if they can be written, and [`HookPlan::apply`]
+//! installs the replacements in the resolved entries.
+
+use std::ffi::{c_int, c_void, CString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::errors::{Error, Result};
+use crate::{
+    c_symbol_name, install_planned, BatchItem, Hook, ObjectFile, ReplaceOptions, Replacement,
+    Symbol,
+};
+use crate::{chain, slot, symbols};
+
+/// A function to replace in an object.
+struct Request {
+    object: ObjectFile,
+    symbol: String,
+    hook: *const c_void,
+}
+
+/// Functions to replace in one or more objects.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::{HookSpec, ObjectFile};
+///
+/// extern "C" fn broken_getpid() -> libc::pid_t {
+///     -1
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let spec = HookSpec::new()
+///     .hook(&program, "getpid", broken_getpid as *const _)
+///     .hook(&program, "no_such_function", broken_getpid as *const _);
+///
+/// let plan = plthook::plan(&spec);
+/// assert_eq!(plan.writes().len(), 1);
+/// assert_eq!(plan.failures().len(), 1);
+///
+/// // The memory is not modified until the plan is applied.
+/// assert_ne!(unsafe { libc::getpid() }, -1);
+///
+/// let replacements = unsafe { plan.apply().unwrap() };
+/// assert_eq!(unsafe { libc::getpid() }, -1);
+///
+/// drop(replacements);
+/// assert_ne!(unsafe { libc::getpid() }, -1);
+/// # }
+/// ```
+#[derive(Default)]
+pub struct HookSpec {
+    requests: Vec<Request>,
+    options: ReplaceOptions,
+}
+
+impl HookSpec {
+    /// Create an empty specification.
+    pub fn new() -> Self {
+        HookSpec::default()
+    }
+
+    /// Replace the function `symbol_name` in `object` with `func_address`.
+    pub fn hook(
+        mut self,
+        object: &ObjectFile,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Self {
+        self.requests.push(Request {
+            object: ObjectFile(Arc::clone(&object.0)),
+            symbol: symbol_name.to_owned(),
+            hook: func_address,
+        });
+        self
+    }
+
+    /// Options used for every replacement.
+    pub fn options(mut self, options: ReplaceOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// A PLT entry that would be written by a [`HookPlan`].
+pub struct PlannedWrite {
+    object: ObjectFile,
+    symbol_name: CString,
+
+    /// Name of the function.
+    pub symbol: String,
+
+    /// Address of the PLT entry.
+    pub slot: *const *const c_void,
+
+    /// Address in the entry when the plan was created.
+    pub current: *const c_void,
+
+    /// Memory protection of the page containing the entry.
+    pub protection: c_int,
+
+    /// Address of the new function.
+    pub hook: *const c_void,
+
+    /// `false` if the entry already has replacements with a higher priority.
+    /// In that case, the entry is not written, and the new function is
+    /// called by the previous replacement in the chain.
+    pub writes_entry: bool,
+}
+
+impl PlannedWrite {
+    /// Path of the object file, as given to [`ObjectFile::open_file`].
+    pub fn object_path(&self) -> Option<&Path> {
+        self.object.0.path.as_deref()
+    }
+}
+
+impl fmt::Debug for PlannedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlannedWrite")
+            .field("object_path", &self.object_path())
+            .field("symbol", &self.symbol)
+            .field("slot", &format_args!("{:p}", self.slot))
+            .field("current", &format_args!("{:p}", self.current))
+            .field("protection", &self.protection)
+            .field("hook", &format_args!("{:p}", self.hook))
+            .field("writes_entry", &self.writes_entry)
+            .finish()
+    }
+}
+
+/// A function in a [`HookSpec`] that can't be replaced.
+#[derive(Debug)]
+pub struct PlanFailure {
+    object_path: Option<PathBuf>,
+
+    /// Name of the function.
+    pub symbol: String,
+
+    /// Error that would be returned by [`ObjectFile::replace_with_options`].
+    pub error: Error,
+}
+
+impl PlanFailure {
+    /// Path of the object file, as given to [`ObjectFile::open_file`].
+    pub fn object_path(&self) -> Option<&Path> {
+        self.object_path.as_deref()
+    }
+}
+
+// The addresses in a plan are never dereferenced.
+unsafe impl Send for PlannedWrite {}
+unsafe impl Sync for PlannedWrite {}
+unsafe impl Send for HookSpec {}
+unsafe impl Sync for HookSpec {}
+
+/// The PLT entries that would be written to install a [`HookSpec`].
+///
+/// It is created by [`plan`].
+#[derive(Debug)]
+pub struct HookPlan {
+    writes: Vec<PlannedWrite>,
+    failures: Vec<PlanFailure>,
+    options: ReplaceOptions,
+}
+
+impl HookPlan {
+    /// Returns the entries that would be written, in the same order as the
+    /// functions in the specification.
+    pub fn writes(&self) -> &[PlannedWrite] {
+        &self.writes
+    }
+
+    /// Returns the functions that can't be replaced.
+    pub fn failures(&self) -> &[PlanFailure] {
+        &self.failures
+    }
+
+    /// Install the replacements in the entries of the plan.
+    ///
+    /// The functions are not resolved again, and the functions in
+    /// [`failures`](Self::failures) are ignored. The entries are written in
+    /// a single batch, like [`ObjectFile::replace_many`].
+    ///
+    /// If an entry does not contain the address it had when the plan was
+    /// created, or [`writes_entry`](PlannedWrite::writes_entry) changed
+    /// because of other replacements, no entry is written, and an error
+    /// with [`ErrorKind::StalePlan`] is returned. The entries are checked
+    /// with the same lock used to install the replacements.
+    ///
+    /// # Safety
+    ///
+    /// See [`ObjectFile::replace`].
+    ///
+    /// [`ErrorKind::StalePlan`]: crate::ErrorKind::StalePlan
+    pub unsafe fn apply(self) -> Result<Vec<Replacement>> {
+        let expected = self
+            .writes
+            .iter()
+            .map(|write| chain::Expected {
+                current: write.current,
+                writes_entry: write.writes_entry,
+            })
+            .collect();
+
+        let mut items = Vec::with_capacity(self.writes.len());
+        for write in &self.writes {
+            items.push(BatchItem {
+                symbol_name: write.symbol_name.clone(),
+                slot: write.slot,
+                prot: write.protection,
+                object_path: write.object.0.path.as_ref(),
+                hook: Hook::with_options(write.hook, &self.options)?,
+            });
+        }
+
+        install_planned(items, expected, &self.options)
+    }
+}
+
+/// Resolve the PLT entries of the functions in `spec`, and check if they
+/// can be written. The memory is not modified.
+pub fn plan(spec: &HookSpec) -> HookPlan {
+    let mut writes = Vec::new();
+    let mut failures = Vec::new();
+
+    let names: Vec<Result<CString>> = spec
+        .requests
+        .iter()
+        .map(|request| c_symbol_name(&request.symbol))
+        .collect();
+
+    let mut resolved: Vec<Option<Symbol>> = Vec::new();
+    resolved.resize_with(spec.requests.len(), || None);
+
+    // Functions in the same object are resolved in a single pass.
+    let mut done: Vec<bool> = names.iter().map(|name| name.is_err()).collect();
+    for (i, request) in spec.requests.iter().enumerate() {
+        if done[i] {
+            continue;
+        }
+
+        let group: Vec<usize> = (i..spec.requests.len())
+            .filter(|&j| !done[j] && Arc::ptr_eq(&spec.requests[j].object.0, &request.object.0))
+            .collect();
+
+        let group_names: Vec<_> = group
+            .iter()
+            .filter_map(|&j| names[j].as_ref().ok().cloned())
+            .collect();
+
+        let symbols = symbols::find_many(&request.object, &group_names);
+        for (&j, symbol) in group.iter().zip(symbols) {
+            resolved[j] = symbol;
+            done[j] = true;
+        }
+    }
+
+    for ((request, name), symbol) in spec.requests.iter().zip(names).zip(resolved) {
+        let checked = name.and_then(|name| match symbol {
+            Some(symbol) => {
+                let slot = symbol.func_address as *const *const c_void;
+                slot::check(slot, symbol.protection, spec.options.protection)
+                    .map(|prot| (name, slot, prot))
+            }
+            None => Err(request.object.function_not_found(&name)),
+        });
+
+        match checked {
+            Ok((symbol_name, slot, protection)) => writes.push(PlannedWrite {
+                object: ObjectFile(Arc::clone(&request.object.0)),
+                symbol_name,
+                symbol: request.symbol.clone(),
+                slot,
+                current: unsafe { slot::read(slot) },
+                protection,
+                hook: request.hook,
+                writes_entry: chain::writes_entry(slot, spec.options.priority),
+            }),
+
+            Err(error) => failures.push(PlanFailure {
+                object_path: request.object.0.path.clone(),
+                symbol: request.symbol.clone(),
+                error: error
+                    .with_symbol(&request.symbol)
+                    .with_object_path(request.object.0.path.as_deref()),
+            }),
+        }
+    }
+
+    HookPlan {
+        writes,
+        failures,
+        options: spec.options.clone(),
+    }
+}
@@ -0,0 +1,123 @@
+//! Replacements that can be applied from a signal handler.
+
+use std::ffi::{c_int, c_void};
+use std::ptr;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::errors::{RestoreError, Result};
+use crate::slot;
+
+/// A replacement of a PLT entry, resolved in advance.
+///
+/// It is created by [`ObjectFile::prepare`]. The address and the memory
+/// protection of the entry are computed when the hook is prepared, so
+/// [`apply`] and [`revert`] don't allocate memory, don't use locks, and
+/// don't format error messages. They only use `mprotect` and atomic
+/// operations, so they can be called from a signal handler, or in the
+/// child process after `fork()`.
+///
+/// Prepared hooks are not tracked in the chains of the entries (see
+/// [`ObjectFile::replace_with_priority`]), so they should not be used in
+/// entries modified by other replacements. Also, the entry is written
+/// without the lock used by other replacements, so two threads must not
+/// modify entries in the same page at the same time (see the safety
+/// requirements of [`ObjectFile::prepare`]).
+///
+/// The entry is not restored when this value is dropped.
+///
+/// [`ObjectFile::prepare`]: crate::ObjectFile::prepare
+/// [`ObjectFile::replace_with_priority`]: crate::ObjectFile::replace_with_priority
+/// [`apply`]: PreparedHook::apply
+/// [`revert`]: PreparedHook::revert
+pub struct PreparedHook {
+    slot: *const *const c_void,
+    prot: c_int,
+    hook: *const c_void,
+    original: AtomicPtr<c_void>,
+    applied: AtomicBool,
+}
+
+// `slot`, `prot` and `hook` are never modified after `new`. The entry is
+// accessed with `slot::read` and `slot::write_unlocked`, and the other
+// fields are atomic.
+unsafe impl Send for PreparedHook {}
+unsafe impl Sync for PreparedHook {}
+
+impl PreparedHook {
+    pub(crate) fn new(slot: *const *const c_void, prot: c_int, hook: *const c_void) -> Self {
+        // The page size is cached before `apply` needs it.
+        #[cfg(unix)]
+        slot::page_size();
+
+        PreparedHook {
+            slot,
+            prot,
+            hook,
+            original: AtomicPtr::new(ptr::null_mut()),
+            applied: AtomicBool::new(false),
+        }
+    }
+
+    /// Write the hook address in the PLT entry.
+    ///
+    /// The current address in the entry is saved, so it can be restored with
+    /// [`revert`](Self::revert). It does nothing if the hook is already
+    /// applied.
+    ///
+    /// If the memory protection of the entry can't be changed, the error
+    /// has a fixed message, without the address of the entry nor the
+    /// error from the operating system.
+    pub fn apply(&self) -> Result<()> {
+        if self.applied.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let current = unsafe { slot::read(self.slot) };
+        self.original.store(current as *mut _, Ordering::SeqCst);
+
+        if let Err(e) = self.write(self.hook) {
+            self.applied.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Restore the address saved by [`apply`](Self::apply).
+    ///
+    /// If the entry does not contain the hook address,
+    /// [`RestoreError::Conflict`] is returned, and the entry is not
+    /// modified. If the hook is not applied, [`RestoreError::Inactive`] is
+    /// returned.
+    pub fn revert(&self) -> result::Result<(), RestoreError> {
+        if !self.applied.load(Ordering::SeqCst) {
+            return Err(RestoreError::Inactive);
+        }
+
+        let current = unsafe { slot::read(self.slot) };
+        if current != self.hook {
+            return Err(RestoreError::Conflict { current });
+        }
+
+        self.write(self.original.load(Ordering::SeqCst))?;
+        self.applied.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns `true` if the hook is applied.
+    pub fn is_applied(&self) -> bool {
+        self.applied.load(Ordering::SeqCst)
+    }
+
+    /// Returns the address saved by the last call to [`apply`](Self::apply),
+    /// or null if it was never applied.
+    pub fn original_address(&self) -> *const c_void {
+        self.original.load(Ordering::SeqCst)
+    }
+
+    fn write(&self, address: *const c_void) -> Result<()> {
+        unsafe { slot::write_unlocked(self.slot, self.prot, address) }
+            .map_err(|e| slot::unlocked_error(&e))
+    }
+}
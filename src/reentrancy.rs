@@ -0,0 +1,212 @@
+//! Detect nested calls to hooks in the same thread.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+thread_local! {
+    /// Return address of the call running in a guarded trampoline.
+    static RETURN_ADDRESS: Cell<*const std::ffi::c_void> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Marks the current thread as running inside a hook.
+///
+/// Hooks that call other functions (for example, a `malloc` hook that
+/// writes a log with `fprintf`) can be invoked again from those functions.
+/// This guard allows them to detect these nested calls, and jump directly
+/// to the original function.
+///
+/// Closures installed with
+/// [`ObjectFile::replace_with_closure`](crate::ObjectFile::replace_with_closure),
+/// and functions installed with
+/// [`ObjectFile::replace_guarded`](crate::ObjectFile::replace_guarded),
+/// use this guard automatically: while one of them is running, any other
+/// of these hooks invoked in the same thread is skipped. Hooks installed
+/// with [`ObjectFile::replace`](crate::ObjectFile::replace) have to use it
+/// explicitly, or with the [`guarded!`](crate::guarded) macro.
+///
+/// The guard can't be sent to other threads.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::{HookGuard, ObjectFile};
+/// use std::ffi::c_void;
+/// use std::mem;
+/// use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+///
+/// static ORIGINAL: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+/// static CALLS: AtomicUsize = AtomicUsize::new(0);
+///
+/// extern "C" fn counted_getpgrp() -> libc::pid_t {
+///     let original: extern "C" fn() -> libc::pid_t =
+///         unsafe { mem::transmute(ORIGINAL.load(Ordering::SeqCst)) };
+///
+///     let _guard = match HookGuard::enter() {
+///         Some(guard) => guard,
+///         None => return original(),
+///     };
+///
+///     CALLS.fetch_add(1, Ordering::SeqCst);
+///
+///     // Nested call. It is not counted.
+///     unsafe { libc::getpgrp() };
+///
+///     original()
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let replacement = unsafe {
+///     program.replace("getpgrp", counted_getpgrp as *const _).unwrap()
+/// };
+///
+/// ORIGINAL.store(replacement.original_address() as *mut _, Ordering::SeqCst);
+///
+/// unsafe { libc::getpgrp() };
+/// assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+/// # }
+/// ```
+pub struct HookGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl HookGuard {
+    /// Mark the current thread as running inside a hook.
+    ///
+    /// Returns `None` if the thread is already inside a hook, or if its
+    /// thread-local storage was destroyed.
+    pub fn enter() -> Option<HookGuard> {
+        let entered = IN_HOOK.try_with(|h| !h.replace(true)).unwrap_or(false);
+        if !entered {
+            return None;
+        }
+
+        Some(HookGuard {
+            _not_send: PhantomData,
+        })
+    }
+}
+
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        let _ = IN_HOOK.try_with(|h| h.set(false));
+    }
+}
+
+/// Returns `true` if the current thread is running inside a hook.
+///
+/// See [`HookGuard`].
+pub fn in_hook() -> bool {
+    IN_HOOK.try_with(|h| h.get()).unwrap_or(true)
+}
+
+/// Enter the guard for a call from a guarded trampoline, and replace its
+/// return address with `stub`.
+///
+/// Returns `false` if the thread is already running a hook. After the guard
+/// is entered, nested calls (even from signal handlers) don't call this
+/// function, so a single return address is kept.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) unsafe fn enter_call(
+    return_address: *mut *const std::ffi::c_void,
+    stub: *const std::ffi::c_void,
+) -> bool {
+    let guard = match HookGuard::enter() {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    if RETURN_ADDRESS.try_with(|r| r.set(*return_address)).is_err() {
+        return false;
+    }
+
+    *return_address = stub;
+
+    // The guard is released by `leave_call`.
+    std::mem::forget(guard);
+    true
+}
+
+/// Leave the guard entered by `enter_call`, and returns the original return
+/// address. It is called by the stub of the guarded trampolines.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) extern "C" fn leave_call() -> *const std::ffi::c_void {
+    // The thread-local storage was available in `enter_call`, and it is not
+    // destroyed while the thread is running a function.
+    let address = RETURN_ADDRESS
+        .try_with(|r| r.get())
+        .unwrap_or_else(|_| std::process::abort());
+
+    drop(HookGuard {
+        _not_send: PhantomData,
+    });
+
+    address
+}
+
+/// Evaluate a block inside a [`HookGuard`].
+///
+/// If the thread is already running a hook, the block is not evaluated,
+/// and the macro returns the value of the first expression, which usually
+/// calls the original function.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(target_os = "linux")] {
+/// use plthook::ObjectFile;
+/// use std::ffi::c_void;
+/// use std::mem;
+/// use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+///
+/// static ORIGINAL: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+/// static CALLS: AtomicUsize = AtomicUsize::new(0);
+///
+/// extern "C" fn counted_getpgid(pid: libc::pid_t) -> libc::pid_t {
+///     let original: extern "C" fn(libc::pid_t) -> libc::pid_t =
+///         unsafe { mem::transmute(ORIGINAL.load(Ordering::SeqCst)) };
+///
+///     plthook::guarded!(original(pid), {
+///         CALLS.fetch_add(1, Ordering::SeqCst);
+///
+///         // Nested call. It is not counted.
+///         unsafe { libc::getpgid(pid) };
+///
+///         original(pid)
+///     })
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+/// let replacement = unsafe {
+///     program.replace("getpgid", counted_getpgid as *const _).unwrap()
+/// };
+///
+/// ORIGINAL.store(replacement.original_address() as *mut _, Ordering::SeqCst);
+///
+/// unsafe { libc::getpgid(0) };
+/// assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! guarded {
+    ($nested:expr, $body:block) => {
+        match $crate::HookGuard::enter() {
+            Some(_guard) => $body,
+            None => $nested,
+        }
+    };
+}
@@ -0,0 +1,158 @@
+//! Hooks that are only visible to a single thread.
+//!
+//! A scoped hook is installed in the chain of the PLT entry like any other
+//! replacement, but its address is a [trampoline](crate::trampoline) that
+//! compares the current thread with the thread that created the hook. The
+//! trampoline jumps to the hook only in that thread, and to the next
+//! function in the chain in any other thread.
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::chain::{self, Link};
+use crate::errors::{Error, ErrorKind, Result};
+use crate::trampoline::Trampolines;
+use crate::{c_symbol_name, Hook, ObjectFile, ReplaceOptions, Replacement};
+
+thread_local! {
+    static THREAD_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns an identifier for the current thread, unique in the process.
+///
+/// It returns `0` if the thread-local storage was destroyed.
+fn current_thread_id() -> usize {
+    THREAD_ID
+        .try_with(|id| {
+            if id.get() == 0 {
+                id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+            }
+            id.get()
+        })
+        .unwrap_or(0)
+}
+
+/// Data used by the trampoline of a scoped hook.
+struct Context {
+    thread: usize,
+    hook: *const c_void,
+    next: Link,
+}
+
+/// Callback for the trampolines. It returns the address of the hook if it
+/// is called from the thread of the scoped hook.
+unsafe extern "C" fn dispatch(context: *const c_void) -> *const c_void {
+    let context = &*(context as *const Context);
+
+    if current_thread_id() == context.thread {
+        context.hook
+    } else {
+        context.next.load(Ordering::SeqCst)
+    }
+}
+
+// The context is never modified after the trampoline is created, and the
+// link is only accessed with atomic operations.
+unsafe impl Send for Context {}
+
+/// A replacement that is only visible to the thread that created it.
+///
+/// Other threads calling the function will invoke the next function in the
+/// chain of the PLT entry, like if the hook was not installed. This is
+/// useful for tests running in parallel.
+///
+/// The PLT entry is restored when this value is dropped. It can't be sent to
+/// other threads.
+///
+/// This type is available on Linux for x86_64 and aarch64.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))] {
+/// use plthook::{ObjectFile, ScopedHook};
+/// use std::thread;
+///
+/// extern "C" fn broken_getpgrp() -> libc::pid_t {
+///     -1
+/// }
+///
+/// let program = ObjectFile::open_main_program().unwrap();
+///
+/// let hook = unsafe {
+///     ScopedHook::for_current_thread(&program, "getpgrp", broken_getpgrp as *const _).unwrap()
+/// };
+///
+/// assert_eq!(unsafe { libc::getpgrp() }, -1);
+///
+/// let pgrp = thread::spawn(|| unsafe { libc::getpgrp() }).join().unwrap();
+/// assert_ne!(pgrp, -1);
+///
+/// drop(hook);
+/// assert_eq!(unsafe { libc::getpgrp() }, pgrp);
+/// # }
+/// ```
+pub struct ScopedHook {
+    replacement: Replacement,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ScopedHook {
+    /// Replace the function `symbol_name` in `object`, only for the current
+    /// thread.
+    ///
+    /// # Safety
+    ///
+    /// See [`ObjectFile::replace`].
+    pub unsafe fn for_current_thread(
+        object: &ObjectFile,
+        symbol_name: &str,
+        func_address: *const c_void,
+    ) -> Result<ScopedHook> {
+        let symbol_name = c_symbol_name(symbol_name)?;
+
+        let thread = current_thread_id();
+        if thread == 0 {
+            let msg = "Thread-local storage is not available".to_string();
+            return Err(Error::new(ErrorKind::InternalError, msg));
+        }
+
+        let next = chain::new_link();
+
+        let context = Box::new(Context {
+            thread,
+            hook: func_address,
+            next: next.clone(),
+        });
+
+        // The context is released with the trampoline, when there are no
+        // calls running in it.
+        let ptr = &*context as *const Context as *const c_void;
+        let trampolines = Trampolines::new(&[(ptr, dispatch, &next)], context)?;
+        let address = trampolines.address(0);
+
+        let hook = Hook {
+            address,
+            chained: address,
+            next,
+            data: Some(Box::new(trampolines)),
+        };
+
+        let replacement = object.install(symbol_name, hook, &ReplaceOptions::default())?;
+
+        Ok(ScopedHook {
+            replacement,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Returns the address of the next function in the chain of the PLT
+    /// entry. See [`Replacement::original_address`].
+    pub fn original_address(&self) -> *const c_void {
+        self.replacement.original_address()
+    }
+}
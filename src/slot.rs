@@ -0,0 +1,534 @@
+//! Write addresses in PLT entries.
+//!
+//! The pages containing the entries are usually read-only (for example,
+//! when the object is linked with `-z relro`), so the protection is changed
+//! before writing the entry, and then restored, according to the
+//! [`ProtectionPolicy`]. By default, this is the same process followed by
+//! `plthook_replace`.
+//!
+//! Entries are accessed with atomic operations, so a thread calling the
+//! function sees a valid address while the entry is written by another
+//! thread.
+//!
+//! When many entries are written at once, a [`Batch`] changes the
+//! protection of every page only once.
+
+use std::cell::RefCell;
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::options::ProtectionPolicy;
+
+/// Lock to serialize the writes.
+///
+/// Two threads writing entries in the same page could restore the
+/// protection of the page while the other is still writing.
+///
+/// It is also held while the protection of the pages is read, because a page
+/// being written by another thread would be reported as writable.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Take the write lock, so the protection of the pages is not changed until
+/// the guard is dropped.
+///
+/// It has to be held when `plthook_open` is called, because the library
+/// reads the protection of the pages from `/proc/self/maps`, and keeps it
+/// for `plthook_enum_with_prot`.
+pub(crate) fn lock_protection() -> MutexGuard<'static, ()> {
+    WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Take the write lock if it is not held by any thread. It never waits, so
+/// it can be used from a signal handler.
+pub(crate) fn try_lock_protection() -> Option<MutexGuard<'static, ()>> {
+    match WRITE_LOCK.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+thread_local! {
+    /// Pages of the [`Batch`] opened by the current thread.
+    static BATCH_PAGES: RefCell<Vec<PageRange>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Read the address in the entry at `slot`.
+pub(crate) unsafe fn read(slot: *const *const c_void) -> *const c_void {
+    (*(slot as *const AtomicPtr<c_void>)).load(Ordering::SeqCst)
+}
+
+/// Store `address` in the entry at `slot`. The page has to be writable.
+unsafe fn store(slot: *const *const c_void, address: *const c_void) {
+    (*(slot as *const AtomicPtr<c_void>)).store(address as *mut _, Ordering::SeqCst)
+}
+
+/// Write `address` in the entry at `slot`. `prot` is the protection of the
+/// page containing the entry, as reported by `plthook_enum_with_prot`.
+///
+/// If `prot` is `0`, the protection is read from `/proc/self/maps` (on
+/// Linux).
+pub(crate) unsafe fn write(
+    slot: *const *const c_void,
+    prot: c_int,
+    policy: ProtectionPolicy,
+    address: *const c_void,
+) -> Result<()> {
+    if in_batch(slot) {
+        store(slot, address);
+        return Ok(());
+    }
+
+    let _guard = lock_protection();
+
+    let prot = resolve_protection(slot, prot)?;
+
+    if policy == ProtectionPolicy::RefuseReadOnly && !is_writable(slot, prot) {
+        return Err(read_only_error(slot));
+    }
+
+    let restore = policy != ProtectionPolicy::KeepWritable;
+
+    match write_unlocked_with(slot, prot, address, restore) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(protection_error(page_of(slot), e)),
+    }
+}
+
+fn read_only_error(slot: *const *const c_void) -> Error {
+    let msg = format!("PLT entry at {slot:?} is in a read-only page");
+    Error::new(ErrorKind::PermissionDenied, msg).with_address(slot)
+}
+
+/// Error for [`write_unlocked`] and [`write_held`]. It has a fixed message,
+/// so it can be created from a signal handler.
+pub(crate) fn unlocked_error(error: &io::Error) -> Error {
+    // Errors from `mprotect` have an error code. `write_held` returns an
+    // error without it for the `RefuseReadOnly` policy.
+    if error.raw_os_error().is_none() {
+        Error::new_static(
+            ErrorKind::PermissionDenied,
+            "PLT entry is in a read-only page",
+        )
+    } else {
+        Error::new_static(
+            ErrorKind::InternalError,
+            "Could not change the process memory permission",
+        )
+    }
+}
+
+pub(crate) fn protection_error(page: *const c_void, error: io::Error) -> Error {
+    let msg = format!("Could not change the process memory permission at {page:?}: {error}");
+    Error::new(ErrorKind::InternalError, msg)
+        .with_address(page)
+        .with_source(error)
+}
+
+/// Returns the protection of the page containing `slot`. If `prot` is `0`,
+/// it is read from `/proc/self/maps` (on Linux).
+pub(crate) fn resolve_protection(slot: *const *const c_void, prot: c_int) -> Result<c_int> {
+    #[cfg(target_os = "linux")]
+    let prot = match prot {
+        0 => maps_protection(page_of(slot) as usize).unwrap_or(0),
+        _ => prot,
+    };
+
+    if !cfg!(windows) && prot == 0 {
+        let msg = format!(
+            "Could not get the process memory permission at {:?}",
+            page_of(slot)
+        );
+        return Err(Error::new(ErrorKind::InternalError, msg).with_address(slot));
+    }
+
+    Ok(prot)
+}
+
+/// Check if the entry at `slot` can be written with `policy`, and returns
+/// the protection of its page.
+///
+/// The write lock is not taken, so the protection may be changed by another
+/// thread before the entry is written.
+pub(crate) fn check(
+    slot: *const *const c_void,
+    prot: c_int,
+    policy: ProtectionPolicy,
+) -> Result<c_int> {
+    let prot = resolve_protection(slot, prot)?;
+
+    if policy == ProtectionPolicy::RefuseReadOnly && !is_writable(slot, prot) {
+        return Err(read_only_error(slot));
+    }
+
+    Ok(prot)
+}
+
+/// Consecutive pages with the same protection.
+#[cfg_attr(windows, allow(dead_code))]
+struct PageRange {
+    start: usize,
+    end: usize,
+    prot: c_int,
+
+    /// `true` if the pages were made writable by the batch.
+    changed: bool,
+}
+
+/// A set of entries that can be written without changing the protection of
+/// their pages.
+///
+/// When the batch is opened, the read-only pages containing the entries are
+/// grouped in ranges, and every range is made writable with a single call.
+/// While the batch is open, [`write`] only stores the address for these
+/// entries, if it is called from the same thread. When the batch is
+/// dropped, the protection of the pages is restored, unless the policy is
+/// [`ProtectionPolicy::KeepWritable`].
+///
+/// The write lock is held while the batch is open.
+pub(crate) struct Batch {
+    restore: bool,
+
+    #[cfg(unix)]
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Batch {
+    /// Open a batch for the entries in `slots`, with the protection of
+    /// their pages, as reported by `plthook_enum_with_prot`.
+    #[cfg(unix)]
+    pub(crate) fn open(
+        slots: &[(*const *const c_void, c_int)],
+        policy: ProtectionPolicy,
+    ) -> Result<Batch> {
+        let guard = lock_protection();
+
+        let mut pages = Vec::with_capacity(slots.len());
+        for &(slot, prot) in slots {
+            let prot = check(slot, prot, policy)?;
+            pages.push((page_of(slot) as usize, prot));
+        }
+
+        pages.sort_unstable_by_key(|&(page, _)| page);
+        pages.dedup_by_key(|&mut (page, _)| page);
+
+        let page_size = page_size();
+        let mut ranges: Vec<PageRange> = Vec::new();
+        for (page, prot) in pages {
+            match ranges.last_mut() {
+                Some(range) if range.end == page && range.prot == prot => range.end += page_size,
+                _ => ranges.push(PageRange {
+                    start: page,
+                    end: page + page_size,
+                    prot,
+                    changed: false,
+                }),
+            }
+        }
+
+        for i in 0..ranges.len() {
+            let range = &ranges[i];
+            if range.prot & libc::PROT_WRITE != 0 {
+                continue;
+            }
+
+            let ret = unsafe {
+                libc::mprotect(
+                    range.start as *mut _,
+                    range.end - range.start,
+                    writable_protection(range.prot),
+                )
+            };
+
+            if ret != 0 {
+                let error = io::Error::last_os_error();
+                restore_ranges(&ranges[..i]);
+                return Err(protection_error(range.start as *const c_void, error));
+            }
+
+            ranges[i].changed = true;
+        }
+
+        BATCH_PAGES.with(|p| *p.borrow_mut() = ranges);
+
+        Ok(Batch {
+            restore: policy != ProtectionPolicy::KeepWritable,
+            _guard: guard,
+        })
+    }
+
+    /// Open a batch for the entries in `slots`.
+    ///
+    /// The protection of every entry is changed with `VirtualProtect` on
+    /// MSWindows, so the entries are written as usual, and the write lock
+    /// is not held by the batch.
+    #[cfg(windows)]
+    pub(crate) fn open(
+        _slots: &[(*const *const c_void, c_int)],
+        policy: ProtectionPolicy,
+    ) -> Result<Batch> {
+        Ok(Batch {
+            restore: policy != ProtectionPolicy::KeepWritable,
+        })
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let ranges = BATCH_PAGES.with(|p| std::mem::take(&mut *p.borrow_mut()));
+
+        if self.restore {
+            restore_ranges(&ranges);
+        }
+    }
+}
+
+/// Restore the protection of the pages changed by a batch.
+#[cfg(unix)]
+fn restore_ranges(ranges: &[PageRange]) {
+    for range in ranges.iter().filter(|r| r.changed) {
+        unsafe {
+            libc::mprotect(range.start as *mut _, range.end - range.start, range.prot);
+        }
+    }
+}
+
+/// Check that `address` can be installed as the hook of the entry at
+/// `slot`: it has to be in an executable mapping (on Linux), and it can't
+/// be the entry itself.
+pub(crate) fn check_hook(slot: *const *const c_void, address: *const c_void) -> Result<()> {
+    let invalid =
+        |msg: String| Err(Error::new(ErrorKind::InvalidArgument, msg).with_address(address));
+
+    if address.is_null() {
+        return invalid("hook address is null".to_string());
+    }
+
+    if address == slot.cast() {
+        return invalid(format!("hook address {address:?} is the PLT entry itself"));
+    }
+
+    #[cfg(target_os = "linux")]
+    match maps_protection(address as usize) {
+        None => return invalid(format!("hook address {address:?} is not mapped")),
+        Some(prot) if prot & libc::PROT_EXEC == 0 => {
+            return invalid(format!(
+                "hook address {address:?} is not in an executable mapping"
+            ));
+        }
+        Some(_) => (),
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn restore_ranges(_ranges: &[PageRange]) {}
+
+/// Returns `true` if `slot` is in a page of the batch opened by the current
+/// thread.
+fn in_batch(slot: *const *const c_void) -> bool {
+    let slot = slot as usize;
+
+    BATCH_PAGES
+        .try_with(|p| p.borrow().iter().any(|r| r.start <= slot && slot < r.end))
+        .unwrap_or(false)
+}
+
+/// Returns the protection of the mapping containing `address`, from
+/// `/proc/self/maps`.
+///
+/// `plthook_enum_with_prot` only knows the mappings of the object, so
+/// this is used when it does not report the protection.
+#[cfg(target_os = "linux")]
+pub(crate) fn maps_protection(address: usize) -> Option<c_int> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next()?;
+        let perms = fields.next()?.as_bytes();
+
+        let (start, end) = range.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+
+        if start <= address && address < end && perms.len() >= 3 {
+            let mut prot = 0;
+            for (flag, value) in
+                perms[..3]
+                    .iter()
+                    .zip([libc::PROT_READ, libc::PROT_WRITE, libc::PROT_EXEC])
+            {
+                if *flag != b'-' {
+                    prot |= value;
+                }
+            }
+            return Some(prot);
+        }
+    }
+
+    None
+}
+
+/// Returns the size of the memory pages. The value is cached after the
+/// first call.
+#[cfg(unix)]
+pub(crate) fn page_size() -> usize {
+    use std::sync::atomic::AtomicUsize;
+
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+            PAGE_SIZE.store(size, Ordering::Relaxed);
+            size
+        }
+
+        size => size,
+    }
+}
+
+#[cfg(unix)]
+fn page_of(slot: *const *const c_void) -> *mut c_void {
+    (slot as usize & !(page_size() - 1)) as *mut c_void
+}
+
+#[cfg(windows)]
+fn page_of(slot: *const *const c_void) -> *mut c_void {
+    slot as *mut c_void
+}
+
+/// Write `address` in the entry at `slot`, without taking the write lock.
+///
+/// This function does not allocate memory, so it can be used from a signal
+/// handler. [`page_size`] has to be called before.
+pub(crate) unsafe fn write_unlocked(
+    slot: *const *const c_void,
+    prot: c_int,
+    address: *const c_void,
+) -> io::Result<()> {
+    write_unlocked_with(slot, prot, address, true)
+}
+
+/// Like [`write`], but the write lock has to be held by the caller, and
+/// `prot` has to be resolved with [`resolve_protection`].
+///
+/// Like [`write_unlocked`], it can be used from a signal handler.
+pub(crate) unsafe fn write_held(
+    slot: *const *const c_void,
+    prot: c_int,
+    policy: ProtectionPolicy,
+    address: *const c_void,
+) -> io::Result<()> {
+    if policy == ProtectionPolicy::RefuseReadOnly && !is_writable(slot, prot) {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+
+    let restore = policy != ProtectionPolicy::KeepWritable;
+    write_unlocked_with(slot, prot, address, restore)
+}
+
+/// Returns `true` if the page containing `slot` is writable.
+#[cfg(unix)]
+fn is_writable(_slot: *const *const c_void, prot: c_int) -> bool {
+    prot & libc::PROT_WRITE != 0
+}
+
+/// Returns the protection to make writable a page with `prot`.
+///
+/// `PROT_EXEC` is kept, because the page can contain code used by other
+/// threads (like `.dynsym` and `.text` when the object is linked without
+/// `-z separate-code`).
+#[cfg(unix)]
+fn writable_protection(prot: c_int) -> c_int {
+    libc::PROT_READ | libc::PROT_WRITE | (prot & libc::PROT_EXEC)
+}
+
+/// Write `address` in the entry at `slot`. If `restore` is `false`, the page
+/// is kept writable.
+#[cfg(unix)]
+unsafe fn write_unlocked_with(
+    slot: *const *const c_void,
+    prot: c_int,
+    address: *const c_void,
+    restore: bool,
+) -> io::Result<()> {
+    let page = page_of(slot);
+    let page_size = page_size();
+    let writable = is_writable(slot, prot);
+
+    if !writable && libc::mprotect(page, page_size, writable_protection(prot)) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    store(slot, address);
+
+    if !writable && restore {
+        libc::mprotect(page, page_size, prot);
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if the page containing `slot` is writable.
+///
+/// The protection from `plthook_enum_with_prot` is not available on
+/// MSWindows, so it is read with `VirtualQuery`.
+#[cfg(windows)]
+fn is_writable(slot: *const *const c_void, _prot: c_int) -> bool {
+    use std::mem::{self, MaybeUninit};
+    use winapi::um::memoryapi::VirtualQuery;
+    use winapi::um::winnt::{
+        MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_READWRITE,
+        PAGE_WRITECOPY,
+    };
+
+    let mut info = MaybeUninit::<MEMORY_BASIC_INFORMATION>::uninit();
+    let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+
+    if unsafe { VirtualQuery(slot as *const _, info.as_mut_ptr(), size) } == 0 {
+        return false;
+    }
+
+    let writable =
+        PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+
+    unsafe { info.assume_init().Protect & writable != 0 }
+}
+
+/// Write `address` in the entry at `slot`. If `restore` is `false`, the page
+/// is kept writable.
+///
+/// The protection from `plthook_enum_with_prot` is not available on
+/// MSWindows, so the entry is always changed with `VirtualProtect`.
+#[cfg(windows)]
+unsafe fn write_unlocked_with(
+    slot: *const *const c_void,
+    _prot: c_int,
+    address: *const c_void,
+    restore: bool,
+) -> io::Result<()> {
+    use std::mem;
+    use winapi::um::memoryapi::VirtualProtect;
+    use winapi::um::winnt::PAGE_EXECUTE_READWRITE;
+
+    let size = mem::size_of::<*const c_void>();
+    let mut old_prot = 0;
+
+    if VirtualProtect(slot as *mut _, size, PAGE_EXECUTE_READWRITE, &mut old_prot) == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    store(slot, address);
+
+    if restore {
+        VirtualProtect(slot as *mut _, size, old_prot, &mut old_prot);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Suggestions for functions that are not found in an object.
+//!
+//! The name of an import is often different from the name used in the
+//! source code, because of the redirections in the glibc headers (see
+//! [`aliases`](crate::aliases)), or a typo. The suggestions are the names
+//! in the same family of the function, and the names at a small edit
+//! distance.
+
+use std::ffi::CStr;
+
+use crate::{aliases, symbols, ObjectFile};
+
+/// Maximum number of suggestions.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Returns the names imported by `object` that are close to `wanted`,
+/// sorted by relevance.
+pub(crate) fn near_misses(object: &ObjectFile, wanted: &str) -> Vec<String> {
+    let family = aliases::family(wanted).unwrap_or(&[]);
+    let max_distance = (wanted.len() / 3).max(1);
+
+    // Names in the family of `wanted` have distance `0`.
+    let mut candidates: Vec<(usize, String)> = Vec::new();
+    for symbol in symbols::iterator(object) {
+        let name = String::from_utf8_lossy(symbols::plain_name(symbol.name.to_bytes()));
+        if name == wanted || candidates.iter().any(|(_, c)| *c == name) {
+            continue;
+        }
+
+        let distance = if family.contains(&&*name) {
+            0
+        } else {
+            edit_distance(wanted.as_bytes(), name.as_bytes())
+        };
+
+        if distance <= max_distance {
+            candidates.push((distance, name.into_owned()));
+        }
+    }
+
+    candidates.sort();
+    candidates.truncate(MAX_SUGGESTIONS);
+    candidates.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Returns `true` if `object` defines the symbol `name`.
+#[cfg(target_os = "linux")]
+pub(crate) fn defines(object: &ObjectFile, name: &CStr) -> bool {
+    let elf = match crate::elf::Object::of(object) {
+        Some(elf) => elf,
+        None => return false,
+    };
+
+    matches!(elf.find_symbol(name), Some(s) if s.defined)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn defines(_object: &ObjectFile, _name: &CStr) -> bool {
+    false
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.iter().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
@@ -33,7 +33,7 @@ use crate::ffi::plthook_enum_with_prot;
 /// assert_eq!(pid, unsafe { (*getpid_fn)() as u32 });
 /// # }
 /// ```
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Symbol {
     /// Name of the symbol.
     pub name: CString,
@@ -69,6 +69,8 @@ impl Iterator for SymbolIterator<'_> {
         let mut func_address = MaybeUninit::uninit();
         let mut protection = 0;
 
+        let _guard = crate::ffi::exts::lock();
+
         let ret = unsafe {
             plthook_enum_with_prot(
                 self.object.0.c_object,
@@ -96,3 +98,166 @@ impl Iterator for SymbolIterator<'_> {
         })
     }
 }
+
+/// Returns the first symbol that `plthook_replace` would select for the
+/// function `wanted`.
+pub(crate) fn find(object: &crate::ObjectFile, wanted: &CStr) -> Option<Symbol> {
+    find_all(object, wanted).next()
+}
+
+/// Returns all symbols that `plthook_replace` could select for `wanted`.
+pub(crate) fn find_all<'a>(
+    object: &'a crate::ObjectFile,
+    wanted: &'a CStr,
+) -> impl Iterator<Item = Symbol> + 'a {
+    let wanted = wanted.to_bytes();
+    iterator(object).filter(move |sym| name_matches(sym.name.to_bytes(), wanted))
+}
+
+/// Returns the first symbol for every function in `wanted`, with a single
+/// pass over the PLT section.
+pub(crate) fn find_many(object: &crate::ObjectFile, wanted: &[CString]) -> Vec<Option<Symbol>> {
+    let mut found = vec![None; wanted.len()];
+    let mut pending = wanted.len();
+
+    let index = WantedIndex::new(wanted);
+
+    for symbol in iterator(object) {
+        if pending == 0 {
+            break;
+        }
+
+        for i in index.candidates(symbol.name.to_bytes()) {
+            if found[i].is_none() && name_matches(symbol.name.to_bytes(), wanted[i].to_bytes()) {
+                found[i] = Some(symbol.clone());
+                pending -= 1;
+            }
+        }
+    }
+
+    found
+}
+
+/// Index to find the functions that a symbol name could match.
+///
+/// On ELF, names can only be followed by a version (`name@VERSION`), so the
+/// functions are indexed by name. On other platforms, every function is a
+/// candidate.
+struct WantedIndex {
+    #[cfg(not(any(windows, target_os = "macos")))]
+    by_name: std::collections::HashMap<Vec<u8>, Vec<usize>>,
+
+    #[cfg(any(windows, target_os = "macos"))]
+    len: usize,
+}
+
+impl WantedIndex {
+    #[cfg(not(any(windows, target_os = "macos")))]
+    fn new(wanted: &[CString]) -> Self {
+        let mut by_name = std::collections::HashMap::<_, Vec<_>>::new();
+        for (i, name) in wanted.iter().enumerate() {
+            by_name.entry(name.to_bytes().to_vec()).or_default().push(i);
+        }
+
+        WantedIndex { by_name }
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    fn candidates(&self, name: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        self.by_name
+            .get(plain_name(name))
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    #[cfg(any(windows, target_os = "macos"))]
+    fn new(wanted: &[CString]) -> Self {
+        WantedIndex { len: wanted.len() }
+    }
+
+    #[cfg(any(windows, target_os = "macos"))]
+    fn candidates(&self, _name: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        0..self.len
+    }
+}
+
+/// Returns `name`, as found in the PLT section, without the decorations
+/// added by the platform, like the version of an ELF symbol. The result can
+/// be used as the name of a function to replace.
+#[cfg(not(any(windows, target_os = "macos")))]
+pub(crate) fn plain_name(name: &[u8]) -> &[u8] {
+    match name.iter().position(|&c| c == b'@') {
+        Some(at) => &name[..at],
+        None => name,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn plain_name(name: &[u8]) -> &[u8] {
+    let name = name.strip_prefix(b"@").unwrap_or(name);
+    let name = name.strip_prefix(b"_").unwrap_or(name);
+    match name.iter().position(|&c| c == b'$') {
+        Some(end) => &name[..end],
+        None => name,
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn plain_name(name: &[u8]) -> &[u8] {
+    name
+}
+
+/// Check if `name`, as found in the PLT section, refers to the function
+/// `wanted`. The rules are the same used by `plthook_replace`.
+#[cfg(not(any(windows, target_os = "macos")))]
+pub(crate) fn name_matches(name: &[u8], wanted: &[u8]) -> bool {
+    is_prefix(name, wanted, b'@')
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn name_matches(mut name: &[u8], wanted: &[u8]) -> bool {
+    if is_prefix(name, wanted, b'$') {
+        return true;
+    }
+
+    if let Some(n) = name.strip_prefix(b"@") {
+        if is_prefix(n, wanted, b'$') {
+            return true;
+        }
+        name = n;
+    }
+
+    matches!(name.strip_prefix(b"_"), Some(n) if is_prefix(n, wanted, b'$'))
+}
+
+#[cfg(windows)]
+pub(crate) fn name_matches(name: &[u8], wanted: &[u8]) -> bool {
+    let by_ordinal = !wanted.starts_with(b"?") && wanted.windows(2).any(|w| w == b":@");
+    if by_ordinal {
+        return name.eq_ignore_ascii_case(wanted);
+    }
+
+    if cfg!(target_pointer_width = "64") {
+        return name == wanted;
+    }
+
+    // Function names may be decorated in Windows 32-bit applications.
+    if is_prefix(name, wanted, b'@') {
+        return true;
+    }
+
+    match name.first() {
+        Some(b'_') | Some(b'@') => is_prefix(&name[1..], wanted, b'@'),
+        _ => false,
+    }
+}
+
+/// Check if `name` is `wanted`, optionally followed by `suffix_sep` and any
+/// other string.
+fn is_prefix(name: &[u8], wanted: &[u8], suffix_sep: u8) -> bool {
+    match name.strip_prefix(wanted) {
+        Some(rest) => rest.is_empty() || rest[0] == suffix_sep,
+        None => false,
+    }
+}
@@ -1,12 +1,62 @@
-use std::ffi::{c_char, c_double, c_int};
-use std::mem::MaybeUninit;
-use std::sync::Mutex;
+use std::ffi::{c_char, c_double, c_int, c_long, c_longlong, c_void};
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, RwLock};
+#[cfg(unix)]
+use std::thread;
 
 use crate::ffi::*;
-use crate::ObjectFile;
+use crate::{ObjectFile, Replacement, RestoreError, RestorePolicy};
 
 lazy_static::lazy_static! {
     static ref MUTEX: Mutex<()> = Mutex::new(());
+
+    /// Scoped hooks don't need `MUTEX`, but they take this lock for
+    /// reading. Tests that modify or inspect every hook take it for
+    /// writing.
+    static ref REGISTRY: RwLock<()> = RwLock::new(());
+}
+
+/// Calls to the hooked functions.
+///
+/// In release builds, LLVM replaces some functions with builtins (like
+/// `labs` with `abs`), and it can hoist the load of the GOT entry out of a
+/// loop. The functions are called through an opaque pointer, so every call
+/// reads the entry again.
+mod calls {
+    use std::ffi::{c_char, c_double, c_int, c_long, c_longlong};
+    use std::hint::black_box;
+
+    mod sys {
+        use std::ffi::{c_double, c_int, c_longlong};
+
+        extern "C" {
+            pub fn ldexp(x: c_double, exp: c_int) -> c_double;
+            pub fn llabs(x: c_longlong) -> c_longlong;
+        }
+    }
+
+    macro_rules! calls {
+        ($($name:ident: fn($($arg:ident: $ty:ty),*) -> $ret:ty = $path:path;)*) => {
+            $(
+                #[inline(never)]
+                pub unsafe fn $name($($arg: $ty),*) -> $ret {
+                    let function: unsafe extern "C" fn($($ty),*) -> $ret = $path;
+                    black_box(function)($($arg),*)
+                }
+            )*
+        };
+    }
+
+    calls! {
+        atoi: fn(nptr: *const c_char) -> c_int = libc::atoi;
+        atol: fn(nptr: *const c_char) -> c_long = libc::atol;
+        atoll: fn(nptr: *const c_char) -> c_longlong = libc::atoll;
+        labs: fn(x: c_long) -> c_long = libc::labs;
+        llabs: fn(x: c_longlong) -> c_longlong = sys::llabs;
+        ldexp: fn(x: c_double, exp: c_int) -> c_double = sys::ldexp;
+        geteuid: fn() -> libc::uid_t = libc::geteuid;
+    }
 }
 
 #[test]
@@ -15,7 +65,7 @@ fn replace_atof() {
         42.0
     }
 
-    let lock = MUTEX.lock().unwrap();
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
 
     let param = b"100\0".as_ptr().cast();
 
@@ -36,7 +86,11 @@ fn replace_atof() {
 
 #[test]
 fn use_c_api() {
-    let lock = MUTEX.lock().unwrap();
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Other tests may call the library, or write entries, at the same time.
+    let protection_lock = crate::slot::lock_protection();
+    let ffi_lock = crate::ffi::exts::lock();
 
     let object = unsafe {
         let mut object = MaybeUninit::uninit();
@@ -69,7 +123,7 @@ fn use_c_api() {
 
     let param = b"123\0".as_ptr().cast();
 
-    assert_eq!(unsafe { libc::atoi(param) }, 42);
+    assert_eq!(unsafe { calls::atoi(param) }, 42);
     assert_eq!((original_func)(param), 123);
 
     // Restore original atoi function.
@@ -85,10 +139,12 @@ fn use_c_api() {
         assert_eq!(ret, 0);
     };
 
-    assert_eq!(unsafe { libc::atoi(param) }, 123);
+    assert_eq!(unsafe { calls::atoi(param) }, 123);
 
     unsafe { plthook_close(object) };
 
+    drop(ffi_lock);
+    drop(protection_lock);
     drop(lock);
 }
 
@@ -104,3 +160,1813 @@ fn open_shared_object() {
     let object = ObjectFile::open_file(soname).unwrap();
     assert!(object.symbols().next().is_some());
 }
+
+#[test]
+fn restore_conflict() {
+    extern "C" fn our_atol(_: *const c_char) -> c_long {
+        1
+    }
+
+    extern "C" fn foreign_atol(_: *const c_char) -> c_long {
+        2
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let param = b"100\0".as_ptr().cast();
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let mut replacement = unsafe { object.replace("atol", our_atol as *const _).unwrap() };
+
+    // Write the entry without the chain, as another library would do.
+    let ret = unsafe {
+        let _protection = crate::slot::lock_protection();
+        let _guard = crate::ffi::exts::lock();
+        plthook_replace(
+            object.0.c_object,
+            b"atol\0".as_ptr().cast(),
+            foreign_atol as *const _,
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(ret, 0);
+
+    assert_eq!(unsafe { calls::atol(param) }, 2);
+
+    match replacement.restore() {
+        Err(RestoreError::Conflict { current }) => assert_eq!(current, foreign_atol as *const _),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // The entry is not modified with the default policy (`Skip`).
+    let libc_atol = replacement.original_address();
+    drop(replacement);
+    assert_eq!(unsafe { calls::atol(param) }, 2);
+
+    unsafe { object.replace("atol", libc_atol).unwrap().discard() };
+    assert_eq!(unsafe { calls::atol(param) }, 100);
+
+    drop(lock);
+}
+
+#[test]
+fn chain_out_of_order() {
+    extern "C" fn first_atoll(nptr: *const c_char) -> c_longlong {
+        let next = ATOLL_NEXT[0].load(Ordering::SeqCst);
+        let next: extern "C" fn(*const c_char) -> c_longlong = unsafe { mem::transmute(next) };
+        next(nptr) + 1
+    }
+
+    extern "C" fn second_atoll(nptr: *const c_char) -> c_longlong {
+        let next = ATOLL_NEXT[1].load(Ordering::SeqCst);
+        let next: extern "C" fn(*const c_char) -> c_longlong = unsafe { mem::transmute(next) };
+        next(nptr) + 10
+    }
+
+    static ATOLL_NEXT: [AtomicPtr<c_void>; 2] = [
+        AtomicPtr::new(std::ptr::null_mut()),
+        AtomicPtr::new(std::ptr::null_mut()),
+    ];
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let param = b"100\0".as_ptr().cast();
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let first = unsafe { object.replace("atoll", first_atoll as *const _).unwrap() };
+    let second = unsafe { object.replace("atoll", second_atoll as *const _).unwrap() };
+
+    let update_links = |first: Option<&Replacement>, second: Option<&Replacement>| {
+        for (link, r) in ATOLL_NEXT.iter().zip([first, second]) {
+            if let Some(r) = r {
+                link.store(r.original_address() as *mut _, Ordering::SeqCst);
+            }
+        }
+    };
+
+    update_links(Some(&first), Some(&second));
+    assert_eq!(unsafe { calls::atoll(param) }, 111);
+
+    // Remove the first layer, which is now in the middle of the chain.
+    drop(first);
+    update_links(None, Some(&second));
+    assert_eq!(unsafe { calls::atoll(param) }, 110);
+
+    drop(second);
+    assert_eq!(unsafe { calls::atoll(param) }, 100);
+
+    drop(lock);
+}
+
+#[test]
+fn registry() {
+    extern "C" fn other_labs(_: c_long) -> c_long {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+    let registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let mut replacement = unsafe { object.replace("labs", other_labs as *const _).unwrap() };
+    replacement.set_owner("tests::registry");
+    replacement.discard();
+
+    assert_eq!(unsafe { calls::labs(-5) }, 42);
+
+    let hooks = crate::active_hooks();
+    let hook = hooks
+        .iter()
+        .find(|h| h.owner.as_deref() == Some("tests::registry"))
+        .unwrap();
+
+    assert_eq!(hook.symbol.to_str(), Ok("labs"));
+    assert_eq!(hook.hook, other_labs as *const _);
+    assert_eq!(hook.original, replacement.original_address());
+    assert_eq!(hook.object_path, None);
+
+    crate::restore_all().unwrap();
+
+    assert_eq!(unsafe { calls::labs(-5) }, 5);
+    assert!(crate::active_hooks().is_empty());
+
+    drop(registry);
+    drop(lock);
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+fn registry_keeps_object_loaded() {
+    const SONAME: &[u8] = b"libresolv.so.2\0";
+
+    let is_loaded = || unsafe {
+        let handle = libc::dlopen(SONAME.as_ptr().cast(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+        if !handle.is_null() {
+            libc::dlclose(handle);
+        }
+        !handle.is_null()
+    };
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let loaded_before = is_loaded();
+    let handle = unsafe { libc::dlopen(SONAME.as_ptr().cast(), libc::RTLD_NOW) };
+    assert!(!handle.is_null());
+
+    let object = ObjectFile::open_file("libresolv.so.2").unwrap();
+    let symbol = object
+        .symbols()
+        .find(|s| !s.func_address.is_null())
+        .unwrap();
+    let name = symbol.name.to_str().unwrap().split('@').next().unwrap();
+
+    // Write the same address, so the library is not affected.
+    let current = unsafe { *(symbol.func_address as *const *const c_void) };
+    let replacement = unsafe { object.replace(name, current) }.unwrap();
+    drop(object);
+
+    unsafe { libc::dlclose(handle) };
+    assert!(is_loaded());
+
+    drop(replacement);
+    if !loaded_before {
+        assert!(!is_loaded());
+    }
+
+    drop(lock);
+}
+
+#[cfg(unix)]
+#[test]
+fn send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<ObjectFile>();
+    assert_send_sync::<Replacement>();
+
+    extern "C" fn other_getegid() -> libc::gid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let replacement = thread::spawn(move || unsafe {
+        object
+            .replace("getegid", other_getegid as *const _)
+            .unwrap()
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(unsafe { libc::getegid() }, 42);
+
+    thread::spawn(move || drop(replacement)).join().unwrap();
+
+    assert_ne!(unsafe { libc::getegid() }, 42);
+
+    drop(lock);
+}
+
+#[test]
+fn symbol_name_matches() {
+    use crate::symbols::name_matches;
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        assert!(name_matches(b"atoi", b"atoi"));
+        assert!(name_matches(b"atoi@GLIBC_2.2.5", b"atoi"));
+        assert!(!name_matches(b"atoi64", b"atoi"));
+        assert!(!name_matches(b"_atoi", b"atoi"));
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        assert!(name_matches(b"atoi", b"atoi"));
+        assert!(name_matches(b"_atoi", b"atoi"));
+        assert!(name_matches(b"_atoi$UNIX2003", b"atoi"));
+        assert!(name_matches(b"@_atoi", b"atoi"));
+        assert!(!name_matches(b"_atoi64", b"atoi"));
+        assert!(!name_matches(b"__atoi", b"atoi"));
+    }
+
+    #[cfg(windows)]
+    {
+        assert!(name_matches(b"atoi", b"atoi"));
+        assert!(!name_matches(b"atoi64", b"atoi"));
+        assert!(name_matches(b"ws2_32.dll:@3", b"WS2_32.DLL:@3"));
+        assert!(!name_matches(b"ws2_32.dll:@3", b"ws2_32.dll:@4"));
+
+        if cfg!(target_pointer_width = "64") {
+            assert!(!name_matches(b"_atoi", b"atoi"));
+        } else {
+            assert!(name_matches(b"_atoi", b"atoi"));
+            assert!(name_matches(b"_Sleep@4", b"Sleep"));
+            assert!(name_matches(b"@Fast@8", b"Fast"));
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn replace_with_closure() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    type LdexpFn = extern "C" fn(c_double, c_int) -> c_double;
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let replacement = unsafe {
+        let calls = Arc::clone(&calls);
+        object
+            .replace_with_closure::<LdexpFn, _>("ldexp", move |original: LdexpFn, x, exp| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                original(x, exp) + 0.5
+            })
+            .unwrap()
+    };
+
+    assert_eq!(unsafe { calls::ldexp(3.0, 2) }, 12.5);
+    assert_eq!(unsafe { calls::ldexp(1.5, 1) }, 3.5);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // Address of the trampoline, like a thread that loaded it before the
+    // replacement is dropped.
+    let symbol = object
+        .symbols()
+        .find(|s| s.name.to_bytes() == b"ldexp")
+        .unwrap();
+    let trampoline: LdexpFn =
+        unsafe { std::mem::transmute(crate::slot::read(symbol.func_address as *const _)) };
+
+    drop(replacement);
+
+    // The closure is released when there are no calls running in it.
+    assert_eq!(Arc::strong_count(&calls), 1);
+    assert_eq!(unsafe { calls::ldexp(3.0, 2) }, 12.0);
+
+    // The trampoline is still mapped, and jumps to the original function.
+    assert_eq!(trampoline(3.0, 2), 12.0);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn closure_hook_in_signal_handler() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+
+    type LabsFn = extern "C" fn(c_long) -> c_long;
+    type LlabsFn = extern "C" fn(c_longlong) -> c_longlong;
+
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+    static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+    // Calls a second closure hook, which can interrupt the first one
+    // before its shim reads the context.
+    extern "C" fn handler(_: c_int) {
+        let expected = if crate::in_hook() { 7 } else { 9 };
+        if unsafe { calls::llabs(-7) } != expected {
+            FAILURES.fetch_add(1, Ordering::SeqCst);
+        }
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    // Closures with different types, so a wrong context is not ignored.
+    let offset = 1_u8;
+    let labs = unsafe {
+        object
+            .replace_with_closure::<LabsFn, _>("labs", move |next: LabsFn, x| {
+                next(x) + c_long::from(offset)
+            })
+            .unwrap()
+    };
+
+    let offset = Box::new(2_i64);
+    let llabs = unsafe {
+        object
+            .replace_with_closure::<LlabsFn, _>("llabs", move |next: LlabsFn, x| next(x) + *offset)
+            .unwrap()
+    };
+
+    let old_action = unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        let mut old_action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const c_void as usize;
+        action.sa_flags = libc::SA_RESTART;
+        assert_eq!(libc::sigaction(libc::SIGUSR1, &action, &mut old_action), 0);
+        old_action
+    };
+
+    let done = Arc::new(AtomicBool::new(false));
+    let target = unsafe { libc::pthread_self() };
+    let sender = {
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                unsafe { libc::pthread_kill(target, libc::SIGUSR1) };
+                thread::yield_now();
+            }
+        })
+    };
+
+    let mut iterations = 0;
+    while iterations < 200_000 || SIGNALS.load(Ordering::SeqCst) < 100 {
+        assert_eq!(unsafe { calls::labs(-5) }, 6);
+        iterations += 1;
+    }
+
+    done.store(true, Ordering::SeqCst);
+    sender.join().unwrap();
+
+    unsafe { libc::sigaction(libc::SIGUSR1, &old_action, std::ptr::null_mut()) };
+
+    assert_eq!(FAILURES.load(Ordering::SeqCst), 0);
+
+    drop(llabs);
+    drop(labs);
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn closure_panic_aborts() {
+    type LabsFn = extern "C" fn(c_long) -> c_long;
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let replacement = unsafe {
+        object
+            .replace_with_closure::<LabsFn, _>("labs", |_: LabsFn, _| panic!("closure hook"))
+            .unwrap()
+    };
+
+    // The panic is triggered in a child process, so the abort doesn't
+    // stop the tests.
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+
+    if pid == 0 {
+        unsafe {
+            std::panic::set_hook(Box::new(|_| {}));
+            calls::labs(-1);
+            libc::_exit(0);
+        }
+    }
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFSIGNALED(status));
+    assert_eq!(libc::WTERMSIG(status), libc::SIGABRT);
+
+    drop(replacement);
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn replace_guarded() {
+    use std::sync::atomic::AtomicBool;
+
+    static IN_HOOK: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn guarded_ldexp(x: c_double, exp: c_int) -> c_double {
+        IN_HOOK.store(crate::in_hook(), Ordering::SeqCst);
+
+        // Nested call. It goes to the original function.
+        unsafe { calls::ldexp(x, exp) + 0.25 }
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let replacement = unsafe {
+        object
+            .replace_guarded("ldexp", guarded_ldexp as *const _)
+            .unwrap()
+    };
+
+    assert_eq!(unsafe { calls::ldexp(3.0, 2) }, 12.25);
+    assert!(IN_HOOK.load(Ordering::SeqCst));
+
+    // The guard is released when the hook returns.
+    assert!(!crate::in_hook());
+
+    // Calls inside another hook go to the original function.
+    let nested = {
+        let _guard = crate::HookGuard::enter().unwrap();
+        unsafe { calls::ldexp(3.0, 2) }
+    };
+    assert_eq!(nested, 12.0);
+
+    drop(replacement);
+    assert_eq!(unsafe { calls::ldexp(3.0, 2) }, 12.0);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_everywhere() {
+    use crate::ObjectFilter;
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let exe = std::env::current_exe().unwrap();
+
+    // The main program contains the hook, so it is excluded by default.
+    let replacements = unsafe {
+        crate::replace_everywhere("getsid", other_getsid as *const _, ObjectFilter::default())
+            .unwrap()
+    };
+
+    assert!(replacements.objects().all(|path| path != exe));
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+    drop(replacements);
+
+    let filter = ObjectFilter::default().exclude_hook_object(false);
+    let mut replacements =
+        unsafe { crate::replace_everywhere("getsid", other_getsid as *const _, filter).unwrap() };
+
+    assert!(replacements.objects().any(|path| path == exe));
+    assert!(replacements
+        .objects()
+        .all(|path| !path.to_string_lossy().contains("ld-linux")));
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+
+    replacements.restore().unwrap();
+    assert!(replacements.is_empty());
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn nested_closure_hooks() {
+    type LabsFn = extern "C" fn(c_long) -> c_long;
+    type GetsidFn = extern "C" fn(libc::pid_t) -> libc::pid_t;
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let outer = unsafe {
+        object
+            .replace_with_closure::<GetsidFn, _>("getsid", |_: GetsidFn, pid| {
+                assert!(crate::in_hook());
+                calls::labs(pid as c_long) as libc::pid_t
+            })
+            .unwrap()
+    };
+
+    let inner = unsafe {
+        object
+            .replace_with_closure::<LabsFn, _>("labs", |_: LabsFn, _| 42)
+            .unwrap()
+    };
+
+    // The nested call to `labs` skips the closure.
+    assert_eq!(unsafe { libc::getsid(-5) }, 5);
+    assert_eq!(unsafe { calls::labs(-5) }, 42);
+    assert!(!crate::in_hook());
+
+    drop(inner);
+    drop(outer);
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn scoped_hook() {
+    use crate::ScopedHook;
+
+    extern "C" fn other_geteuid() -> libc::uid_t {
+        42
+    }
+
+    extern "C" fn another_geteuid() -> libc::uid_t {
+        43
+    }
+
+    let lock = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let euid = unsafe { calls::geteuid() };
+
+    let hook = unsafe {
+        ScopedHook::for_current_thread(&object, "geteuid", other_geteuid as *const _).unwrap()
+    };
+
+    let other_thread = thread::spawn(move || {
+        let hook = unsafe {
+            ScopedHook::for_current_thread(&object, "geteuid", another_geteuid as *const _).unwrap()
+        };
+
+        let value = unsafe { calls::geteuid() };
+        drop(hook);
+        value
+    });
+
+    assert_eq!(other_thread.join().unwrap(), 43);
+    assert_eq!(unsafe { calls::geteuid() }, 42);
+
+    let original: extern "C" fn() -> libc::uid_t =
+        unsafe { mem::transmute(hook.original_address()) };
+    assert_eq!(original(), euid);
+
+    drop(hook);
+    assert_eq!(unsafe { calls::geteuid() }, euid);
+
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn scoped_hook_threads() {
+    use crate::ScopedHook;
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    thread_local! {
+        static EUID: Cell<libc::uid_t> = const { Cell::new(0) };
+    }
+
+    extern "C" fn thread_geteuid() -> libc::uid_t {
+        EUID.with(|e| e.get())
+    }
+
+    let lock = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+
+    let euid = unsafe { calls::geteuid() };
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Threads without hooks call the function while the hooks of the other
+    // threads are installed and restored.
+    let unhooked: Vec<_> = (0..4)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    assert_eq!(unsafe { calls::geteuid() }, euid);
+                }
+            })
+        })
+        .collect();
+
+    let object = Arc::new(ObjectFile::open_main_program().unwrap());
+    let hooked: Vec<_> = (1..=4)
+        .map(|n| {
+            let object = Arc::clone(&object);
+            thread::spawn(move || {
+                let value = euid.wrapping_add(n);
+                EUID.with(|e| e.set(value));
+
+                for _ in 0..100 {
+                    let hook = unsafe {
+                        ScopedHook::for_current_thread(
+                            &object,
+                            "geteuid",
+                            thread_geteuid as *const _,
+                        )
+                        .unwrap()
+                    };
+
+                    assert_eq!(unsafe { calls::geteuid() }, value);
+                    drop(hook);
+                    assert_eq!(unsafe { calls::geteuid() }, euid);
+                }
+            })
+        })
+        .collect();
+
+    for thread in hooked {
+        thread.join().unwrap();
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    for thread in unhooked {
+        thread.join().unwrap();
+    }
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_data() {
+    extern "C" {
+        static environ: *const *const c_char;
+    }
+
+    // The address is read from the GOT entry on every call.
+    #[inline(never)]
+    fn current_environ() -> *const *const c_char {
+        unsafe { std::ptr::read_volatile(std::hint::black_box(std::ptr::addr_of!(environ))) }
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let env = [b"PLTHOOK=1\0".as_ptr() as *const c_char, std::ptr::null()];
+    let mut other_environ = env.as_ptr();
+    let original = current_environ();
+
+    let replacement = unsafe {
+        object
+            .replace_data::<*const *const c_char>("environ", &mut other_environ)
+            .unwrap()
+    };
+
+    assert_eq!(current_environ(), env.as_ptr());
+    assert_eq!(unsafe { *replacement.original() }, original);
+
+    drop(replacement);
+    assert_eq!(current_environ(), original);
+
+    // Functions are rejected.
+    let error = unsafe { object.replace_data::<c_int>("getsid", std::ptr::null_mut()) };
+    assert!(matches!(
+        error.err().map(|e| e.kind()),
+        Some(crate::ErrorKind::InvalidArgument)
+    ));
+
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn suspend_resume() {
+    type GetpgidFn = extern "C" fn(libc::pid_t) -> libc::pid_t;
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let pgid = unsafe { libc::getpgid(0) };
+
+    let mut inner = unsafe {
+        object
+            .replace_with_closure::<GetpgidFn, _>("getpgid", |next: GetpgidFn, pid| next(pid) + 1)
+            .unwrap()
+    };
+
+    let mut outer = unsafe {
+        object
+            .replace_with_closure::<GetpgidFn, _>("getpgid", |next: GetpgidFn, pid| next(pid) + 10)
+            .unwrap()
+    };
+
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid + 11);
+
+    // Suspend the layer in the middle of the chain.
+    inner.suspend().unwrap();
+    assert!(!inner.is_active());
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid + 10);
+
+    // Suspend the first layer.
+    outer.suspend().unwrap();
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid);
+
+    inner.resume().unwrap();
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid + 1);
+
+    outer.resume().unwrap();
+    assert!(outer.is_active());
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid + 11);
+
+    // A suspended replacement can be dropped.
+    outer.suspend().unwrap();
+    drop(outer);
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid + 1);
+
+    drop(inner);
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn suspend_in_signal_handler() {
+    use std::sync::atomic::AtomicBool;
+
+    extern "C" fn other_getpgid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    static REPLACEMENT: AtomicPtr<Replacement> = AtomicPtr::new(std::ptr::null_mut());
+    static SUSPEND: AtomicBool = AtomicBool::new(true);
+
+    extern "C" fn handler(_: c_int) {
+        let replacement = unsafe { &mut *REPLACEMENT.load(Ordering::SeqCst) };
+        if SUSPEND.load(Ordering::SeqCst) {
+            replacement.suspend().unwrap();
+        } else {
+            replacement.resume().unwrap();
+        }
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let pgid = unsafe { libc::getpgid(0) };
+
+    let replacement = unsafe {
+        object
+            .replace("getpgid", other_getpgid as *const _)
+            .unwrap()
+    };
+    REPLACEMENT.store(Box::into_raw(Box::new(replacement)), Ordering::SeqCst);
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as usize;
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+
+        SUSPEND.store(true, Ordering::SeqCst);
+        libc::raise(libc::SIGUSR1);
+        assert_eq!(libc::getpgid(0), pgid);
+
+        SUSPEND.store(false, Ordering::SeqCst);
+        libc::raise(libc::SIGUSR1);
+        assert_eq!(libc::getpgid(0), 42);
+
+        // With the write lock held, the request is applied the next time
+        // the registry is locked.
+        SUSPEND.store(true, Ordering::SeqCst);
+        let protection_lock = crate::slot::lock_protection();
+        libc::raise(libc::SIGUSR1);
+        assert_eq!(libc::getpgid(0), 42);
+        drop(protection_lock);
+
+        let replacement = Box::from_raw(REPLACEMENT.swap(std::ptr::null_mut(), Ordering::SeqCst));
+        assert!(!replacement.is_active());
+        assert_eq!(libc::getpgid(0), pgid);
+
+        libc::signal(libc::SIGUSR1, libc::SIG_DFL);
+        drop(replacement);
+    }
+
+    assert_eq!(unsafe { libc::getpgid(0) }, pgid);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn prepared_hook_in_signal_handler() {
+    use crate::PreparedHook;
+
+    extern "C" fn other_getpgid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    static HOOK: AtomicPtr<PreparedHook> = AtomicPtr::new(std::ptr::null_mut());
+
+    extern "C" fn handler(_: c_int) {
+        let hook = unsafe { &*HOOK.load(Ordering::SeqCst) };
+        if hook.is_applied() {
+            hook.revert().unwrap();
+        } else {
+            hook.apply().unwrap();
+        }
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let pgid = unsafe { libc::getpgid(0) };
+
+    let hook = unsafe {
+        object
+            .prepare("getpgid", other_getpgid as *const _)
+            .unwrap()
+    };
+    HOOK.store(Box::into_raw(Box::new(hook)), Ordering::SeqCst);
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as usize;
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+
+        libc::raise(libc::SIGUSR1);
+        assert_eq!(libc::getpgid(0), 42);
+
+        libc::raise(libc::SIGUSR1);
+        assert_eq!(libc::getpgid(0), pgid);
+
+        libc::signal(libc::SIGUSR1, libc::SIG_DFL);
+        drop(Box::from_raw(
+            HOOK.swap(std::ptr::null_mut(), Ordering::SeqCst),
+        ));
+    }
+
+    drop(lock);
+}
+
+/// Check the permissions of the mapping containing `address`.
+#[cfg(target_os = "linux")]
+fn is_writable(address: usize) -> bool {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().any(|line| {
+        let mut fields = line.split(' ');
+        let (start, end) = fields.next().unwrap().split_once('-').unwrap();
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        (start..end).contains(&address) && fields.next().unwrap().as_bytes()[1] == b'w'
+    })
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn protection_policy() {
+    use crate::{ProtectionPolicy, ReplaceOptions};
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    // The protection of the page is shared with the entries of the scoped
+    // hooks.
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+    let registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let symbol = object
+        .symbols()
+        .find(|s| s.name.to_str() == Ok("getsid"))
+        .unwrap();
+
+    let slot = symbol.func_address as usize;
+
+    // The test program is linked with `-z relro`.
+    assert!(!is_writable(slot));
+
+    let options = ReplaceOptions::new().protection(ProtectionPolicy::RefuseReadOnly);
+    let error =
+        unsafe { object.replace_with_options("getsid", other_getsid as *const _, &options) };
+    assert_eq!(
+        error.err().map(|e| e.kind()),
+        Some(crate::ErrorKind::PermissionDenied)
+    );
+    assert!(crate::active_hooks()
+        .iter()
+        .all(|h| h.slot as usize != slot));
+
+    let options = ReplaceOptions::new().protection(ProtectionPolicy::KeepWritable);
+    let replacement =
+        unsafe { object.replace_with_options("getsid", other_getsid as *const _, &options) }
+            .unwrap();
+
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+    assert!(is_writable(slot));
+
+    drop(replacement);
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    // Restore RELRO for the other tests.
+    let page_size = crate::slot::page_size();
+    unsafe {
+        let page = (slot & !(page_size - 1)) as *mut c_void;
+        libc::mprotect(page, page_size, symbol.protection);
+    }
+
+    // The entry is written with the strictest policy of its chain, so a
+    // new replacement with `KeepWritable` doesn't weaken the policy of the
+    // previous one.
+    let first = unsafe { object.replace("getsid", other_getsid as *const _) }.unwrap();
+    let second =
+        unsafe { object.replace_with_options("getsid", other_getsid as *const _, &options) }
+            .unwrap();
+    assert!(!is_writable(slot));
+
+    drop(second);
+    drop(first);
+    assert!(!is_writable(slot));
+
+    drop(registry);
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_many() {
+    use crate::ReplaceOptions;
+
+    extern "C" fn other_getegid() -> libc::gid_t {
+        4242
+    }
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let gid = unsafe { libc::getegid() };
+
+    // No function is replaced if one of them is missing.
+    let hooks = [
+        ("getegid", other_getegid as *const c_void),
+        ("no_such_function", other_getsid as *const c_void),
+    ];
+    assert!(unsafe { object.replace_many(&hooks, &ReplaceOptions::new()) }.is_err());
+    assert_eq!(unsafe { libc::getegid() }, gid);
+
+    let hooks = [
+        ("getegid", other_getegid as *const c_void),
+        ("getsid", other_getsid as *const c_void),
+    ];
+    let mut replacements = unsafe { object.replace_many(&hooks, &ReplaceOptions::new()) }.unwrap();
+
+    assert_eq!(replacements.len(), 2);
+    assert_eq!(unsafe { libc::getegid() }, 4242);
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+
+    // The protection of the pages is restored.
+    for symbol in object.symbols() {
+        if symbol.name.to_str() == Ok("getegid") || symbol.name.to_str() == Ok("getsid") {
+            assert!(!is_writable(symbol.func_address as usize));
+        }
+    }
+
+    replacements[1].restore().unwrap();
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+    assert_eq!(unsafe { libc::getegid() }, 4242);
+
+    drop(replacements);
+    assert_eq!(unsafe { libc::getegid() }, gid);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn coverage_check() {
+    use crate::CoverageWarning;
+
+    let program = ObjectFile::open_main_program().unwrap();
+    let coverage = program.coverage_check("getsid").unwrap();
+    assert!(!coverage.defined);
+    assert!(coverage.plt_entry || coverage.got_entry);
+    assert!(!coverage.warnings().contains(&CoverageWarning::NotImported));
+
+    let coverage = program.coverage_check("no_such_function").unwrap();
+    assert_eq!(coverage.warnings(), [CoverageWarning::NotImported]);
+
+    // libc defines `malloc`, and calls it without the PLT.
+    let libc = ObjectFile::open_file("libc.so.6").unwrap();
+    let coverage = libc.coverage_check("malloc").unwrap();
+    assert!(coverage.defined);
+    assert!(coverage
+        .warnings()
+        .contains(&CoverageWarning::DefinedLocally));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn hook_plan() {
+    use crate::{HookSpec, ProtectionPolicy, ReplaceOptions};
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let sid = unsafe { libc::getsid(0) };
+
+    // Read-only pages are reported as failures with `RefuseReadOnly`.
+    let spec = HookSpec::new()
+        .hook(&object, "getsid", other_getsid as *const _)
+        .options(ReplaceOptions::new().protection(ProtectionPolicy::RefuseReadOnly));
+    let plan = crate::plan(&spec);
+    assert!(plan.writes().is_empty());
+    assert_eq!(plan.failures()[0].symbol, "getsid");
+    assert_eq!(
+        plan.failures()[0].error.kind(),
+        crate::ErrorKind::PermissionDenied
+    );
+
+    let spec = HookSpec::new().hook(&object, "getsid", other_getsid as *const _);
+    let plan = crate::plan(&spec);
+    let write = &plan.writes()[0];
+    assert_eq!(write.current, unsafe { crate::slot::read(write.slot) });
+    assert!(write.writes_entry);
+
+    // Addresses are printed in hexadecimal.
+    let debug = format!("{plan:?}");
+    assert!(debug.starts_with("HookPlan { writes: [PlannedWrite {"));
+    assert!(debug.contains(r#"symbol: "getsid""#));
+    assert!(debug.contains(&format!("slot: {:p},", write.slot)));
+    assert!(debug.contains(&format!("hook: {:p},", other_getsid as *const c_void)));
+    assert!(debug.contains("writes_entry: true"));
+    assert_eq!(unsafe { libc::getsid(0) }, sid);
+
+    // A plan can't be applied if the entry was modified.
+    let stale = crate::plan(&spec);
+    let replacements = unsafe { plan.apply() }.unwrap();
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+    match unsafe { stale.apply() } {
+        Ok(_) => panic!("the plan should be stale"),
+        Err(e) => assert_eq!(e.kind(), crate::ErrorKind::StalePlan),
+    }
+
+    drop(replacements);
+    assert_eq!(unsafe { libc::getsid(0) }, sid);
+
+    // Missing functions have the same suggestions as `replace`.
+    let spec = HookSpec::new()
+        .hook(&object, "getsidd", other_getsid as *const _)
+        .hook(&object, "get\0sid", other_getsid as *const _);
+    let plan = crate::plan(&spec);
+    assert!(plan.writes().is_empty());
+    assert_eq!(plan.failures()[0].error.suggestions()[0], "getsid");
+    assert_eq!(plan.failures()[1].symbol, "get\0sid");
+
+    let debug = format!("{:?}", plan.failures()[0]);
+    assert!(debug.starts_with(r#"PlanFailure { object_path: None, symbol: "getsidd", error: "#));
+    assert!(debug.contains("FunctionNotFound"));
+
+    drop(lock);
+}
+
+#[cfg(unix)]
+#[test]
+fn concurrent_errors() {
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let threads: Vec<_> = (0..16)
+        .map(|n| {
+            thread::spawn(move || {
+                for i in 0..50 {
+                    // Every error has to contain the name of its own file.
+                    let missing = format!("/nonexistent/libmissing-{n}-{i}.so");
+                    let error = match ObjectFile::open_file(&missing) {
+                        Ok(_) => panic!("{} should not exist", missing),
+                        Err(e) => e,
+                    };
+
+                    if cfg!(target_os = "linux") {
+                        assert!(error.message().contains(&missing), "{}", error);
+                    }
+
+                    let object = ObjectFile::open_main_program().unwrap();
+
+                    let missing = format!("no_such_function_{n}_{i}");
+                    match unsafe { object.replace(&missing, other_getsid as *const _) } {
+                        Ok(_) => panic!("{} should not exist", missing),
+                        Err(e) => assert!(e.message().contains(&missing), "{}", e),
+                    }
+
+                    let replacement =
+                        unsafe { object.replace("getsid", other_getsid as *const _) }.unwrap();
+                    drop(replacement);
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn error_context() {
+    use crate::{ErrorKind, ProtectionPolicy, ReplaceOptions};
+    use std::error::Error as _;
+    use std::path::Path;
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let error = match ObjectFile::open_file("/nonexistent/libmissing.so") {
+        Ok(_) => panic!("the file should not exist"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), ErrorKind::FileNotFound);
+    assert_eq!(
+        error.object_path(),
+        Some(Path::new("/nonexistent/libmissing.so"))
+    );
+    assert!(error.source().is_some());
+    assert_eq!(error.os_error(), Some(libc::ENOENT));
+
+    let libc = ObjectFile::open_file("libc.so.6").unwrap();
+    let error = match unsafe { libc.replace("no_such_function", other_getsid as *const _) } {
+        Ok(_) => panic!("the function should not exist"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), ErrorKind::FunctionNotFound);
+    assert_eq!(error.symbol(), Some("no_such_function"));
+    assert_eq!(error.object_path(), Some(Path::new("libc.so.6")));
+
+    let error = match unsafe { libc.replace("get\0sid", other_getsid as *const _) } {
+        Ok(_) => panic!("the name should be invalid"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let slot = object
+        .symbols()
+        .find(|s| s.name.to_str() == Ok("getsid"))
+        .unwrap()
+        .func_address;
+
+    let options = ReplaceOptions::new().protection(ProtectionPolicy::RefuseReadOnly);
+    let error = match unsafe {
+        object.replace_with_options("getsid", other_getsid as *const _, &options)
+    } {
+        Ok(_) => panic!("the entry should be read-only"),
+        Err(e) => e,
+    };
+    assert_eq!(error.symbol(), Some("getsid"));
+    assert_eq!(error.address(), Some(slot.cast()));
+    assert_eq!(error.os_error(), None);
+
+    drop(lock);
+
+    // Errors from prepared hooks have a fixed message. The page can't be
+    // made writable, because it is a shared mapping of a read-only file.
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+    let page_size = crate::slot::page_size();
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(page, libc::MAP_FAILED);
+
+    let prepared = crate::PreparedHook::new(page.cast(), libc::PROT_READ, other_getsid as *const _);
+    let error = match prepared.apply() {
+        Ok(_) => panic!("the page should not be writable"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), ErrorKind::InternalError);
+    assert_eq!(
+        error.to_string(),
+        "plthook error: InternalError: Could not change the process memory permission"
+    );
+
+    unsafe { libc::munmap(page, page_size) };
+}
+
+#[cfg(unix)]
+#[test]
+fn not_found_suggestions() {
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let error = match unsafe { object.replace("getsd", other_getsid as *const _) } {
+        Ok(_) => panic!("the function should not exist"),
+        Err(e) => e,
+    };
+    assert!(error.suggestions().iter().any(|s| s == "getsid"));
+    assert!(!error.is_defined_locally());
+
+    // libc defines `getsid`, but does not import it.
+    #[cfg(target_os = "linux")]
+    {
+        let libc = ObjectFile::open_file("libc.so.6").unwrap();
+        let error = match unsafe { libc.replace("getsid", other_getsid as *const _) } {
+            Ok(_) => panic!("the function should not be imported"),
+            Err(e) => e,
+        };
+        assert!(error.is_defined_locally());
+    }
+
+    assert_eq!(
+        crate::suggest::near_misses(&object, "no_such_function"),
+        Vec::<String>::new()
+    );
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+fn replace_family() {
+    extern "C" fn other_lseek(_: c_int, _: libc::off64_t, _: c_int) -> libc::off64_t {
+        -42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    // The standard library calls `lseek64` to seek files.
+    let mut family = unsafe { object.replace_family("lseek", other_lseek as *const _) }.unwrap();
+    assert!(family.names().any(|n| n == "lseek64"));
+    assert!(family.missed().is_empty());
+    assert_eq!(unsafe { libc::lseek64(0, 0, libc::SEEK_CUR) }, -42);
+
+    family.restore().unwrap();
+    assert_ne!(unsafe { libc::lseek64(0, 0, libc::SEEK_CUR) }, -42);
+
+    let error = match unsafe { object.replace_family("no_such_function", other_lseek as *const _) }
+    {
+        Ok(_) => panic!("the function should not exist"),
+        Err(e) => e,
+    };
+    assert_eq!(error.symbol(), Some("no_such_function"));
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_target() {
+    use crate::ObjectFilter;
+
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    extern "C" fn another_getsid(_: libc::pid_t) -> libc::pid_t {
+        43
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let original = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"getsid\0".as_ptr().cast()) };
+    assert!(!original.is_null());
+
+    let object = ObjectFile::open_main_program().unwrap();
+
+    let replacements =
+        unsafe { object.replace_target(original, other_getsid as *const _) }.unwrap();
+    assert_eq!(replacements.len(), 1);
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+
+    // Entries in a chain are compared with their original address.
+    let filter = ObjectFilter::default().exclude_hook_object(false);
+    let set = unsafe {
+        crate::replace_target_everywhere(original, another_getsid as *const _, filter).unwrap()
+    };
+    assert!(!set.is_empty());
+    assert_eq!(unsafe { libc::getsid(0) }, 43);
+
+    drop(set);
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+
+    drop(replacements);
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    let error = match unsafe { object.replace_target(other_getsid as *const _, original) } {
+        Ok(_) => panic!("no entry should point to the hook"),
+        Err(e) => e,
+    };
+    assert_eq!(error.address(), Some(other_getsid as *const c_void));
+
+    drop(lock);
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn hook_all() {
+    use std::sync::atomic::AtomicUsize;
+
+    static GETSID_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static LDEXP_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+    let registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+
+    let sid = unsafe { libc::getsid(0) };
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let mut hook = unsafe {
+        object.hook_all(|slot| match slot.name() {
+            "getsid" => {
+                GETSID_CALLS.fetch_add(1, Ordering::SeqCst);
+            }
+            "ldexp" => {
+                LDEXP_CALLS.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => (),
+        })
+    }
+    .unwrap();
+
+    let getsid_slot = hook.slots().find(|s| s.name() == "getsid").unwrap();
+    let symbol = object.symbols().nth(getsid_slot.index()).unwrap();
+    assert_eq!(symbol.func_address as *const _, getsid_slot.slot());
+
+    // Data symbols are not hooked.
+    assert!(hook.slots().all(|s| s.name() != "environ"));
+
+    // Arguments in integer and floating-point registers are not modified.
+    assert_eq!(unsafe { libc::getsid(0) }, sid);
+    assert_eq!(unsafe { calls::ldexp(1.5, 2) }, 6.0);
+    assert_eq!(GETSID_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(LDEXP_CALLS.load(Ordering::SeqCst), 1);
+
+    hook.restore().unwrap();
+    assert_eq!(unsafe { libc::getsid(0) }, sid);
+    assert_eq!(GETSID_CALLS.load(Ordering::SeqCst), 1);
+    assert!(crate::active_hooks().is_empty());
+
+    drop(hook);
+    drop(registry);
+    drop(lock);
+}
+
+// Functions used by `trampoline_extended_state`.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".globl plthook_rs_test_store_ymm0",
+    ".hidden plthook_rs_test_store_ymm0",
+    "plthook_rs_test_store_ymm0:",
+    "vmovdqu ymmword ptr [rdi], ymm0",
+    "ret",
+    ".globl plthook_rs_test_return_st0",
+    ".hidden plthook_rs_test_return_st0",
+    "plthook_rs_test_return_st0:",
+    "fld1",
+    "ret",
+    ".popsection",
+);
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn trampoline_extended_state() {
+    use crate::trampoline::{Callback, Trampolines};
+    use std::arch::asm;
+
+    extern "C" {
+        fn plthook_rs_test_store_ymm0();
+        fn plthook_rs_test_return_st0();
+    }
+
+    #[target_feature(enable = "avx")]
+    unsafe extern "C" fn clobber_ymm0(context: *const c_void) -> *const c_void {
+        asm!("vxorps ymm0, ymm0, ymm0", out("ymm0") _);
+        context
+    }
+
+    // Call `function` with all bits of `ymm0` set, and a pointer to
+    // `ymm0` in `rdi`.
+    #[target_feature(enable = "avx")]
+    unsafe fn call_with_ymm0(function: *const c_void, ymm0: &mut [u64; 4]) {
+        asm!(
+            "vcmpps ymm0, ymm0, ymm0, 15",
+            "call {function}",
+            function = in(reg) function,
+            in("rdi") ymm0.as_mut_ptr(),
+            clobber_abi("C"),
+        );
+    }
+
+    // Call `function`, which returns a `long double`, and convert its
+    // value to `f64`.
+    unsafe fn call_long_double(function: *const c_void) -> f64 {
+        let mut value = 0.0;
+        asm!(
+            "call {function}",
+            "fstp qword ptr [r12]",
+            function = in(reg) function,
+            in("r12") &mut value,
+            clobber_abi("C"),
+        );
+        value
+    }
+
+    // The upper half of `ymm0` is restored after the callback.
+    if std::is_x86_feature_detected!("avx") {
+        let target = plthook_rs_test_store_ymm0 as *const c_void;
+        let link = crate::chain::new_link();
+        let trampolines =
+            Trampolines::new(&[(target, clobber_ymm0 as Callback, &link)], Box::new(())).unwrap();
+
+        let mut ymm0 = [0; 4];
+        unsafe { call_with_ymm0(trampolines.address(0), &mut ymm0) };
+        assert_eq!(ymm0, [u64::MAX; 4]);
+    }
+
+    // A `long double` in `st0` is kept when the guard is left.
+    let target = plthook_rs_test_return_st0 as *const c_void;
+    let link = crate::chain::new_link();
+    let trampolines = Trampolines::guarded(target, &link).unwrap();
+
+    assert_eq!(unsafe { call_long_double(trampolines.address(0)) }, 1.0);
+    assert!(!crate::in_hook());
+}
+
+#[cfg(unix)]
+#[test]
+fn replace_checked() {
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    static NOT_CODE: u64 = 0;
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let slot = object
+        .symbols()
+        .find(|s| s.name.to_bytes() == b"getsid")
+        .unwrap()
+        .func_address;
+
+    let mut invalid = vec![std::ptr::null(), slot as *const c_void];
+    if cfg!(target_os = "linux") {
+        invalid.push(&NOT_CODE as *const u64 as *const c_void);
+    }
+
+    for address in invalid {
+        match unsafe { object.replace_checked("getsid", address) } {
+            Ok(_) => panic!("{:?} should be rejected", address),
+            Err(e) => {
+                assert_eq!(e.kind(), crate::ErrorKind::InvalidArgument);
+                assert_eq!(e.address(), Some(address));
+                assert_eq!(e.symbol(), Some("getsid"));
+            }
+        }
+    }
+
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    let replacement =
+        unsafe { object.replace_checked("getsid", other_getsid as *const _) }.unwrap();
+    assert_eq!(unsafe { libc::getsid(0) }, 42);
+
+    drop(replacement);
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn next_definition() {
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let object = ObjectFile::open_main_program().unwrap();
+    let replacement = unsafe { object.replace("getsid", other_getsid as *const _) }.unwrap();
+
+    let real = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"getsid\0".as_ptr().cast()) };
+    let found = crate::next_definition("getsid", None, &object).unwrap();
+    assert_eq!(found, real as *const c_void);
+    assert_eq!(found, replacement.original_address());
+
+    let error = match crate::next_definition("getsid", Some("NO_SUCH_VERSION"), &object) {
+        Ok(_) => panic!("the version should not exist"),
+        Err(e) => e,
+    };
+    assert_eq!(error.symbol(), Some("getsid"));
+
+    drop(replacement);
+    drop(lock);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_export() {
+    extern "C" fn other_getsid(_: libc::pid_t) -> libc::pid_t {
+        42
+    }
+
+    extern "C" fn another_getsid(_: libc::pid_t) -> libc::pid_t {
+        43
+    }
+
+    let dlsym_getsid = || unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"getsid\0".as_ptr().cast()) };
+
+    let lock = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+
+    let original = dlsym_getsid();
+    let libc = ObjectFile::open_file("libc.so.6").unwrap();
+
+    let mut replacement =
+        unsafe { libc.replace_export("getsid", other_getsid as *const _) }.unwrap();
+    assert_eq!(replacement.original_address(), original as *const c_void);
+    assert_eq!(dlsym_getsid(), other_getsid as *mut c_void);
+
+    // Entries that were already resolved are not modified.
+    assert_ne!(unsafe { libc::getsid(0) }, 42);
+
+    replacement.restore().unwrap();
+    assert_eq!(dlsym_getsid(), original);
+    assert!(matches!(replacement.restore(), Err(RestoreError::Inactive)));
+
+    // Conflicts are reported by `restore`, and resolved by the policy when
+    // the replacement is dropped.
+    let mut first = unsafe { libc.replace_export("getsid", other_getsid as *const _) }.unwrap();
+    let mut second = unsafe { libc.replace_export("getsid", another_getsid as *const _) }.unwrap();
+    assert!(matches!(
+        first.restore(),
+        Err(RestoreError::Conflict { current }) if current == another_getsid as *const c_void
+    ));
+
+    first.set_restore_policy(RestorePolicy::Force);
+    drop(first);
+    assert_eq!(dlsym_getsid(), original);
+    assert!(matches!(
+        second.restore(),
+        Err(RestoreError::Conflict { .. })
+    ));
+    second.set_restore_policy(RestorePolicy::Skip);
+    drop(second);
+    assert_eq!(dlsym_getsid(), original);
+
+    let error = match unsafe { libc.replace_export("environ", other_getsid as *const _) } {
+        Ok(_) => panic!("environ is not a function"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), crate::ErrorKind::InvalidArgument);
+
+    let error = match unsafe { libc.replace_export("no_such_function", other_getsid as *const _) } {
+        Ok(_) => panic!("the function should not exist"),
+        Err(e) => e,
+    };
+    assert_eq!(error.kind(), crate::ErrorKind::FunctionNotFound);
+
+    drop(lock);
+}
+
+#[cfg(all(feature = "detour", target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn detour_chain() {
+    #[inline(never)]
+    extern "C" fn triangle(n: u32) -> u32 {
+        (1..=n).fold(0, u32::wrapping_add)
+    }
+
+    static NEXT: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+    extern "C" fn double(n: u32) -> u32 {
+        let next: extern "C" fn(u32) -> u32 =
+            unsafe { mem::transmute(NEXT.load(Ordering::SeqCst)) };
+        next(n) * 2
+    }
+
+    extern "C" fn identity(n: u32) -> u32 {
+        n
+    }
+
+    let call = |n| {
+        let f: extern "C" fn(u32) -> u32 = unsafe { std::ptr::read_volatile(&(triangle as _)) };
+        f(n)
+    };
+
+    // The layers are in the chain registry, like scoped hooks.
+    let lock = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+
+    let function = triangle as *const c_void;
+    let code = unsafe { std::ptr::read(function as *const [u8; 16]) };
+
+    let low = unsafe { crate::detour::replace(function, identity as *const _) }.unwrap();
+    assert_eq!(call(4), 4);
+
+    let options = crate::ReplaceOptions::new().priority(1);
+    let high =
+        unsafe { crate::detour::replace_with_options(function, double as *const _, &options) }
+            .unwrap();
+    NEXT.store(high.original_address() as *mut _, Ordering::SeqCst);
+    assert_eq!(call(4), 8);
+
+    // The original function is still available after removing the first
+    // replacement.
+    let original: extern "C" fn(u32) -> u32 = unsafe { mem::transmute(low.original_address()) };
+    drop(low);
+    assert_eq!(original(4), 10);
+    NEXT.store(original as *mut _, Ordering::SeqCst);
+    assert_eq!(call(4), 20);
+
+    drop(high);
+    assert_eq!(call(4), 10);
+    assert_eq!(unsafe { std::ptr::read(function as *const [u8; 16]) }, code);
+
+    drop(lock);
+}
+
+#[cfg(all(feature = "detour", target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn detour_foreign_patch() {
+    #[inline(never)]
+    extern "C" fn square(n: u32) -> u32 {
+        (0..n).fold(0, |acc, _| acc.wrapping_add(n))
+    }
+
+    extern "C" fn identity(n: u32) -> u32 {
+        n
+    }
+
+    extern "C" fn zero(_: u32) -> u32 {
+        0
+    }
+
+    let call = |n| {
+        let f: extern "C" fn(u32) -> u32 = unsafe { std::ptr::read_volatile(&(square as _)) };
+        f(n)
+    };
+
+    let lock = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+
+    let function = square as *const c_void as usize;
+    let code = unsafe { std::ptr::read(function as *const [u8; 5]) };
+
+    let replacement =
+        unsafe { crate::detour::replace(function as *const _, identity as *const _) }.unwrap();
+    assert_eq!(call(4), 4);
+
+    // Another library patches the function after us.
+    let mut jump = [0xe9, 0, 0, 0, 0];
+    let disp = (zero as *const () as usize).wrapping_sub(function + 5) as i32;
+    jump[1..].copy_from_slice(&disp.to_ne_bytes());
+    unsafe { crate::detour::write_code(function, None, &jump).unwrap() };
+    assert_eq!(call(4), 0);
+
+    // Its jump is kept when the detour is removed.
+    drop(replacement);
+    assert_eq!(unsafe { std::ptr::read(function as *const [u8; 5]) }, jump);
+    assert_eq!(call(4), 0);
+
+    // The code is only written if it contains the expected bytes.
+    assert!(!unsafe { crate::detour::write_code(function, Some(&code), &code).unwrap() });
+    assert!(unsafe { crate::detour::write_code(function, Some(&jump), &code).unwrap() });
+    assert_eq!(call(4), 16);
+
+    drop(lock);
+}
+
+#[cfg(all(feature = "detour", target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn detour_relocate() {
+    use crate::detour::{decode, relocate, Decoded};
+
+    let source = 0x10_0000;
+    let target = 0x20_0000;
+
+    // endbr64; mov rax, [rip + 0x10]; jne +0x20
+    let code = [
+        0xf3, 0x0f, 0x1e, 0xfa, 0x48, 0x8b, 0x05, 0x10, 0, 0, 0, 0x75, 0x20,
+    ];
+
+    let (copied, output) = relocate(&code, source, target).unwrap();
+    assert_eq!(copied, 11);
+    assert_eq!(&output[..7], &code[..7]);
+    let disp = i32::from_ne_bytes([output[7], output[8], output[9], output[10]]);
+    assert_eq!(
+        (target + 11).wrapping_add(disp as usize),
+        source + 11 + 0x10
+    );
+
+    // A rel8 branch is converted to rel32.
+    let code = [0x90, 0x90, 0x90, 0x90, 0x75, 0x20];
+    let (copied, output) = relocate(&code, source, target).unwrap();
+    assert_eq!(copied, 6);
+    assert_eq!(&output[4..6], &[0x0f, 0x85]);
+    let disp = i32::from_ne_bytes([output[6], output[7], output[8], output[9]]);
+    assert_eq!((target + 10).wrapping_add(disp as usize), source + 6 + 0x20);
+
+    // ret before the space for the jump.
+    let error = relocate(&[0x31, 0xc0, 0xc3, 0x90, 0x90, 0x90], source, target).unwrap_err();
+    assert_eq!(error.kind(), crate::ErrorKind::InvalidArgument);
+
+    // VEX instructions are not supported.
+    let error = relocate(&[0xc5, 0xf8, 0x77, 0x90, 0x90, 0x90], source, target).unwrap_err();
+    assert_eq!(error.kind(), crate::ErrorKind::NotImplemented);
+
+    // Branches to any copied byte, including the first one.
+    for code in [
+        [0x90, 0x90, 0x90, 0x90, 0x75, 0xfa],
+        [0x90, 0x90, 0x90, 0x90, 0x75, 0xff],
+    ]
+    .iter()
+    {
+        let error = relocate(code, source, target).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::NotImplemented);
+    }
+
+    // Instructions are decoded from partial input.
+    assert_eq!(decode(&[0x48, 0x89]), Decoded::Incomplete);
+    assert!(matches!(decode(&[0x48, 0x89, 0xe5]), Decoded::Instruction(i) if i.len == 3));
+    assert_eq!(decode(&[0x67]), Decoded::Unsupported);
+}
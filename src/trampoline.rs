@@ -0,0 +1,707 @@
+//! Trampolines generated at runtime.
+//!
+//! A trampoline is a small piece of code that loads the address of a record
+//! with a context pointer and a callback in a scratch register, and jumps
+//! to a common entry function. The entry saves the registers used to pass
+//! arguments, calls the callback with the context, restores the registers,
+//! and jumps to the address returned by the callback.
+//!
+//! Thus, the callback runs before the target function, and the arguments
+//! (in registers or in the stack) are not modified.
+//!
+//! The registers used by the trampolines are:
+//!
+//! | Architecture | Record  | Scratch     |
+//! |--------------|---------|-------------|
+//! | x86_64       | `r10`   | `r11`       |
+//! | aarch64      | `x16`   | `x17`, `x9` |
+//!
+//! # Saved registers
+//!
+//! On x86_64, the entry saves the registers used to pass arguments in the C
+//! calling convention (`rdi`, `rsi`, `rdx`, `rcx`, `r8`, `r9` and `rax`),
+//! the flags, and the whole extended state with `xsave` (or `fxsave`, if
+//! the processor doesn't support it). Thus, the `ymm` and `zmm` registers,
+//! and the x87 and `mxcsr` state, are restored before jumping to the
+//! target. The size of the saved area is read from CPUID, and it is
+//! allocated in the stack of the thread.
+//!
+//! On aarch64, the entry saves `x0`-`x9` and `q0`-`q7`. The upper bits of
+//! the SVE registers are not preserved, so functions that receive SVE
+//! values are not supported.
+//!
+//! Arguments in the stack are never modified.
+//!
+//! # Guarded trampolines
+//!
+//! A guarded trampoline enters a [`HookGuard`](crate::HookGuard) before
+//! jumping to its target, or jumps to the next function in the chain if the
+//! thread is already running a hook. To leave the guard when the target
+//! returns, the return address of the call is saved in a thread-local
+//! variable, and replaced with the address of a stub. The stub leaves the
+//! guard and jumps to the saved address, preserving the registers used to
+//! return values. On x86_64, the stub saves the extended state like the
+//! entry, so a `long double` returned in `st0` is kept, and empties the x87
+//! stack before calling any function.
+//!
+//! Only one return address is saved per thread, because nested calls
+//! don't enter the guard. The target must return normally: if it unwinds
+//! or calls `longjmp`, the thread is kept inside the guard. The modified
+//! return address is not compatible with shadow stacks.
+//!
+//! # Releasing the trampolines
+//!
+//! A thread can load the address of a trampoline from a PLT entry just
+//! before it is removed, and run it at any time later. Thus, the code of a
+//! trampoline is never unmapped nor reused for another context. The
+//! trampolines are allocated from pages shared by all groups, so every
+//! trampoline only uses a few bytes after it is released.
+//!
+//! Every trampoline has a counter of running calls and a `retired` flag, in
+//! a writable page. The first instruction of a trampoline increments the
+//! counter, and the entry decrements it after the callback returns.
+//! Callbacks that need the data of the group after returning (like the
+//! closure of a closure hook) can keep a counter incremented for longer
+//! (see [`Trampolines::calls`]).
+//!
+//! When a group is dropped, its trampolines are retired. The entry checks
+//! the flag after incrementing the counter, and a retired trampoline jumps
+//! to the next function in the chain without calling the callback. The
+//! data of the group is released when all counters are zero after the
+//! flags are set, since any later call will see the flags.
+
+use std::any::Any;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{io, mem, ptr};
+
+use crate::chain::Link;
+use crate::errors::{Error, ErrorKind, Result};
+use crate::reentrancy;
+
+/// Function called by the entry. It returns the address to jump to.
+pub(crate) type Callback = unsafe extern "C" fn(context: *const c_void) -> *const c_void;
+
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".p2align 4",
+    ".globl plthook_rs_trampoline_entry",
+    ".hidden plthook_rs_trampoline_entry",
+    ".type plthook_rs_trampoline_entry, @function",
+    "plthook_rs_trampoline_entry:",
+    "push rbp",
+    "mov rbp, rsp",
+    "pushfq",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push r8",
+    "push r9",
+    "push rax",
+    // Extended state, in an area aligned to 64 bytes.
+    "mov rax, qword ptr [rip + {xsave_size}]",
+    "test rax, rax",
+    "jz 2f",
+    "sub rsp, rax",
+    "and rsp, -64",
+    // `xrstor` requires the reserved bytes of the header to be zero.
+    "xor eax, eax",
+    "mov qword ptr [rsp + 512], rax",
+    "mov qword ptr [rsp + 520], rax",
+    "mov qword ptr [rsp + 528], rax",
+    "mov qword ptr [rsp + 536], rax",
+    "mov qword ptr [rsp + 544], rax",
+    "mov qword ptr [rsp + 552], rax",
+    "mov qword ptr [rsp + 560], rax",
+    "mov qword ptr [rsp + 568], rax",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xsave64 [rsp]",
+    "jmp 3f",
+    "2:",
+    "sub rsp, 512",
+    "and rsp, -64",
+    "fxsave64 [rsp]",
+    "3:",
+    // Keep the record (and the stack alignment) during the call.
+    "push r10",
+    "sub rsp, 8",
+    "mov rdi, qword ptr [r10]",
+    "lea rsi, [rbp + 8]",
+    "call qword ptr [r10 + 8]",
+    "add rsp, 8",
+    "pop r10",
+    "mov r11, rax",
+    // The record can't be used after the counter is decremented.
+    "mov r10, qword ptr [r10 + 16]",
+    "lock dec qword ptr [r10]",
+    "cmp qword ptr [rip + {xsave_size}], 0",
+    "je 4f",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xrstor64 [rsp]",
+    "jmp 5f",
+    "4:",
+    "fxrstor64 [rsp]",
+    "5:",
+    "lea rsp, [rbp - 64]",
+    "pop rax",
+    "pop r9",
+    "pop r8",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "popfq",
+    "pop rbp",
+    "jmp r11",
+    ".size plthook_rs_trampoline_entry, . - plthook_rs_trampoline_entry",
+    ".popsection",
+    xsave_size = sym XSAVE_SIZE,
+);
+
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".p2align 2",
+    ".globl plthook_rs_trampoline_entry",
+    ".hidden plthook_rs_trampoline_entry",
+    ".type plthook_rs_trampoline_entry, %function",
+    "plthook_rs_trampoline_entry:",
+    // BTI landing pad (`bti jc`). It is a NOP in older processors.
+    "hint #38",
+    "stp x29, x30, [sp, #-16]!",
+    "mov x29, sp",
+    "stp x0, x1, [sp, #-16]!",
+    "stp x2, x3, [sp, #-16]!",
+    "stp x4, x5, [sp, #-16]!",
+    "stp x6, x7, [sp, #-16]!",
+    "stp x8, x9, [sp, #-16]!",
+    "stp q0, q1, [sp, #-32]!",
+    "stp q2, q3, [sp, #-32]!",
+    "stp q4, q5, [sp, #-32]!",
+    "stp q6, q7, [sp, #-32]!",
+    // Keep the record during the call.
+    "str x16, [sp, #-16]!",
+    "ldr x0, [x16]",
+    "add x1, sp, #232",
+    "ldr x17, [x16, #8]",
+    "blr x17",
+    "ldr x16, [sp], #16",
+    // The record can't be used after the counter is decremented.
+    "ldr x16, [x16, #16]",
+    "2:",
+    "ldxr x17, [x16]",
+    "sub x17, x17, #1",
+    "stlxr w9, x17, [x16]",
+    "cbnz w9, 2b",
+    "mov x16, x0",
+    "ldp q6, q7, [sp], #32",
+    "ldp q4, q5, [sp], #32",
+    "ldp q2, q3, [sp], #32",
+    "ldp q0, q1, [sp], #32",
+    "ldp x8, x9, [sp], #16",
+    "ldp x6, x7, [sp], #16",
+    "ldp x4, x5, [sp], #16",
+    "ldp x2, x3, [sp], #16",
+    "ldp x0, x1, [sp], #16",
+    "ldp x29, x30, [sp], #16",
+    "br x16",
+    ".size plthook_rs_trampoline_entry, . - plthook_rs_trampoline_entry",
+    ".popsection",
+);
+
+// Return address of the calls from a guarded trampoline. It leaves the
+// guard, and jumps to the original return address, with the registers used
+// to return values.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".p2align 4",
+    ".globl plthook_rs_guard_return",
+    ".hidden plthook_rs_guard_return",
+    ".type plthook_rs_guard_return, @function",
+    "plthook_rs_guard_return:",
+    "push rbp",
+    "mov rbp, rsp",
+    "push rax",
+    "push rdx",
+    // Extended state, including `xmm0`, `xmm1`, `st0` and `st1`.
+    "mov rax, qword ptr [rip + {xsave_size}]",
+    "test rax, rax",
+    "jz 2f",
+    "sub rsp, rax",
+    "and rsp, -64",
+    "xor eax, eax",
+    "mov qword ptr [rsp + 512], rax",
+    "mov qword ptr [rsp + 520], rax",
+    "mov qword ptr [rsp + 528], rax",
+    "mov qword ptr [rsp + 536], rax",
+    "mov qword ptr [rsp + 544], rax",
+    "mov qword ptr [rsp + 552], rax",
+    "mov qword ptr [rsp + 560], rax",
+    "mov qword ptr [rsp + 568], rax",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xsave64 [rsp]",
+    "jmp 3f",
+    "2:",
+    "sub rsp, 512",
+    "and rsp, -64",
+    "fxsave64 [rsp]",
+    "3:",
+    // The x87 stack has to be empty when a function is called.
+    "fninit",
+    "call {leave}",
+    "mov r11, rax",
+    "cmp qword ptr [rip + {xsave_size}], 0",
+    "je 4f",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xrstor64 [rsp]",
+    "jmp 5f",
+    "4:",
+    "fxrstor64 [rsp]",
+    "5:",
+    "lea rsp, [rbp - 16]",
+    "pop rdx",
+    "pop rax",
+    "pop rbp",
+    "jmp r11",
+    ".size plthook_rs_guard_return, . - plthook_rs_guard_return",
+    ".popsection",
+    leave = sym reentrancy::leave_call,
+    xsave_size = sym XSAVE_SIZE,
+);
+
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".pushsection .text",
+    ".p2align 2",
+    ".globl plthook_rs_guard_return",
+    ".hidden plthook_rs_guard_return",
+    ".type plthook_rs_guard_return, %function",
+    "plthook_rs_guard_return:",
+    "stp x0, x1, [sp, #-16]!",
+    "stp x2, x3, [sp, #-16]!",
+    "stp x4, x5, [sp, #-16]!",
+    "stp x6, x7, [sp, #-16]!",
+    "stp q0, q1, [sp, #-32]!",
+    "stp q2, q3, [sp, #-32]!",
+    "bl {leave}",
+    "mov x16, x0",
+    "ldp q2, q3, [sp], #32",
+    "ldp q0, q1, [sp], #32",
+    "ldp x6, x7, [sp], #16",
+    "ldp x4, x5, [sp], #16",
+    "ldp x2, x3, [sp], #16",
+    "ldp x0, x1, [sp], #16",
+    // `ret` is not checked by BTI, unlike `br`.
+    "ret x16",
+    ".size plthook_rs_guard_return, . - plthook_rs_guard_return",
+    ".popsection",
+    leave = sym reentrancy::leave_call,
+);
+
+extern "C" {
+    fn plthook_rs_trampoline_entry();
+    fn plthook_rs_guard_return();
+}
+
+/// Size of the area used to save the extended state, or zero if the
+/// processor doesn't support `xsave`, and `fxsave` is used instead.
+///
+/// It is set before the first trampoline is created.
+#[cfg(target_arch = "x86_64")]
+static XSAVE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Read the size of the `xsave` area for the features enabled by the
+/// system.
+#[cfg(target_arch = "x86_64")]
+fn init_xsave_size() {
+    use std::arch::x86_64::__cpuid_count;
+
+    // CPUID.1:ECX.OSXSAVE[bit 27] is set when `xsave` can be used.
+    //
+    // `__cpuid_count` is only safe in recent versions of Rust.
+    #[allow(unused_unsafe)]
+    let size = unsafe {
+        if __cpuid_count(1, 0).ecx & (1 << 27) == 0 {
+            0
+        } else {
+            __cpuid_count(0xd, 0).ebx as usize
+        }
+    };
+
+    XSAVE_SIZE.store(size, Ordering::SeqCst);
+}
+
+/// Size reserved for every trampoline.
+const TRAMPOLINE_SIZE: usize = 64;
+
+/// Write the code of a trampoline in `buf`.
+///
+/// `buf` is at `address`, and `counter` is the address of the counter of
+/// running calls.
+#[cfg(target_arch = "x86_64")]
+fn write_trampoline(
+    buf: &mut [u8],
+    address: usize,
+    context: usize,
+    callback: usize,
+    counter: usize,
+    entry: usize,
+) {
+    let mut code = Vec::with_capacity(TRAMPOLINE_SIZE);
+
+    // lock inc qword ptr [rip + counter]
+    let disp = counter.wrapping_sub(address + 8) as i64;
+    code.extend_from_slice(&[0xf0, 0x48, 0xff, 0x05]);
+    code.extend_from_slice(&(disp as i32).to_le_bytes());
+
+    // lea r10, [rip + 9]
+    code.extend_from_slice(&[0x4c, 0x8d, 0x15, 9, 0, 0, 0]);
+
+    // jmp [rip + 27]
+    code.extend_from_slice(&[0xff, 0x25, 27, 0, 0, 0]);
+
+    // int3
+    code.extend_from_slice(&[0xcc; 3]);
+
+    // Record, at offset 24.
+    code.extend_from_slice(&context.to_ne_bytes());
+    code.extend_from_slice(&callback.to_ne_bytes());
+    code.extend_from_slice(&counter.to_ne_bytes());
+
+    // Entry, at offset 48.
+    code.extend_from_slice(&entry.to_ne_bytes());
+
+    buf[..code.len()].copy_from_slice(&code);
+}
+
+/// Write the code of a trampoline in `buf`.
+///
+/// `counter` is the address of the counter of running calls.
+#[cfg(target_arch = "aarch64")]
+fn write_trampoline(
+    buf: &mut [u8],
+    _address: usize,
+    context: usize,
+    callback: usize,
+    counter: usize,
+    entry: usize,
+) {
+    let mut code = Vec::with_capacity(TRAMPOLINE_SIZE);
+
+    let instructions: [u32; 8] = [
+        0x5800_0190, // ldr x16, #48
+        0xc85f_7e11, // ldxr x17, [x16]
+        0x9100_0631, // add x17, x17, #1
+        0xc809_7e11, // stxr w9, x17, [x16]
+        0x35ff_ffa9, // cbnz w9, #-12
+        0x1000_0070, // adr x16, #12
+        0x5800_0109, // ldr x9, #32
+        0xd61f_0120, // br x9
+    ];
+
+    for inst in instructions.iter() {
+        code.extend_from_slice(&inst.to_le_bytes());
+    }
+
+    // Record, at offset 32.
+    code.extend_from_slice(&context.to_ne_bytes());
+    code.extend_from_slice(&callback.to_ne_bytes());
+    code.extend_from_slice(&counter.to_ne_bytes());
+
+    // Entry, at offset 56.
+    code.extend_from_slice(&entry.to_ne_bytes());
+
+    buf[..code.len()].copy_from_slice(&code);
+}
+
+/// State of a trampoline, in the writable page of its chunk.
+///
+/// All fields are zero until the trampoline is allocated. `calls` and
+/// `retired` are the only fields modified after that.
+#[repr(C)]
+struct Slot {
+    calls: AtomicUsize,
+    retired: AtomicBool,
+    guarded: bool,
+    context: *const c_void,
+    callback: Option<Callback>,
+
+    /// Value of the `Link` to the next function in the chain. The `Arc` is
+    /// never released.
+    next: *const AtomicPtr<c_void>,
+}
+
+/// Callback written in every trampoline. It calls the callback of the slot,
+/// or returns the next function if the trampoline is retired.
+///
+/// `return_address` is the address of the return address of the call. In
+/// a guarded trampoline, it is replaced with the address of the stub that
+/// leaves the guard.
+unsafe extern "C" fn dispatch(
+    slot: *const c_void,
+    return_address: *mut *const c_void,
+) -> *const c_void {
+    let slot = &*(slot as *const Slot);
+
+    if slot.retired.load(Ordering::SeqCst) {
+        return (*slot.next).load(Ordering::SeqCst);
+    }
+
+    if slot.guarded {
+        let stub = plthook_rs_guard_return as *const c_void;
+        if !reentrancy::enter_call(return_address, stub) {
+            return (*slot.next).load(Ordering::SeqCst);
+        }
+    }
+
+    match slot.callback {
+        Some(callback) => callback(slot.context),
+        None => (*slot.next).load(Ordering::SeqCst),
+    }
+}
+
+/// Trampolines that are not allocated yet.
+///
+/// Every chunk is a mapping with a page for the code of the trampolines,
+/// followed by the pages for their slots. The mappings are never released.
+struct Arena {
+    /// Next trampoline to allocate in the last chunk, as `(code, slot)`.
+    free: Vec<(usize, *mut Slot)>,
+}
+
+// The addresses are only used with the lock of `ARENA`.
+unsafe impl Send for Arena {}
+
+static ARENA: Mutex<Arena> = Mutex::new(Arena { free: Vec::new() });
+
+impl Arena {
+    /// Take a trampoline, and map a new chunk if there are no free ones.
+    fn take(&mut self) -> io::Result<(usize, *mut Slot)> {
+        if self.free.is_empty() {
+            self.map_chunk()?;
+        }
+
+        Ok(self.free.pop().unwrap())
+    }
+
+    fn map_chunk(&mut self) -> io::Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        init_xsave_size();
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let count = page_size / TRAMPOLINE_SIZE;
+        let slots_len = (count * mem::size_of::<Slot>() + page_size - 1) & !(page_size - 1);
+        let len = page_size + slots_len;
+
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let buf = unsafe { std::slice::from_raw_parts_mut(base as *mut u8, page_size) };
+        let slots = unsafe { (base as *mut u8).add(page_size) as *mut Slot };
+        let entry = plthook_rs_trampoline_entry as *const () as usize;
+
+        let mut free = Vec::with_capacity(count);
+        for (index, chunk) in buf.chunks_mut(TRAMPOLINE_SIZE).enumerate() {
+            let address = base as usize + index * TRAMPOLINE_SIZE;
+            let slot = unsafe { slots.add(index) };
+            write_trampoline(
+                chunk,
+                address,
+                slot as usize,
+                dispatch as *const () as usize,
+                slot as usize,
+                entry,
+            );
+
+            free.push((address, slot));
+        }
+
+        unsafe {
+            if libc::mprotect(base, page_size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let error = io::Error::last_os_error();
+                libc::munmap(base, len);
+                return Err(error);
+            }
+
+            #[cfg(target_arch = "aarch64")]
+            {
+                extern "C" {
+                    fn __clear_cache(start: *mut std::ffi::c_char, end: *mut std::ffi::c_char);
+                }
+
+                __clear_cache(base.cast(), (base as *mut std::ffi::c_char).add(page_size));
+            }
+        }
+
+        // Allocate the trampolines in order.
+        free.reverse();
+        self.free = free;
+        Ok(())
+    }
+}
+
+/// A group of trampolines.
+///
+/// The data attached to the group is released after the group is dropped
+/// and there are no calls running in it (see the [module
+/// documentation](self)).
+pub(crate) struct Trampolines {
+    trampolines: Vec<(usize, *mut Slot)>,
+    data: Option<Box<dyn Any + Send>>,
+}
+
+// The slots are only modified with atomic operations after
+// `Trampolines::new`. The data is only accessed to release it.
+unsafe impl Send for Trampolines {}
+unsafe impl Sync for Trampolines {}
+
+/// Groups dropped while there were calls running in them.
+static PENDING: Mutex<Vec<Trampolines>> = Mutex::new(Vec::new());
+
+impl Trampolines {
+    /// Create a trampoline for every `(context, callback, next)` target.
+    ///
+    /// `next` is the link to the next function in the chain, used after the
+    /// group is dropped. `data` is released with the trampolines. It should
+    /// own the memory of the contexts.
+    pub(crate) fn new(
+        targets: &[(*const c_void, Callback, &Link)],
+        data: Box<dyn Any + Send>,
+    ) -> Result<Trampolines> {
+        Self::allocate(targets, data, false)
+    }
+
+    /// Create a trampoline that jumps to `address` inside a
+    /// [`HookGuard`](crate::HookGuard). If the thread is already running a
+    /// hook, it jumps to the next function in the chain.
+    pub(crate) fn guarded(address: *const c_void, next: &Link) -> Result<Trampolines> {
+        unsafe extern "C" fn target(context: *const c_void) -> *const c_void {
+            context
+        }
+
+        Self::allocate(&[(address, target, next)], Box::new(()), true)
+    }
+
+    fn allocate(
+        targets: &[(*const c_void, Callback, &Link)],
+        data: Box<dyn Any + Send>,
+        guarded: bool,
+    ) -> Result<Trampolines> {
+        release_pending();
+
+        let mut arena = ARENA.lock().unwrap_or_else(|e| e.into_inner());
+        let mut trampolines = Vec::with_capacity(targets.len());
+        for (context, callback, next) in targets {
+            let (address, slot) = arena.take().map_err(|e| {
+                let msg = format!("Could not allocate trampoline: {e}");
+                Error::new(ErrorKind::OutOfMemory, msg).with_source(e)
+            })?;
+
+            // The trampoline is not used by any other thread until its
+            // address is written in a PLT entry.
+            unsafe {
+                (*slot).guarded = guarded;
+                (*slot).context = *context;
+                (*slot).callback = Some(*callback);
+                (*slot).next = Arc::into_raw(Arc::clone(next));
+            }
+
+            trampolines.push((address, slot));
+        }
+
+        Ok(Trampolines {
+            trampolines,
+            data: Some(data),
+        })
+    }
+
+    /// Address of the trampoline at `index`.
+    pub(crate) fn address(&self, index: usize) -> *const c_void {
+        self.trampolines[index].0 as *const c_void
+    }
+
+    /// Counter of running calls of the first trampoline.
+    ///
+    /// A callback can increment it before returning, to keep the data of
+    /// the group after the entry decrements its own counter.
+    pub(crate) fn calls(&self) -> *const AtomicUsize {
+        unsafe { &(*self.trampolines[0].1).calls }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.trampolines
+            .iter()
+            .all(|&(_, slot)| unsafe { (*slot).calls.load(Ordering::SeqCst) == 0 })
+    }
+}
+
+impl Drop for Trampolines {
+    fn drop(&mut self) {
+        let data = match self.data.take() {
+            Some(data) => data,
+            None => return,
+        };
+
+        for &(_, slot) in &self.trampolines {
+            unsafe { (*slot).retired.store(true, Ordering::SeqCst) };
+        }
+
+        if self.is_idle() {
+            drop(data);
+        } else {
+            // Keep the data until the running calls return. It is released
+            // by `release_pending`.
+            let trampolines = Trampolines {
+                trampolines: mem::take(&mut self.trampolines),
+                data: Some(data),
+            };
+
+            PENDING
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(trampolines);
+        }
+
+        release_pending();
+    }
+}
+
+/// Release the data of the groups with no running calls.
+fn release_pending() {
+    let idle: Vec<_> = {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut idle = Vec::new();
+        let mut i = 0;
+        while i < pending.len() {
+            if pending[i].is_idle() {
+                idle.push(pending.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        idle
+    };
+
+    // The trampolines are already retired, so `Drop` releases the data.
+    drop(idle);
+}